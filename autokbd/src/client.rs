@@ -0,0 +1,112 @@
+// Requests over the raw HID channel, independent of how reports reach the device
+
+use crate::protocol::{self, Command, Message, REPORT_SIZE};
+use std::time::Duration;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Typing text is answered once it has been typed; allow this much per character
+const TYPING_TIME_PER_CHAR: Duration = Duration::from_millis(150);
+
+pub trait Transport {
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> std::io::Result<()>;
+    // None if no report arrived within `timeout`
+    fn read_report(&mut self, timeout: Duration) -> std::io::Result<Option<[u8; REPORT_SIZE]>>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> std::io::Result<()> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&mut self, timeout: Duration) -> std::io::Result<Option<[u8; REPORT_SIZE]>> {
+        (**self).read_report(timeout)
+    }
+}
+
+pub struct Client<T> {
+    transport: T,
+    pub timeout: Duration,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    // Sends `request` and returns the data of its response
+    pub fn request(&mut self, request: &Message) -> anyhow::Result<Vec<u8>> {
+        self.request_with_timeout(request, self.timeout)
+    }
+
+    fn request_with_timeout(
+        &mut self,
+        request: &Message,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<u8>> {
+        for report in request.encode() {
+            self.transport.write_report(&report)?;
+        }
+
+        let mut assembler = protocol::Assembler::new();
+        loop {
+            let Some(report) = self.transport.read_report(timeout)? else {
+                anyhow::bail!("no response to {:?} within {timeout:?}", request.command);
+            };
+            let Some(response) = assembler.push(&report)? else {
+                continue;
+            };
+            anyhow::ensure!(
+                response.command == request.command,
+                "expected a response to {:?}, got {:?}",
+                request.command,
+                response.command
+            );
+            if let Some(message) = response.error_message() {
+                anyhow::bail!("device: {message}");
+            }
+            return Ok(response.data);
+        }
+    }
+
+    pub fn status(&mut self) -> anyhow::Result<String> {
+        let data = self.request(&Message::new(Command::Status, []))?;
+        Ok(String::from_utf8(data)?)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> anyhow::Result<Vec<u8>> {
+        self.request(&Message::new(Command::ReadFile, path))
+    }
+
+    pub fn write_file(&mut self, path: &str, content: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            path.len() + 1 + content.len() <= protocol::MAX_MESSAGE_SIZE,
+            "{path} is too large to transfer ({} bytes)",
+            content.len()
+        );
+        let data = protocol::join_path(path, content);
+        self.request(&Message::new(Command::WriteFile, data))?;
+        Ok(())
+    }
+
+    pub fn read_config(&mut self) -> anyhow::Result<String> {
+        let data = self.request(&Message::new(Command::ReadConfig, []))?;
+        Ok(String::from_utf8(data)?)
+    }
+
+    pub fn write_config(&mut self, config: &str) -> anyhow::Result<()> {
+        self.request(&Message::new(Command::WriteConfig, config))?;
+        Ok(())
+    }
+
+    // Types `text`, or the payload of the selected slot if None
+    pub fn type_text(&mut self, text: Option<&str>) -> anyhow::Result<()> {
+        let text = text.unwrap_or_default();
+        let timeout = self.timeout + TYPING_TIME_PER_CHAR * text.chars().count() as u32;
+        self.request_with_timeout(&Message::new(Command::Type, text), timeout)?;
+        Ok(())
+    }
+}
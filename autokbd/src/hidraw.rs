@@ -0,0 +1,130 @@
+// Devices found through the Linux hidraw driver; the vendor interface is recognized by the
+// usage page at the start of its report descriptor
+
+use crate::client::Transport;
+use crate::protocol::REPORT_SIZE;
+use std::io::{Read as _, Write as _};
+use std::os::fd::AsRawFd as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// USB IDs set in `usb::descriptor::device_descriptor`
+const VENDOR_ID: u32 = 0x16c0;
+const PRODUCT_ID: u32 = 0x27db;
+
+// Usage Page (Vendor Defined 0xFF00), see `usb::vendor::REPORT_DESCRIPTOR`
+const VENDOR_USAGE_PAGE: &[u8] = &[0x06, 0x00, 0xff];
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    // The `serial` of `usb::descriptor::StringDescriptor`
+    pub serial: String,
+}
+
+pub fn list() -> anyhow::Result<Vec<DeviceInfo>> {
+    let mut devices = Vec::new();
+    let entries = match std::fs::read_dir("/sys/class/hidraw") {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(devices),
+        result => result?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let device = entry.path().join("device");
+        let (Ok(uevent), Ok(descriptor)) = (
+            std::fs::read_to_string(device.join("uevent")),
+            std::fs::read(device.join("report_descriptor")),
+        ) else {
+            continue;
+        };
+        if !descriptor.starts_with(VENDOR_USAGE_PAGE) {
+            continue;
+        }
+
+        let field = |name: &str| {
+            uevent
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+                .unwrap_or_default()
+                .to_string()
+        };
+        // HID_ID=0003:000016C0:000027DB (bus:vendor:product)
+        let ids: Vec<u32> = field("HID_ID")
+            .split(':')
+            .filter_map(|id| u32::from_str_radix(id, 16).ok())
+            .collect();
+        if ids.get(1..) != Some(&[VENDOR_ID, PRODUCT_ID]) {
+            continue;
+        }
+
+        devices.push(DeviceInfo {
+            path: Path::new("/dev").join(entry.file_name()),
+            name: field("HID_NAME"),
+            serial: field("HID_UNIQ"),
+        });
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+// The device with `serial`, or the only one connected
+pub fn find(serial: Option<&str>) -> anyhow::Result<DeviceInfo> {
+    let devices = list()?;
+    let mut matching = devices
+        .iter()
+        .filter(|device| serial.is_none() || serial == Some(device.serial.as_str()));
+    match (matching.next(), matching.next(), serial) {
+        (Some(device), None, _) => Ok(device.clone()),
+        (None, _, Some(serial)) => anyhow::bail!("no device with serial {serial}"),
+        (None, _, None) => anyhow::bail!("no device found"),
+        (Some(_), Some(_), _) => anyhow::bail!("several devices found, select one with --serial"),
+    }
+}
+
+pub struct Device {
+    file: std::fs::File,
+}
+
+impl Device {
+    pub fn open(info: &DeviceInfo) -> anyhow::Result<Self> {
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&info.path)
+            .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", info.path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+impl Transport for Device {
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> std::io::Result<()> {
+        // hidraw expects the report ID first, 0 for devices without numbered reports
+        let mut buffer = [0; REPORT_SIZE + 1];
+        buffer[1..].copy_from_slice(report);
+        self.file.write_all(&buffer)
+    }
+
+    fn read_report(&mut self, timeout: Duration) -> std::io::Result<Option<[u8; REPORT_SIZE]>> {
+        let mut poll = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut poll, 1, timeout_ms) } {
+            -1 => return Err(std::io::Error::last_os_error()),
+            0 => return Ok(None),
+            _ => {}
+        }
+        let mut report = [0; REPORT_SIZE];
+        let length = self.file.read(&mut report)?;
+        if length != REPORT_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("short report ({length} bytes)"),
+            ));
+        }
+        Ok(Some(report))
+    }
+}
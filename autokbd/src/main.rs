@@ -0,0 +1,723 @@
+// Host-side companion for the auto-keyboard firmware.
+// Modules shared with the firmware are included by path, so both sides always agree on formats.
+//
+// The repository defaults to the ESP32-S3 target, so build it for the host explicitly:
+//     cargo run -p autokbd --target x86_64-unknown-linux-gnu -- --help
+
+mod client;
+mod hidraw;
+mod mock;
+
+// Not every item of the shared modules is needed on the host
+#[allow(dead_code)]
+#[path = "../../src/bytecode.rs"]
+mod bytecode;
+#[allow(dead_code)]
+#[path = "../../src/config.rs"]
+mod config;
+#[allow(dead_code)]
+#[path = "../../src/console.rs"]
+mod console;
+#[allow(dead_code)]
+#[path = "../../src/crypto.rs"]
+mod crypto;
+#[allow(dead_code)]
+#[path = "../../src/escape.rs"]
+mod escape;
+#[allow(dead_code)]
+#[path = "../../src/hotp.rs"]
+mod hotp;
+#[allow(dead_code)]
+#[path = "../../src/lint.rs"]
+mod lint;
+#[allow(dead_code)]
+#[path = "../../src/payload.rs"]
+mod payload;
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+#[allow(dead_code)]
+#[path = "../../src/template.rs"]
+mod template;
+// `script` has submodules, so it is included through its parent directory
+#[path = "../../src"]
+mod src {
+    #[allow(dead_code, unused_imports)]
+    pub mod script;
+}
+use src::script;
+#[path = "../../src/led"]
+mod led {
+    #[allow(dead_code)]
+    pub mod pattern;
+}
+// Same module path as in the firmware, which `escape` relies on
+#[path = "../../src/usb"]
+mod usb {
+    #[allow(dead_code)]
+    pub mod keycode;
+    pub mod bus {
+        #[allow(dead_code)]
+        pub mod state;
+    }
+}
+
+use clap::Parser as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use usb::keycode::{self, KeyChord};
+use zeroize::Zeroizing;
+
+#[derive(clap::Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Device to talk to, by USB serial number; required if several are connected
+    #[arg(long, global = true)]
+    serial: Option<String>,
+    /// Talk to a simulated device using DIR as its drive instead
+    #[arg(long, global = true, value_name = "DIR")]
+    mock: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// List connected devices
+    List,
+    /// Show the state of the device
+    Status,
+    /// Copy a file to the drive of the device
+    Push {
+        file: PathBuf,
+        /// Path on the drive; defaults to the file name
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Copy a file from the drive of the device
+    Pull {
+        name: String,
+        /// Defaults to the file name in the current directory; `-` for stdout
+        output: Option<PathBuf>,
+    },
+    /// Show the config of the device
+    Config {
+        /// Validate FILE and replace the config with it instead
+        #[arg(long, value_name = "FILE")]
+        write: Option<PathBuf>,
+    },
+    /// Type TEXT, or the payload of the selected slot
+    Type { text: Option<String> },
+    /// Check that a payload can be typed, or that a config file is well-formed
+    Validate {
+        file: PathBuf,
+        /// Config file enabling templates or escapes and defining variables
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Check a payload for likely mistakes and estimate how long typing it takes
+    Lint {
+        file: PathBuf,
+        /// Config file enabling templates or escapes and defining variables
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Hold plus release time of a key on the device, in milliseconds
+        #[arg(long, default_value_t = DEFAULT_TAP_MS)]
+        tap_ms: u64,
+    },
+    /// Show the keyboard reports a payload is typed with
+    Preview {
+        file: PathBuf,
+        /// Config file enabling templates or escapes and defining variables
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Compile a payload, which the device then runs from its drive without loading it into RAM
+    Compile {
+        input: PathBuf,
+        /// Defaults to the input path with its extension replaced by `.kbc`
+        output: Option<PathBuf>,
+        /// Config file enabling escapes or scripts
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Keyboard layout the device is set to
+        #[arg(long, default_value = keycode::LAYOUTS[0])]
+        layout: String,
+    },
+    /// Encrypt a payload; the device asks for the PIN with its button before typing it
+    Encrypt {
+        input: PathBuf,
+        /// Defaults to the input path with `.enc` appended
+        output: Option<PathBuf>,
+        #[arg(long, default_value_t = crypto::DEFAULT_ITERATIONS)]
+        iterations: u32,
+    },
+}
+
+// Hold plus release time of `usb::Timing::DEFAULT`
+const DEFAULT_TAP_MS: u64 = 80;
+
+// Shows warnings of the shared modules, e.g. about a config value that is used
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("warning: {}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> anyhow::Result<()> {
+    log::set_logger(&Logger).ok();
+    log::set_max_level(log::LevelFilter::Warn);
+
+    let cli = Cli::parse();
+    match cli.command {
+        Command::List => list(),
+        Command::Status => {
+            print!("{}", connect(&cli)?.status()?);
+            Ok(())
+        }
+        Command::Push { ref file, ref name } => {
+            let name = match name {
+                Some(name) => name.clone(),
+                None => file
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| anyhow::anyhow!("cannot name {} on the drive", file.display()))?
+                    .to_string(),
+            };
+            let content = std::fs::read(file)?;
+            connect(&cli)?.write_file(&name, &content)?;
+            eprintln!("Wrote {name} ({} bytes)", content.len());
+            Ok(())
+        }
+        Command::Pull {
+            ref name,
+            ref output,
+        } => {
+            let content = connect(&cli)?.read_file(name)?;
+            match output {
+                Some(output) if output.as_os_str() == "-" => {
+                    std::io::stdout().write_all(&content)?
+                }
+                output => {
+                    let output = output
+                        .clone()
+                        .unwrap_or_else(|| PathBuf::from(name.rsplit('/').next().unwrap_or(name)));
+                    std::fs::write(&output, &content)?;
+                    eprintln!("Wrote {}", output.display());
+                }
+            }
+            Ok(())
+        }
+        Command::Config { write: None } => {
+            print!("{}", connect(&cli)?.read_config()?);
+            Ok(())
+        }
+        Command::Config {
+            write: Some(ref file),
+        } => {
+            let text = std::fs::read_to_string(file)?;
+            let (_, problems) = config::Config::check(&text);
+            if !problems.is_empty() {
+                show_problems(&problems);
+                anyhow::bail!("{} has problems, not written", file.display());
+            }
+            connect(&cli)?.write_config(&text)
+        }
+        Command::Type { ref text } => connect(&cli)?.type_text(text.as_deref()),
+        Command::Validate { file, config } => validate(file, config),
+        Command::Lint {
+            file,
+            config,
+            tap_ms,
+        } => lint_file(file, config, Duration::from_millis(tap_ms)),
+        Command::Preview { file, config } => preview(file, config),
+        Command::Compile {
+            input,
+            output,
+            config,
+            layout,
+        } => compile(input, output, config, &layout),
+        Command::Encrypt {
+            input,
+            output,
+            iterations,
+        } => encrypt(input, output, iterations),
+    }
+}
+
+fn connect(cli: &Cli) -> anyhow::Result<client::Client<Box<dyn client::Transport>>> {
+    let transport: Box<dyn client::Transport> = match cli.mock {
+        Some(ref root) => Box::new(mock::MockDevice::new(root)),
+        None => Box::new(hidraw::Device::open(&hidraw::find(cli.serial.as_deref())?)?),
+    };
+    Ok(client::Client::new(transport))
+}
+
+fn list() -> anyhow::Result<()> {
+    let devices = hidraw::list()?;
+    if devices.is_empty() {
+        eprintln!("No device found");
+    }
+    for device in devices {
+        println!(
+            "{}\t{}\t{}",
+            device.serial,
+            device.path.display(),
+            device.name
+        );
+    }
+    Ok(())
+}
+
+// How the firmware reads a payload
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Syntax {
+    Plain,
+    Escapes,
+    Script,
+}
+
+// The text of a payload, with templates expanded using placeholder values, and its syntax.
+// Slot settings are those of slot 0.
+fn payload_text(file: &PathBuf, config: Option<PathBuf>) -> anyhow::Result<(String, Syntax)> {
+    let config = match config {
+        Some(path) => config::Config::parse(&std::fs::read_to_string(path)?),
+        None => config::Config::default(),
+    };
+    if file.extension() == Some(bytecode::FILE_EXTENSION.as_ref()) {
+        anyhow::bail!("{} is compiled, check its source instead", file.display());
+    }
+    let content = std::fs::read(file)?;
+    if crypto::is_encrypted(&content) {
+        anyhow::bail!("{} is encrypted, decrypt it to check it", file.display());
+    }
+    let text = String::from_utf8(content)
+        .map_err(|e| anyhow::anyhow!("{} is not UTF-8: {e}", file.display()))?;
+    let syntax = if config.slot_mode(0) == config::SlotMode::Script
+        || file.extension() == Some(script::FILE_EXTENSION.as_ref())
+    {
+        Syntax::Script
+    } else if config.escapes() || file.extension() == Some(escape::FILE_EXTENSION.as_ref()) {
+        Syntax::Escapes
+    } else {
+        Syntax::Plain
+    };
+
+    if !(config.templates() && template::contains_placeholders(&text)) {
+        return Ok((text, syntax));
+    }
+    let mut context = template::Context {
+        serial: "0",
+        slot: 0,
+        variables: &config.variables(),
+        counter: &mut || 1,
+        random: &mut || 0,
+    };
+    Ok((template::expand(&text, &mut context)?, syntax))
+}
+
+// INCLUDE paths are relative to the directory of the script, as they are to the drive
+fn includes(file: &Path) -> impl FnMut(&str) -> std::io::Result<String> + '_ {
+    let directory = file.parent().unwrap_or(Path::new(""));
+    move |path| {
+        let path = console::relative_path(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        std::fs::read_to_string(directory.join(path))
+    }
+}
+
+fn parse_script(file: &Path, text: &str) -> Result<script::Script, script::ParseError> {
+    script::parse(text, &file.display().to_string(), &mut includes(file))
+}
+
+fn lint_payload(
+    file: &PathBuf,
+    config: Option<PathBuf>,
+    tap: Duration,
+) -> anyhow::Result<lint::Report> {
+    let (text, syntax) = payload_text(file, config)?;
+    let name = file.display().to_string();
+    Ok(match syntax {
+        Syntax::Plain => lint::plain(&text, &name, tap),
+        Syntax::Escapes => lint::escaped(&text, &name, tap),
+        Syntax::Script => lint::script(&text, &name, &mut includes(file), tap),
+    })
+}
+
+fn validate(file: PathBuf, config: Option<PathBuf>) -> anyhow::Result<()> {
+    let problems = problems(&file, config)?;
+    show_problems(&problems);
+    match problems.len() {
+        0 => {
+            eprintln!("{}: OK", file.display());
+            Ok(())
+        }
+        count => anyhow::bail!("{}: {count} problem(s)", file.display()),
+    }
+}
+
+// What keeps `file` from working as written; `lint` also shows likely mistakes
+fn problems(file: &PathBuf, config: Option<PathBuf>) -> anyhow::Result<Vec<String>> {
+    if file.file_name().and_then(|name| name.to_str()) == Some(config::FILE_NAME) {
+        return Ok(config::Config::check(&std::fs::read_to_string(file)?).1);
+    }
+    if file.extension() == Some(bytecode::FILE_EXTENSION.as_ref()) {
+        // Checks the header and checksum
+        let program = bytecode::Program::load(std::fs::File::open(file)?, None)?;
+        eprintln!(
+            "{}: compiled for the {:?} layout",
+            file.display(),
+            program.header().layout
+        );
+        return Ok(Vec::new());
+    }
+    // How the payload is read depends on the configuration, so it has to be right first
+    if let Some(ref path) = config {
+        let problems = config::Config::check(&std::fs::read_to_string(path)?).1;
+        if !problems.is_empty() {
+            return Ok(problems);
+        }
+    }
+    let report = lint_payload(file, config, Duration::from_millis(DEFAULT_TAP_MS))?;
+    Ok(report
+        .findings
+        .into_iter()
+        .filter(|finding| finding.check.is_error() || finding.check == lint::Check::Untypeable)
+        .map(|finding| format!("{}: {}", finding.location, finding.message))
+        .collect())
+}
+
+fn show_problems(problems: &[String]) {
+    for problem in problems {
+        eprintln!("warning: {problem}");
+    }
+}
+
+fn lint_file(file: PathBuf, config: Option<PathBuf>, tap: Duration) -> anyhow::Result<()> {
+    let report = lint_payload(&file, config, tap)?;
+    print!("{report}");
+    if report.has_errors() {
+        anyhow::bail!("{} cannot be typed as written", file.display());
+    }
+    Ok(())
+}
+
+// One line per report, in the order `HidInstance::type_keys` sends them, or
+// `usb::controller::KeyboardController` for payloads with escapes and scripts
+fn preview(file: PathBuf, config: Option<PathBuf>) -> anyhow::Result<()> {
+    if file.extension() == Some(bytecode::FILE_EXTENSION.as_ref()) {
+        let mut program = bytecode::Program::load(std::fs::File::open(&file)?, None)?;
+        let mut controller = PreviewController::default();
+        bytecode::run(&mut program, &mut controller, script::Limits::DEFAULT)?;
+        if !controller.down.is_empty() {
+            controller.send("(release all)", KeyChord::default());
+        }
+        return Ok(());
+    }
+    let (text, syntax) = payload_text(&file, config)?;
+    match syntax {
+        Syntax::Plain => {}
+        Syntax::Escapes => return preview_escaped(&text),
+        Syntax::Script => {
+            let script = parse_script(&file, &text)?;
+            let mut controller = PreviewController::default();
+            script::run(&script, &mut controller, script::Limits::DEFAULT)?;
+            // Keys left down are released when the controller is dropped
+            if !controller.down.is_empty() {
+                controller.send("(release all)", KeyChord::default());
+            }
+            return Ok(());
+        }
+    }
+    for char in text.chars() {
+        let report = match keycode::AsKeyboardReport::as_keyboard_report(char) {
+            Ok(report) => report,
+            Err(e) => {
+                println!("{char:?}\tskipped: {e}");
+                continue;
+            }
+        };
+        let line = |report: &usbd_hid::descriptor::KeyboardReport| {
+            format!(
+                "{char:?}\tmodifier {:08b} keys {:02x?}",
+                report.modifier, report.keycodes
+            )
+        };
+        let mut modifier_only = report;
+        modifier_only.keycodes = [0; 6];
+        if report.modifier != 0 {
+            println!("{}", line(&modifier_only));
+        }
+        println!("{}", line(&report));
+        if report.modifier != 0 {
+            println!("{}", line(&modifier_only));
+        }
+        println!("{}", line(&usbd_hid::descriptor::KeyboardReport::default()));
+    }
+    Ok(())
+}
+
+fn preview_escaped(text: &str) -> anyhow::Result<()> {
+    let mut controller = PreviewController::default();
+    for token in escape::tokenize(text)? {
+        match token {
+            escape::Token::Text(text) => controller.type_str(&text),
+            escape::Token::Tap { chord, count } => {
+                for _ in 0..count {
+                    controller.tap(&format!("{{{chord}}}"), chord);
+                }
+            }
+            escape::Token::Press(chord) => controller.press(&format!("{{{chord} down}}"), chord),
+            escape::Token::Release(chord) => controller.release(&format!("{{{chord} up}}"), chord),
+            escape::Token::Sleep(duration) => println!("{{Sleep}}\tsleep {duration:?}"),
+        }
+    }
+    // Keys left down are released when the controller is dropped
+    if !controller.down.is_empty() {
+        controller.send("(release all)", KeyChord::default());
+    }
+    Ok(())
+}
+
+// Mirrors the reports `usb::controller::KeyboardController` sends
+#[derive(Default)]
+struct PreviewController {
+    down: KeyChord,
+    // Keyboard LEDs as simulated by `lint::simulate_leds`
+    leds: u8,
+}
+
+impl PreviewController {
+    fn send(&mut self, label: &str, chord: KeyChord) {
+        let report = usbd_hid::descriptor::KeyboardReport::from(chord);
+        println!(
+            "{label}\tmodifier {:08b} keys {:02x?}",
+            report.modifier, report.keycodes
+        );
+        self.down = chord;
+    }
+
+    fn press(&mut self, label: &str, chord: KeyChord) {
+        let mut next = self.down;
+        if let Err(e) = next.merge(&chord) {
+            println!("{label}\tskipped: {e}");
+            return;
+        }
+        let new_modifiers = chord.modifiers - self.down.modifiers;
+        if !new_modifiers.is_empty() && chord.keys().next().is_some() {
+            let mut modifiers_only = self.down;
+            modifiers_only.modifiers |= new_modifiers;
+            self.send(label, modifiers_only);
+        }
+        self.send(label, next);
+    }
+
+    fn release(&mut self, label: &str, chord: KeyChord) {
+        let mut next = self.down;
+        next.remove(&chord);
+        let released_modifiers = self.down.modifiers - next.modifiers;
+        if !released_modifiers.is_empty() && self.down.keys().ne(next.keys()) {
+            let mut keys_released = next;
+            keys_released.modifiers |= released_modifiers;
+            self.send(label, keys_released);
+        }
+        self.send(label, next);
+    }
+
+    fn tap(&mut self, label: &str, chord: KeyChord) {
+        let added = chord.difference(&self.down);
+        self.press(label, chord);
+        self.release(label, added);
+    }
+
+    fn type_str(&mut self, text: &str) {
+        for char in text.chars() {
+            match keycode::character_to_chord(char) {
+                Some(chord) => self.tap(&format!("{char:?}"), chord),
+                None => println!(
+                    "{char:?}\tskipped: {}",
+                    keycode::KeycodeError::Unmappable(char)
+                ),
+            }
+        }
+    }
+}
+
+impl script::Host for PreviewController {
+    type Error = std::convert::Infallible;
+
+    fn type_text(&mut self, text: &str) -> Result<(), Self::Error> {
+        self.type_str(text);
+        Ok(())
+    }
+
+    fn tap(&mut self, chord: KeyChord) -> Result<(), Self::Error> {
+        PreviewController::tap(self, &chord.to_string(), chord);
+        Ok(())
+    }
+
+    fn press(&mut self, chord: KeyChord) -> Result<(), Self::Error> {
+        PreviewController::press(self, &format!("HOLD {chord}"), chord);
+        Ok(())
+    }
+
+    fn release(&mut self, chord: KeyChord) -> Result<(), Self::Error> {
+        PreviewController::release(self, &format!("RELEASE {chord}"), chord);
+        Ok(())
+    }
+
+    fn sleep(&mut self, duration: Duration) -> Result<(), Self::Error> {
+        println!("DELAY\tsleep {duration:?}");
+        Ok(())
+    }
+
+    fn keyboard_leds(&mut self) -> u8 {
+        self.leds
+    }
+
+    fn wait_for_leds(
+        &mut self,
+        done: &mut dyn FnMut(u8) -> bool,
+        _: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        let Some(leds) = lint::simulate_leds(self.leds, done) else {
+            return Ok(false);
+        };
+        if leds != self.leds {
+            println!("WAIT_FOR\thost sets keyboard LEDs to {leds:03b}");
+            self.leds = leds;
+        }
+        Ok(true)
+    }
+
+    fn wait_for_button(&mut self, _: Option<Duration>) -> Result<bool, Self::Error> {
+        println!("WAIT_FOR_BUTTON\toperator presses the button");
+        Ok(true)
+    }
+}
+
+fn compile(
+    input: PathBuf,
+    output: Option<PathBuf>,
+    config: Option<PathBuf>,
+    layout: &str,
+) -> anyhow::Result<()> {
+    if !keycode::LAYOUTS.contains(&layout) {
+        anyhow::bail!(
+            "unsupported layout {layout:?} (available: {})",
+            keycode::LAYOUTS.join(", ")
+        );
+    }
+    let output = output.unwrap_or_else(|| input.with_extension(bytecode::FILE_EXTENSION));
+    // Templates are expanded when typing, which a compiled payload cannot do
+    let templates = match config {
+        Some(ref path) => config::Config::parse(&std::fs::read_to_string(path)?).templates(),
+        None => config::Config::default().templates(),
+    };
+    let (text, syntax) = payload_text(&input, config)?;
+    if templates && template::contains_placeholders(&std::fs::read_to_string(&input)?) {
+        anyhow::bail!(
+            "{} has template placeholders, which cannot be compiled",
+            input.display()
+        );
+    }
+
+    let name = input.file_name().map_or_else(
+        || input.display().to_string(),
+        |name| name.to_string_lossy().into(),
+    );
+    let compiled = match syntax {
+        Syntax::Plain => bytecode::compile_text(&text, &name, layout)?,
+        Syntax::Escapes => bytecode::compile_escaped(&escape::tokenize(&text)?, &name, layout)?,
+        Syntax::Script => bytecode::compile(&parse_script(&input, &text)?, layout)?,
+    };
+    std::fs::write(&output, &compiled)?;
+    eprintln!(
+        "Wrote {} ({} bytes from {})",
+        output.display(),
+        compiled.len(),
+        text.len()
+    );
+    Ok(())
+}
+
+fn encrypt(input: PathBuf, output: Option<PathBuf>, iterations: u32) -> anyhow::Result<()> {
+    let output = output.unwrap_or_else(|| {
+        let mut path = input.clone().into_os_string();
+        path.push(".enc");
+        path.into()
+    });
+
+    let plaintext = Zeroizing::new(std::fs::read(&input)?);
+    if crypto::is_encrypted(&plaintext) {
+        anyhow::bail!("{} is already encrypted", input.display());
+    }
+
+    let pin = read_pin("PIN (each digit is entered as that many clicks, 0 as ten): ")?;
+    crypto::validate_pin(&pin)?;
+    if *read_pin("Repeat PIN: ")? != *pin {
+        anyhow::bail!("PINs do not match");
+    }
+
+    let mut salt = [0u8; crypto::SALT_LENGTH];
+    let mut nonce = [0u8; crypto::NONCE_LENGTH];
+    getrandom::getrandom(&mut salt)?;
+    getrandom::getrandom(&mut nonce)?;
+
+    let encrypted = crypto::encrypt(&plaintext, &pin, &salt, &nonce, iterations, None)?;
+    std::fs::write(&output, encrypted)?;
+    eprintln!("Wrote {}", output.display());
+    Ok(())
+}
+
+fn read_pin(prompt: &str) -> anyhow::Result<Zeroizing<String>> {
+    eprint!("{prompt}");
+    std::io::stderr().flush()?;
+    let mut line = Zeroizing::new(String::new());
+    {
+        let _echo = EchoOff::new();
+        std::io::stdin().read_line(&mut line)?;
+    }
+    // The newline was not echoed either
+    eprintln!();
+    Ok(Zeroizing::new(line.trim().to_string()))
+}
+
+// Turns off terminal echo on stdin until dropped; does nothing if stdin is not a terminal
+struct EchoOff(Option<libc::termios>);
+
+impl EchoOff {
+    fn new() -> Self {
+        let mut saved = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) } != 0 {
+            return Self(None);
+        }
+        let saved = unsafe { saved.assume_init() };
+        let mut quiet = saved;
+        quiet.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        quiet.c_lflag |= libc::ICANON;
+        match unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) } {
+            0 => Self(Some(saved)),
+            _ => Self(None),
+        }
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// In-process stand-in for the device, answering requests like the firmware does with a local
+// directory as the drive. Text the device would type is written to stderr.
+//
+// Selected with `--mock DIR`, so that the tool (and the protocol) can be exercised without
+// hardware.
+
+use crate::client::Transport;
+use crate::console;
+use crate::protocol::{self, Command, Message, REPORT_SIZE};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct MockDevice {
+    root: PathBuf,
+    assembler: protocol::Assembler,
+    responses: VecDeque<[u8; REPORT_SIZE]>,
+}
+
+impl MockDevice {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            assembler: protocol::Assembler::new(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    // There is no boot to read `config.txt` at, so it is read for every request
+    fn ensure_host_writes(&self) -> anyhow::Result<()> {
+        let config = crate::config::Config::load(self.root.join(crate::config::FILE_NAME))?;
+        anyhow::ensure!(
+            config.host_writes(),
+            "writing from the host is disabled, see `host_writes` in {}",
+            crate::config::FILE_NAME
+        );
+        Ok(())
+    }
+
+    fn serve(&mut self, request: &Message) -> anyhow::Result<Vec<u8>> {
+        let config_path = self.root.join(crate::config::FILE_NAME);
+        match request.command {
+            Command::Status => Ok(b"mode: Keyboard\nslot: 0\nbus: Mounted\n".to_vec()),
+            Command::ReadFile => {
+                let path = console::relative_path(std::str::from_utf8(&request.data)?)?;
+                let content = std::fs::read(self.root.join(&path))?;
+                anyhow::ensure!(
+                    content.len() <= protocol::MAX_MESSAGE_SIZE,
+                    "{path} is too large to transfer ({} bytes)",
+                    content.len()
+                );
+                Ok(content)
+            }
+            Command::WriteFile => {
+                self.ensure_host_writes()?;
+                let (path, content) = protocol::split_path(&request.data)
+                    .ok_or_else(|| anyhow::anyhow!("malformed write request"))?;
+                let path = console::relative_path(path)?;
+                std::fs::write(self.root.join(path), content)?;
+                Ok(Vec::new())
+            }
+            Command::ReadConfig => match std::fs::read(config_path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                result => Ok(result?),
+            },
+            Command::WriteConfig => {
+                self.ensure_host_writes()?;
+                std::fs::write(config_path, &request.data)?;
+                Ok(Vec::new())
+            }
+            Command::Type => {
+                if request.data.is_empty() {
+                    eprintln!("[mock] typing the selected slot");
+                } else {
+                    let config = crate::config::Config::load(config_path)?;
+                    anyhow::ensure!(
+                        config.host_typing(),
+                        "typing from the host is disabled, see `host_typing` in {}",
+                        crate::config::FILE_NAME
+                    );
+                    let text = String::from_utf8_lossy(&request.data);
+                    eprintln!("[mock] typing {text:?}");
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+impl Transport for MockDevice {
+    fn write_report(&mut self, report: &[u8; REPORT_SIZE]) -> std::io::Result<()> {
+        // The firmware drops malformed requests without answering, and so does the mock
+        let Ok(Some(request)) = self.assembler.push(report) else {
+            return Ok(());
+        };
+        let response = match self.serve(&request) {
+            Ok(data) => Message::new(request.command, data),
+            Err(e) => Message::error(request.command, e),
+        };
+        self.responses.extend(response.encode());
+        Ok(())
+    }
+
+    fn read_report(&mut self, _timeout: Duration) -> std::io::Result<Option<[u8; REPORT_SIZE]>> {
+        Ok(self.responses.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+
+    // A drive of its own for every test, as they run in parallel
+    fn drive(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("autokbd-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn client(root: &PathBuf) -> Client<MockDevice> {
+        Client::new(MockDevice::new(root))
+    }
+
+    #[test]
+    fn status_and_type() {
+        let root = drive("status");
+        let mut client = client(&root);
+        assert!(client.status().unwrap().contains("mode: Keyboard"));
+        // The selected slot can always be typed, text only on request
+        client.type_text(None).unwrap();
+        let error = client.type_text(Some("hello")).unwrap_err();
+        assert!(error.to_string().contains("host_typing"), "{error}");
+
+        std::fs::write(root.join(crate::config::FILE_NAME), "host_typing = true\n").unwrap();
+        client.type_text(Some("hello")).unwrap();
+    }
+
+    #[test]
+    fn files_round_trip_across_reports() {
+        let root = drive("files");
+        std::fs::write(root.join(crate::config::FILE_NAME), "host_writes = true\n").unwrap();
+        let mut client = client(&root);
+
+        // Several reports each way
+        let content: Vec<u8> = (0..=255).cycle().take(REPORT_SIZE * 5 + 7).collect();
+        client.write_file("input.txt", &content).unwrap();
+        assert_eq!(std::fs::read(root.join("input.txt")).unwrap(), content);
+        assert_eq!(client.read_file("input.txt").unwrap(), content);
+
+        client.write_file("empty.txt", b"").unwrap();
+        assert_eq!(client.read_file("empty.txt").unwrap(), b"");
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let root = drive("config");
+        let mut client = client(&root);
+        // A missing file is the default configuration
+        assert_eq!(client.read_config().unwrap(), "");
+
+        std::fs::write(root.join(crate::config::FILE_NAME), "host_writes = true\n").unwrap();
+        let config = "host_writes = true\nslot.0.file = a.txt\n";
+        client.write_config(config).unwrap();
+        assert_eq!(client.read_config().unwrap(), config);
+    }
+
+    #[test]
+    fn errors_reach_the_client() {
+        let root = drive("errors");
+        let mut client = client(&root);
+
+        let error = client.write_file("input.txt", b"x").unwrap_err();
+        assert!(error.to_string().contains("host_writes"), "{error}");
+        assert!(client.write_config("read_only = true").is_err());
+        assert!(!root.join("input.txt").exists());
+        assert!(!root.join(crate::config::FILE_NAME).exists());
+
+        std::fs::write(
+            root.join(crate::config::FILE_NAME),
+            "host_writes = true\nread_only = true\n",
+        )
+        .unwrap();
+        assert!(client.write_file("input.txt", b"x").is_err());
+
+        let error = client.read_file("../secret").unwrap_err();
+        assert!(
+            error.to_string().starts_with("device: invalid path"),
+            "{error}"
+        );
+        assert!(client.read_file("missing.txt").is_err());
+        // The channel still works after errors
+        assert!(client.status().is_ok());
+    }
+
+    #[test]
+    fn oversized_writes_are_refused_before_sending() {
+        let mut client = client(&drive("oversized"));
+        let content = vec![0; protocol::MAX_MESSAGE_SIZE];
+        let error = client.write_file("big.txt", &content).unwrap_err();
+        assert!(error.to_string().contains("too large"), "{error}");
+    }
+
+    #[test]
+    fn oversized_files_are_not_read() {
+        let root = drive("oversized-read");
+        std::fs::write(
+            root.join("big.txt"),
+            vec![0; protocol::MAX_MESSAGE_SIZE + 1],
+        )
+        .unwrap();
+        let error = client(&root).read_file("big.txt").unwrap_err();
+        assert!(error.to_string().contains("too large"), "{error}");
+    }
+}
@@ -0,0 +1,1253 @@
+// Compiled payloads: plain text, escapes or a script turned into compact instructions, which a
+// small VM reads from the file as it runs them, so that a large payload never has to fit in RAM.
+// `autokbd compile` produces them, and the firmware compiles a script payload on boot.
+//
+// A compiled file is a header and the code, integers little-endian:
+//
+//     "AKBC", version u8
+//     keyboard layout: u8 length, UTF-8
+//     file names for error locations: u8 count, each u8 length, UTF-8
+//     variable names: u16 count, each u8 length, UTF-8
+//     code length u32, CRC-32 of the code u32
+//     code
+//
+// Expressions run on a small stack. Jump targets are code addresses, i.e. offsets from the
+// start of the code. Functions follow the main code, which ends with END.
+
+use crate::escape;
+use crate::payload::crc32;
+use crate::script::{
+    self, BinaryOp, Expr, Host, Limits, Location, Lock, LockState, RuntimeError, Script, Statement,
+    StatementKind, UnaryOp,
+};
+use crate::usb::keycode::{KeyChord, Modifiers, MAX_CHORD_KEYS};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Payload files with this extension are compiled
+pub const FILE_EXTENSION: &str = "kbc";
+
+pub const MAGIC: &[u8; 4] = b"AKBC";
+// Bump this whenever the format changes; older files then have to be compiled again
+pub const VERSION: u8 = 1;
+
+// Code read from the file at once; jumps within it do not read the file again
+const BUFFER_SIZE: usize = 512;
+// Text is typed in pieces of this many bytes
+const TEXT_CHUNK: usize = 256;
+// Deepest expression the compiler accepts, which bounds the VM's stack
+const MAX_STACK: usize = 64;
+
+mod op {
+    pub const END: u8 = 0x00;
+    // File index u8, line u32; counts a step
+    pub const LINE: u8 = 0x01;
+    // Address u32
+    pub const JUMP: u8 = 0x02;
+    // Address u32; pops the condition
+    pub const JUMP_IF_ZERO: u8 = 0x03;
+    // Address u32
+    pub const CALL: u8 = 0x04;
+    pub const RETURN: u8 = 0x05;
+    // Newline u8, length u32, UTF-8
+    pub const TYPE: u8 = 0x10;
+    // Each followed by a chord: modifiers u8, `MAX_CHORD_KEYS` usage IDs
+    pub const TAP: u8 = 0x11;
+    pub const PRESS: u8 = 0x12;
+    pub const RELEASE: u8 = 0x13;
+    // Pop milliseconds
+    pub const DELAY: u8 = 0x14;
+    pub const DEFAULT_DELAY: u8 = 0x15;
+    // i32
+    pub const PUSH: u8 = 0x20;
+    // Variable index u16
+    pub const LOAD: u8 = 0x21;
+    pub const STORE: u8 = 0x22;
+    pub const DECLARE: u8 = 0x23;
+    // Lock index u8
+    pub const LOCK: u8 = 0x24;
+    // Operator index u8
+    pub const UNARY: u8 = 0x25;
+    pub const BINARY: u8 = 0x26;
+    // Lock index u8, state index u8, has timeout u8; pops the timeout if there is one
+    pub const WAIT_FOR_LOCK: u8 = 0x30;
+    // Has timeout u8
+    pub const WAIT_FOR_BUTTON: u8 = 0x31;
+}
+
+// Operands are indices into these
+const LOCKS: [Lock; 3] = [Lock::Num, Lock::Caps, Lock::Scroll];
+const LOCK_STATES: [LockState; 3] = [LockState::On, LockState::Off, LockState::Change];
+const UNARY_OPS: [UnaryOp; 2] = [UnaryOp::Negate, UnaryOp::Not];
+// `And` and `Or` are compiled to jumps
+const BINARY_OPS: [BinaryOp; 11] = [
+    BinaryOp::Add,
+    BinaryOp::Subtract,
+    BinaryOp::Multiply,
+    BinaryOp::Divide,
+    BinaryOp::Remainder,
+    BinaryOp::Equal,
+    BinaryOp::NotEqual,
+    BinaryOp::Less,
+    BinaryOp::LessOrEqual,
+    BinaryOp::Greater,
+    BinaryOp::GreaterOrEqual,
+];
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    NotCompiled,
+    Version(u8),
+    // The layout it was compiled for
+    Layout(String),
+    Checksum,
+    Truncated,
+    Corrupt(&'static str),
+    // What does not fit the format
+    TooLarge(&'static str),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::NotCompiled => write!(f, "not a compiled payload"),
+            Self::Version(version) => write!(
+                f,
+                "compiled payload version {version} is not supported (expected {VERSION}), compile it again"
+            ),
+            Self::Layout(layout) => write!(f, "payload is compiled for the {layout:?} layout"),
+            Self::Checksum => write!(f, "compiled payload is damaged (checksum mismatch)"),
+            Self::Truncated => write!(f, "compiled payload is truncated"),
+            Self::Corrupt(what) => write!(f, "invalid compiled payload: {what}"),
+            Self::TooLarge(what) => write!(f, "too many {what} to compile"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError<E> {
+    // The file cannot be read or is not valid
+    Program(Error),
+    Runtime {
+        location: Location,
+        error: RuntimeError,
+    },
+    Host(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for RunError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Program(e) => write!(f, "{e}"),
+            Self::Runtime { location, error } => write!(f, "{location}: {error}"),
+            Self::Host(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for RunError<E> {}
+
+impl<E> From<Error> for RunError<E> {
+    fn from(e: Error) -> Self {
+        Self::Program(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub layout: String,
+    pub files: Vec<Arc<str>>,
+    pub variables: Vec<String>,
+    pub code_length: u32,
+    pub checksum: u32,
+}
+
+impl Header {
+    // Checks the magic and version, not the code
+    pub fn read(reader: &mut impl Read) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::NotCompiled);
+        }
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+        let layout = read_name(reader)?;
+        let files = (0..read_u8(reader)?)
+            .map(|_| read_name(reader).map(Arc::from))
+            .collect::<Result<_, _>>()?;
+        let mut count = [0; 2];
+        reader.read_exact(&mut count)?;
+        let variables = (0..u16::from_le_bytes(count))
+            .map(|_| read_name(reader))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            version,
+            layout,
+            files,
+            variables,
+            code_length: read_u32(reader)?,
+            checksum: read_u32(reader)?,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        write_name(out, &self.layout, "layout name bytes")?;
+        out.push(u8::try_from(self.files.len()).map_err(|_| Error::TooLarge("included files"))?);
+        for file in &self.files {
+            write_name(out, file, "file name bytes")?;
+        }
+        let variables =
+            u16::try_from(self.variables.len()).map_err(|_| Error::TooLarge("variables"))?;
+        out.extend_from_slice(&variables.to_le_bytes());
+        for variable in &self.variables {
+            write_name(out, variable, "variable name bytes")?;
+        }
+        out.extend_from_slice(&self.code_length.to_le_bytes());
+        out.extend_from_slice(&self.checksum.to_le_bytes());
+        Ok(())
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8, Error> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_name(reader: &mut impl Read) -> Result<String, Error> {
+    let mut name = vec![0; read_u8(reader)? as usize];
+    reader.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|_| Error::Corrupt("name is not UTF-8"))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str, what: &'static str) -> Result<(), Error> {
+    out.push(u8::try_from(name.len()).map_err(|_| Error::TooLarge(what))?);
+    out.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+pub fn compile(script: &Script, layout: &str) -> Result<Vec<u8>, Error> {
+    let mut compiler = Compiler::default();
+    compiler.block(&script.statements)?;
+    compiler.code.push(op::END);
+
+    // Sorted, so that the same script always compiles to the same file
+    let mut functions: Vec<_> = script.functions.iter().collect();
+    functions.sort_by_key(|(name, _)| *name);
+    let mut addresses = HashMap::new();
+    for (name, body) in functions {
+        addresses.insert(name.as_str(), compiler.code.len());
+        compiler.block(body)?;
+        compiler.code.push(op::RETURN);
+    }
+    for (at, name) in std::mem::take(&mut compiler.calls) {
+        // Checked by the parser
+        compiler.patch(at, addresses[name]);
+    }
+    compiler.finish(layout)
+}
+
+// A plain payload, typed as it is
+pub fn compile_text(text: &str, file: &str, layout: &str) -> Result<Vec<u8>, Error> {
+    let mut compiler = Compiler::default();
+    compiler.files.push(file.into());
+    compiler.text(text, false)?;
+    compiler.code.push(op::END);
+    compiler.finish(layout)
+}
+
+// A payload with inline escapes, see `escape::tokenize`
+pub fn compile_escaped(
+    tokens: &[escape::Token],
+    file: &str,
+    layout: &str,
+) -> Result<Vec<u8>, Error> {
+    use escape::Token;
+
+    let mut compiler = Compiler::default();
+    compiler.files.push(file.into());
+    for token in tokens {
+        match token {
+            Token::Text(text) => compiler.text(text, false)?,
+            Token::Tap { chord, count } => {
+                for _ in 0..*count {
+                    compiler.chord(op::TAP, chord);
+                }
+            }
+            Token::Press(chord) => compiler.chord(op::PRESS, chord),
+            Token::Release(chord) => compiler.chord(op::RELEASE, chord),
+            Token::Sleep(duration) => {
+                let ms = i32::try_from(duration.as_millis())
+                    .map_err(|_| Error::TooLarge("milliseconds"))?;
+                compiler.code.push(op::PUSH);
+                compiler.code.extend_from_slice(&ms.to_le_bytes());
+                compiler.code.push(op::DELAY);
+            }
+        }
+    }
+    compiler.code.push(op::END);
+    compiler.finish(layout)
+}
+
+#[derive(Default)]
+struct Compiler<'s> {
+    code: Vec<u8>,
+    files: Vec<Arc<str>>,
+    variables: Vec<&'s str>,
+    // Address operands to patch with the function's address once it is compiled
+    calls: Vec<(usize, &'s str)>,
+}
+
+impl<'s> Compiler<'s> {
+    fn finish(self, layout: &str) -> Result<Vec<u8>, Error> {
+        let header = Header {
+            version: VERSION,
+            layout: layout.into(),
+            files: self.files,
+            variables: self.variables.into_iter().map(String::from).collect(),
+            code_length: u32::try_from(self.code.len()).map_err(|_| Error::TooLarge("bytes"))?,
+            checksum: crc32(0, &self.code),
+        };
+        let mut out = Vec::with_capacity(self.code.len() + 64);
+        header.write(&mut out)?;
+        out.extend_from_slice(&self.code);
+        Ok(out)
+    }
+
+    fn block(&mut self, statements: &'s [Statement]) -> Result<(), Error> {
+        statements
+            .iter()
+            .try_for_each(|statement| self.statement(statement))
+    }
+
+    fn statement(&mut self, statement: &'s Statement) -> Result<(), Error> {
+        // Loops jump back here, so that each test counts a step like in the interpreter
+        let start = self.code.len();
+        let file = match self
+            .files
+            .iter()
+            .position(|file| *file == statement.location.file)
+        {
+            Some(index) => index,
+            None => {
+                self.files.push(statement.location.file.clone());
+                self.files.len() - 1
+            }
+        };
+        self.code.push(op::LINE);
+        self.code
+            .push(u8::try_from(file).map_err(|_| Error::TooLarge("included files"))?);
+        let line = u32::try_from(statement.location.line).map_err(|_| Error::TooLarge("lines"))?;
+        self.code.extend_from_slice(&line.to_le_bytes());
+
+        match &statement.kind {
+            StatementKind::Type { text, newline } => self.text(text, *newline)?,
+            StatementKind::Tap(chord) => self.chord(op::TAP, chord),
+            StatementKind::Hold(chord) => self.chord(op::PRESS, chord),
+            StatementKind::Release(chord) => self.chord(op::RELEASE, chord),
+            StatementKind::Delay(ms) => {
+                self.expression(ms)?;
+                self.code.push(op::DELAY);
+            }
+            StatementKind::DefaultDelay(ms) => {
+                self.expression(ms)?;
+                self.code.push(op::DEFAULT_DELAY);
+            }
+            StatementKind::Assign {
+                name,
+                value,
+                declare,
+            } => {
+                self.expression(value)?;
+                self.code
+                    .push(if *declare { op::DECLARE } else { op::STORE });
+                self.variable(name)?;
+            }
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                let mut ends = Vec::new();
+                for (condition, body) in branches {
+                    self.expression(condition)?;
+                    let next = self.jump(op::JUMP_IF_ZERO);
+                    self.block(body)?;
+                    ends.push(self.jump(op::JUMP));
+                    self.patch(next, self.code.len());
+                }
+                self.block(otherwise)?;
+                for end in ends {
+                    self.patch(end, self.code.len());
+                }
+            }
+            StatementKind::While { condition, body } => {
+                self.expression(condition)?;
+                let end = self.jump(op::JUMP_IF_ZERO);
+                self.block(body)?;
+                let back = self.jump(op::JUMP);
+                self.patch(back, start);
+                self.patch(end, self.code.len());
+            }
+            StatementKind::Call(name) => {
+                let at = self.jump(op::CALL);
+                self.calls.push((at, name));
+            }
+            StatementKind::Return => self.code.push(op::RETURN),
+            StatementKind::WaitForLock {
+                lock,
+                until,
+                timeout,
+            } => {
+                if let Some(timeout) = timeout {
+                    self.expression(timeout)?;
+                }
+                self.code.extend_from_slice(&[
+                    op::WAIT_FOR_LOCK,
+                    index(&LOCKS, *lock),
+                    index(&LOCK_STATES, *until),
+                    timeout.is_some() as u8,
+                ]);
+            }
+            StatementKind::WaitForButton { timeout } => {
+                if let Some(timeout) = timeout {
+                    self.expression(timeout)?;
+                }
+                self.code
+                    .extend_from_slice(&[op::WAIT_FOR_BUTTON, timeout.is_some() as u8]);
+            }
+        }
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str, newline: bool) -> Result<(), Error> {
+        let length = u32::try_from(text.len()).map_err(|_| Error::TooLarge("bytes"))?;
+        self.code.extend_from_slice(&[op::TYPE, newline as u8]);
+        self.code.extend_from_slice(&length.to_le_bytes());
+        self.code.extend_from_slice(text.as_bytes());
+        Ok(())
+    }
+
+    fn chord(&mut self, op: u8, chord: &KeyChord) {
+        let mut keys = [0; MAX_CHORD_KEYS];
+        keys.iter_mut()
+            .zip(chord.keys())
+            .for_each(|(slot, key)| *slot = key);
+        self.code.extend_from_slice(&[op, chord.modifiers.bits()]);
+        self.code.extend_from_slice(&keys);
+    }
+
+    fn variable(&mut self, name: &'s str) -> Result<(), Error> {
+        let index = match self.variables.iter().position(|variable| *variable == name) {
+            Some(index) => index,
+            None => {
+                self.variables.push(name);
+                self.variables.len() - 1
+            }
+        };
+        let index = u16::try_from(index).map_err(|_| Error::TooLarge("variables"))?;
+        self.code.extend_from_slice(&index.to_le_bytes());
+        Ok(())
+    }
+
+    // Emits a jump and returns where its address goes
+    fn jump(&mut self, op: u8) -> usize {
+        self.code.push(op);
+        self.code.extend_from_slice(&[0; 4]);
+        self.code.len() - 4
+    }
+
+    // `finish` checks that every address fits
+    fn patch(&mut self, at: usize, address: usize) {
+        self.code[at..at + 4].copy_from_slice(&(address as u32).to_le_bytes());
+    }
+
+    fn expression(&mut self, expr: &'s Expr) -> Result<(), Error> {
+        if stack_depth(expr) > MAX_STACK {
+            return Err(Error::TooLarge("nested operators"));
+        }
+        self.push(expr)
+    }
+
+    fn push(&mut self, expr: &'s Expr) -> Result<(), Error> {
+        match expr {
+            Expr::Number(number) => {
+                self.code.push(op::PUSH);
+                self.code.extend_from_slice(&number.to_le_bytes());
+            }
+            Expr::Variable(name) => {
+                self.code.push(op::LOAD);
+                self.variable(name)?;
+            }
+            Expr::Lock(lock) => self
+                .code
+                .extend_from_slice(&[op::LOCK, index(&LOCKS, *lock)]),
+            Expr::Unary(op, operand) => {
+                self.push(operand)?;
+                self.code
+                    .extend_from_slice(&[op::UNARY, index(&UNARY_OPS, *op)]);
+            }
+            // Short-circuit like the interpreter; `!!` turns the right side into 0 or 1
+            Expr::Binary(BinaryOp::And, left, right) => {
+                self.push(left)?;
+                let short = self.jump(op::JUMP_IF_ZERO);
+                self.push(right)?;
+                self.not_not();
+                let end = self.jump(op::JUMP);
+                self.patch(short, self.code.len());
+                self.code.push(op::PUSH);
+                self.code.extend_from_slice(&0i32.to_le_bytes());
+                self.patch(end, self.code.len());
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                self.push(left)?;
+                let other = self.jump(op::JUMP_IF_ZERO);
+                self.code.push(op::PUSH);
+                self.code.extend_from_slice(&1i32.to_le_bytes());
+                let end = self.jump(op::JUMP);
+                self.patch(other, self.code.len());
+                self.push(right)?;
+                self.not_not();
+                self.patch(end, self.code.len());
+            }
+            Expr::Binary(op, left, right) => {
+                self.push(left)?;
+                self.push(right)?;
+                self.code
+                    .extend_from_slice(&[op::BINARY, index(&BINARY_OPS, *op)]);
+            }
+        }
+        Ok(())
+    }
+
+    fn not_not(&mut self) {
+        let not = index(&UNARY_OPS, UnaryOp::Not);
+        self.code
+            .extend_from_slice(&[op::UNARY, not, op::UNARY, not]);
+    }
+}
+
+// Values on the stack while `expr` is evaluated
+fn stack_depth(expr: &Expr) -> usize {
+    match expr {
+        Expr::Number(_) | Expr::Variable(_) | Expr::Lock(_) => 1,
+        Expr::Unary(_, operand) => stack_depth(operand),
+        // The left value is popped before the right side runs
+        Expr::Binary(BinaryOp::And | BinaryOp::Or, left, right) => {
+            stack_depth(left).max(stack_depth(right))
+        }
+        Expr::Binary(_, left, right) => stack_depth(left).max(1 + stack_depth(right)),
+    }
+}
+
+fn index<T: PartialEq>(table: &[T], item: T) -> u8 {
+    table
+        .iter()
+        .position(|entry| *entry == item)
+        .expect("every operand is in its table") as u8
+}
+
+// A compiled payload whose header and checksum have been checked
+pub struct Program<R> {
+    header: Header,
+    code: Code<R>,
+}
+
+impl<R: Read + Seek> Program<R> {
+    // Reads the header and checks the whole code against its checksum, without keeping it;
+    // any layout is accepted without `layout`. `reader` needs no buffer, the program has one.
+    pub fn load(mut reader: R, layout: Option<&str>) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = Header::read(&mut reader)?;
+        if layout.is_some() && layout != Some(header.layout.as_str()) {
+            return Err(Error::Layout(header.layout));
+        }
+        let start = reader.stream_position()?;
+
+        let mut buffer = [0; BUFFER_SIZE];
+        let mut crc = 0;
+        let mut remaining = header.code_length as usize;
+        while remaining > 0 {
+            let chunk = remaining.min(BUFFER_SIZE);
+            reader.read_exact(&mut buffer[..chunk])?;
+            crc = crc32(crc, &buffer[..chunk]);
+            remaining -= chunk;
+        }
+        if crc != header.checksum {
+            return Err(Error::Checksum);
+        }
+
+        let code = Code {
+            reader,
+            start,
+            length: header.code_length,
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            buffered_at: 0,
+            buffered: 0,
+            address: 0,
+        };
+        Ok(Self { header, code })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+// The code of a program, read through a buffer
+struct Code<R> {
+    reader: R,
+    // File offset of address 0
+    start: u64,
+    length: u32,
+    buffer: Box<[u8]>,
+    // Address of `buffer[0]`, and how much of the buffer is valid
+    buffered_at: u32,
+    buffered: usize,
+    address: u32,
+}
+
+impl<R: Read + Seek> Code<R> {
+    fn read(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        if out.len() > (self.length - self.address) as usize {
+            return Err(Error::Corrupt("code runs past its end"));
+        }
+        let mut filled = 0;
+        while filled < out.len() {
+            let offset = self.address.wrapping_sub(self.buffered_at) as usize;
+            if self.address < self.buffered_at || offset >= self.buffered {
+                self.fill()?;
+                continue;
+            }
+            let count = (self.buffered - offset).min(out.len() - filled);
+            out[filled..filled + count].copy_from_slice(&self.buffer[offset..offset + count]);
+            filled += count;
+            self.address += count as u32;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        let count = ((self.length - self.address) as usize).min(self.buffer.len());
+        self.reader
+            .seek(SeekFrom::Start(self.start + self.address as u64))?;
+        self.reader.read_exact(&mut self.buffer[..count])?;
+        self.buffered_at = self.address;
+        self.buffered = count;
+        Ok(())
+    }
+
+    fn jump(&mut self, address: u32) -> Result<(), Error> {
+        if address >= self.length {
+            return Err(Error::Corrupt("jump past the end of the code"));
+        }
+        self.address = address;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        let mut bytes = [0];
+        self.read(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        self.read(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn operand<T: Copy>(&mut self, table: &[T]) -> Result<T, Error> {
+        let index = self.u8()?;
+        table
+            .get(index as usize)
+            .copied()
+            .ok_or(Error::Corrupt("unknown operand"))
+    }
+
+    fn chord(&mut self) -> Result<KeyChord, Error> {
+        let mut chord = KeyChord::from(Modifiers::from_bits_retain(self.u8()?));
+        let mut keys = [0; MAX_CHORD_KEYS];
+        self.read(&mut keys)?;
+        for key in keys.into_iter().filter(|&key| key != 0) {
+            chord
+                .push_key(key)
+                .map_err(|_| Error::Corrupt("invalid chord"))?;
+        }
+        Ok(chord)
+    }
+}
+
+// Runs a program from its start with the same limits and semantics as `script::run`
+pub fn run<R: Read + Seek, H: Host>(
+    program: &mut Program<R>,
+    host: &mut H,
+    limits: Limits,
+) -> Result<(), RunError<H::Error>> {
+    program.code.address = 0;
+    let location = Location {
+        file: program
+            .header
+            .files
+            .first()
+            .cloned()
+            .unwrap_or_else(|| "".into()),
+        line: 0,
+    };
+    let mut vm = Vm {
+        code: &mut program.code,
+        header: &program.header,
+        host,
+        limits,
+        location,
+        stack: Vec::new(),
+        variables: vec![None; program.header.variables.len()],
+        calls: Vec::new(),
+        default_delay: Duration::ZERO,
+        steps: 0,
+    };
+    vm.run()
+}
+
+struct Vm<'p, 'h, R, H: Host> {
+    code: &'p mut Code<R>,
+    header: &'p Header,
+    host: &'h mut H,
+    limits: Limits,
+    // Of the last LINE
+    location: Location,
+    stack: Vec<i32>,
+    variables: Vec<Option<i32>>,
+    // Return addresses
+    calls: Vec<u32>,
+    default_delay: Duration,
+    steps: u32,
+}
+
+impl<R: Read + Seek, H: Host> Vm<'_, '_, R, H> {
+    fn run(&mut self) -> Result<(), RunError<H::Error>> {
+        loop {
+            match self.code.u8()? {
+                op::END => return Ok(()),
+                op::LINE => {
+                    let file = self.code.u8()?;
+                    let line = self.code.u32()?;
+                    self.location = Location {
+                        file: self
+                            .header
+                            .files
+                            .get(file as usize)
+                            .cloned()
+                            .ok_or(Error::Corrupt("unknown file"))?,
+                        line: line as usize,
+                    };
+                    self.steps += 1;
+                    if self.steps > self.limits.max_steps {
+                        return Err(self.runtime(RuntimeError::StepLimit(self.limits.max_steps)));
+                    }
+                }
+                op::JUMP => {
+                    let address = self.code.u32()?;
+                    self.code.jump(address)?;
+                }
+                op::JUMP_IF_ZERO => {
+                    let address = self.code.u32()?;
+                    if self.pop()? == 0 {
+                        self.code.jump(address)?;
+                    }
+                }
+                op::CALL => {
+                    let address = self.code.u32()?;
+                    if self.calls.len() >= self.limits.max_depth {
+                        return Err(
+                            self.runtime(RuntimeError::RecursionLimit(self.limits.max_depth))
+                        );
+                    }
+                    self.calls.push(self.code.address);
+                    self.code.jump(address)?;
+                }
+                // RETURN outside of a function ends the script
+                op::RETURN => match self.calls.pop() {
+                    Some(address) => self.code.jump(address)?,
+                    None => return Ok(()),
+                },
+                op::TYPE => {
+                    let newline = self.code.u8()? != 0;
+                    let length = self.code.u32()?;
+                    self.type_text(length)?;
+                    if newline {
+                        self.host.type_text("\n").map_err(RunError::Host)?;
+                    }
+                    self.pause()?;
+                }
+                op::TAP => {
+                    let chord = self.code.chord()?;
+                    self.host.tap(chord).map_err(RunError::Host)?;
+                    self.pause()?;
+                }
+                op::PRESS => {
+                    let chord = self.code.chord()?;
+                    self.host.press(chord).map_err(RunError::Host)?;
+                    self.pause()?;
+                }
+                op::RELEASE => {
+                    let chord = self.code.chord()?;
+                    self.host.release(chord).map_err(RunError::Host)?;
+                    self.pause()?;
+                }
+                op::DELAY => {
+                    let duration = self.duration()?;
+                    self.host.sleep(duration).map_err(RunError::Host)?;
+                }
+                op::DEFAULT_DELAY => self.default_delay = self.duration()?,
+                op::PUSH => {
+                    let value = self.code.u32()? as i32;
+                    self.push(value)?;
+                }
+                op::LOAD => {
+                    let index = self.code.u16()? as usize;
+                    let value = *self
+                        .variables
+                        .get(index)
+                        .ok_or(Error::Corrupt("unknown variable"))?;
+                    let value = value.ok_or_else(|| {
+                        let name = self.header.variables[index].clone();
+                        self.runtime(RuntimeError::UndefinedVariable(name))
+                    })?;
+                    self.push(value)?;
+                }
+                code @ (op::STORE | op::DECLARE) => {
+                    let index = self.code.u16()? as usize;
+                    let value = self.pop()?;
+                    let defined = match self.variables.get(index) {
+                        Some(variable) => variable.is_some(),
+                        None => return Err(Error::Corrupt("unknown variable").into()),
+                    };
+                    if code == op::STORE && !defined {
+                        let name = self.header.variables[index].clone();
+                        return Err(self.runtime(RuntimeError::UndefinedVariable(name)));
+                    }
+                    self.variables[index] = Some(value);
+                }
+                op::LOCK => {
+                    let lock = self.code.operand(&LOCKS)?;
+                    let on = self.host.keyboard_leds() & lock.mask() != 0;
+                    self.push(on as i32)?;
+                }
+                op::UNARY => {
+                    let op = self.code.operand(&UNARY_OPS)?;
+                    let value = self.pop()?;
+                    let value = op.apply(value).map_err(|e| self.runtime(e))?;
+                    self.push(value)?;
+                }
+                op::BINARY => {
+                    let op = self.code.operand(&BINARY_OPS)?;
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    let value = op.apply(left, right).map_err(|e| self.runtime(e))?;
+                    self.push(value)?;
+                }
+                op::WAIT_FOR_LOCK => {
+                    let lock = self.code.operand(&LOCKS)?;
+                    let until = self.code.operand(&LOCK_STATES)?;
+                    let timeout = self.timeout()?;
+                    let done = script::wait_for_lock(self.host, lock, until, timeout);
+                    self.waited(done, timeout)?;
+                }
+                op::WAIT_FOR_BUTTON => {
+                    let timeout = self.timeout()?;
+                    let pressed = self.host.wait_for_button(timeout);
+                    self.waited(pressed, timeout)?;
+                }
+                _ => return Err(Error::Corrupt("unknown instruction").into()),
+            }
+        }
+    }
+
+    // Streams `length` bytes of text to the host, never splitting a character
+    fn type_text(&mut self, mut length: u32) -> Result<(), RunError<H::Error>> {
+        // Room for the start of a character left over from the previous chunk
+        let mut buffer = [0; TEXT_CHUNK + 3];
+        let mut pending = 0;
+        while length > 0 {
+            let chunk = (length as usize).min(TEXT_CHUNK);
+            self.code.read(&mut buffer[pending..pending + chunk])?;
+            length -= chunk as u32;
+            let filled = pending + chunk;
+            let valid = match std::str::from_utf8(&buffer[..filled]) {
+                Ok(text) => text.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => return Err(Error::Corrupt("text is not UTF-8").into()),
+            };
+            if valid > 0 {
+                let text = std::str::from_utf8(&buffer[..valid]).expect("checked above");
+                self.host.type_text(text).map_err(RunError::Host)?;
+            }
+            buffer.copy_within(valid..filled, 0);
+            pending = filled - valid;
+        }
+        if pending > 0 {
+            return Err(Error::Corrupt("text is not UTF-8").into());
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), RunError<H::Error>> {
+        if self.default_delay.is_zero() {
+            return Ok(());
+        }
+        self.host.sleep(self.default_delay).map_err(RunError::Host)
+    }
+
+    fn duration(&mut self) -> Result<Duration, RunError<H::Error>> {
+        match self.pop()? {
+            ms if ms < 0 => Err(self.runtime(RuntimeError::NegativeDelay(ms))),
+            ms => Ok(Duration::from_millis(ms as u64)),
+        }
+    }
+
+    fn timeout(&mut self) -> Result<Option<Duration>, RunError<H::Error>> {
+        match self.code.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.duration()?)),
+        }
+    }
+
+    fn waited(
+        &self,
+        done: Result<bool, H::Error>,
+        timeout: Option<Duration>,
+    ) -> Result<(), RunError<H::Error>> {
+        match done {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(self.runtime(RuntimeError::Timeout(timeout.unwrap_or_default()))),
+            Err(e) => Err(RunError::Host(e)),
+        }
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), Error> {
+        if self.stack.len() >= MAX_STACK {
+            return Err(Error::Corrupt("stack overflow"));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i32, Error> {
+        self.stack.pop().ok_or(Error::Corrupt("stack underflow"))
+    }
+
+    fn runtime(&self, error: RuntimeError) -> RunError<H::Error> {
+        RunError::Runtime {
+            location: self.location.clone(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::testing::{Event, Recorder};
+    use std::convert::Infallible;
+    use std::io::Cursor;
+
+    type Outcome = (Vec<Event>, Result<(), (String, RuntimeError)>);
+
+    fn parse(text: &str) -> Script {
+        script::parse(text, "test.ducky", &mut |path| match path {
+            "lib.ducky" => Ok("FUNCTION LIB()\nSTRING lib\nEND_FUNCTION".into()),
+            _ => Err(std::io::ErrorKind::NotFound.into()),
+        })
+        .unwrap()
+    }
+
+    fn load(compiled: Vec<u8>) -> Program<Cursor<Vec<u8>>> {
+        Program::load(Cursor::new(compiled), Some("us")).unwrap()
+    }
+
+    fn interpret(text: &str, host: Recorder, limits: Limits) -> Outcome {
+        let mut host = host;
+        let result = script::run(&parse(text), &mut host, limits).map_err(|e| match e {
+            script::RunError::Runtime { location, error } => (location.to_string(), error),
+            script::RunError::Host(e) => match e {},
+        });
+        (host.events, result)
+    }
+
+    fn execute(compiled: Vec<u8>, host: Recorder, limits: Limits) -> Outcome {
+        let mut host = host;
+        let result = run(&mut load(compiled), &mut host, limits).map_err(|e| match e {
+            RunError::Runtime { location, error } => (location.to_string(), error),
+            RunError::Program(e) => panic!("{e}"),
+            RunError::Host(e) => match e {},
+        });
+        (host.events, result)
+    }
+
+    // Runs `text` both ways, which must do the same
+    fn same(text: &str, host: impl Fn() -> Recorder, limits: Limits) -> Outcome {
+        let interpreted = interpret(text, host(), limits);
+        let compiled = execute(compile(&parse(text), "us").unwrap(), host(), limits);
+        assert_eq!(compiled, interpreted, "{text}");
+        interpreted
+    }
+
+    fn same_by_default(text: &str) -> Outcome {
+        same(text, Recorder::default, Limits::DEFAULT)
+    }
+
+    fn failure(location: &str, error: RuntimeError) -> Result<(), (String, RuntimeError)> {
+        Err((format!("test.ducky:{location}"), error))
+    }
+
+    const SCRIPTS: &[&str] = &[
+        "STRINGLN hello\nSTRING  world\nDEFAULT_DELAY 20\nGUI r\nHOLD SHIFT\nDELAY 5\nRELEASE SHIFT",
+        "VAR $a = 2\nVAR $b = $a * 3 + 1\n$a = $b - $a\nDELAY $a\nDELAY $b % 4\nDELAY -(-$a) / 2",
+        "VAR $n = 0\nDELAY $n != 0 && 10 / $n > 1\nDELAY $n == 0 || 10 / $n > 1\nDELAY 2 && 3",
+        "VAR $n = 2\nIF $n == 1 THEN\nSTRING one\nELSE IF $n == 2\nSTRING two\nELSE\nSTRING many\nEND_IF",
+        "VAR $n = 5\nIF $n < 3\nSTRING small\nELSE\nSTRING big\nEND_IF",
+        "VAR $i = 0\nWHILE $i < 3\nVAR $j = $i\nWHILE $j > 0\nSTRING x\n$j = $j - 1\nEND_WHILE\nSTRING |\n$i = $i + 1\nEND_WHILE",
+        "FUNCTION F()\nSTRING a\nIF TRUE THEN\nRETURN\nEND_IF\nSTRING b\nEND_FUNCTION\nF()\nF()\nSTRING c",
+        "FUNCTION G()\nVAR $k = 0\nWHILE TRUE\n$k = $k + 1\nIF $k == 3 THEN\nRETURN\nEND_IF\nEND_WHILE\nEND_FUNCTION\nG()\nDELAY $k",
+        "STRING a\nRETURN\nSTRING b",
+        "INCLUDE lib.ducky\nLIB()\nSTRING main",
+        "IF CAPS_LOCK && !NUM_LOCK THEN\nSTRING caps\nEND_IF\nWAIT_FOR_CAPS_ON\nWAIT_FOR_SCROLL_OFF timeout=5",
+    ];
+
+    #[test]
+    fn runs_like_the_interpreter() {
+        for text in SCRIPTS {
+            let (events, result) = same(
+                text,
+                || Recorder {
+                    leds: Lock::Caps.mask(),
+                    pressed: true,
+                    ..Default::default()
+                },
+                Limits::DEFAULT,
+            );
+            assert_eq!(result, Ok(()), "{text}");
+            assert!(!events.is_empty(), "{text}");
+        }
+        assert_eq!(same_by_default(SCRIPTS[6]).0, [Event::Type("aac".into())]);
+    }
+
+    #[test]
+    fn fails_like_the_interpreter() {
+        let cases = [
+            (
+                "STRING a\n$x = 1",
+                "2",
+                RuntimeError::UndefinedVariable("x".into()),
+            ),
+            (
+                "VAR $a = $b",
+                "1",
+                RuntimeError::UndefinedVariable("b".into()),
+            ),
+            (
+                "STRING a\nDELAY 1 / (1 - 1)",
+                "2",
+                RuntimeError::DivisionByZero,
+            ),
+            ("DELAY 2147483647 + 1", "1", RuntimeError::Overflow),
+            (
+                "VAR $m = -2147483647 - 1\nDELAY -$m",
+                "2",
+                RuntimeError::Overflow,
+            ),
+            ("DELAY 0 - 5", "1", RuntimeError::NegativeDelay(-5)),
+            (
+                "STRING a\nWAIT_FOR_BUTTON timeout=30",
+                "2",
+                RuntimeError::Timeout(Duration::from_millis(30)),
+            ),
+            (
+                "WHILE TRUE\nSTRING x\nEND_WHILE",
+                "1",
+                RuntimeError::StepLimit(Limits::DEFAULT.max_steps),
+            ),
+            (
+                "FUNCTION F()\nSTRING f\nF()\nEND_FUNCTION\nF()",
+                "3",
+                RuntimeError::RecursionLimit(Limits::DEFAULT.max_depth),
+            ),
+        ];
+        for (text, location, error) in cases {
+            assert_eq!(same_by_default(text).1, failure(location, error), "{text}");
+        }
+    }
+
+    #[test]
+    fn limits() {
+        // Loop tests count as steps in both
+        // VAR and WHILE, then per pass its 2 statements and the next loop test
+        for max_steps in 1..14 {
+            let limits = Limits {
+                max_steps,
+                ..Limits::DEFAULT
+            };
+            let text = "VAR $i = 0\nWHILE $i < 3\n$i = $i + 1\nSTRING x\nEND_WHILE";
+            let (_, result) = same(text, Recorder::default, limits);
+            assert_eq!(result.is_ok(), max_steps >= 11, "{max_steps}");
+        }
+        let text = "FUNCTION A()\nB()\nEND_FUNCTION\nFUNCTION B()\nSTRING b\nEND_FUNCTION\nA()";
+        for max_depth in 0..3 {
+            let limits = Limits {
+                max_depth,
+                ..Limits::DEFAULT
+            };
+            let (_, result) = same(text, Recorder::default, limits);
+            assert_eq!(result.is_ok(), max_depth >= 2, "{max_depth}");
+        }
+    }
+
+    #[test]
+    fn long_text() {
+        // Longer than the code buffer and text chunks, with multibyte characters across them
+        let text = "aé€😀".repeat(200);
+        let script = format!("VAR $i = 0\nWHILE $i < 2\nSTRING {text}\n$i = $i + 1\nEND_WHILE");
+        let (events, result) = same_by_default(&script);
+        assert_eq!(result, Ok(()));
+        assert_eq!(events, [Event::Type(text.repeat(2))]);
+
+        let compiled = compile_text("ab\n€", "plain.txt", "us").unwrap();
+        let (events, _) = execute(compiled, Recorder::default(), Limits::DEFAULT);
+        assert_eq!(events, [Event::Type("ab\n€".into())]);
+    }
+
+    #[test]
+    fn deterministic() {
+        let text = "FUNCTION B()\nSTRING b\nEND_FUNCTION\nFUNCTION A()\nB()\nEND_FUNCTION\nA()";
+        let compiled = compile(&parse(text), "us").unwrap();
+        for _ in 0..8 {
+            assert_eq!(compile(&parse(text), "us").unwrap(), compiled);
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let compiled = compile(&parse(SCRIPTS[5]), "us").unwrap();
+        let header = Header::read(&mut compiled.as_slice()).unwrap();
+        let code_start = compiled.len() - header.code_length as usize;
+        let load = |bytes: &[u8]| Program::load(Cursor::new(bytes.to_vec()), Some("us"));
+        assert_eq!(load(&compiled).unwrap().header(), &header);
+
+        for length in 0..compiled.len() {
+            assert!(
+                matches!(load(&compiled[..length]), Err(Error::Truncated)),
+                "{length}"
+            );
+        }
+        for at in code_start..compiled.len() {
+            let mut damaged = compiled.clone();
+            damaged[at] ^= 0x40;
+            assert!(matches!(load(&damaged), Err(Error::Checksum)), "{at}");
+        }
+
+        let mut damaged = compiled.clone();
+        damaged[0] = b'X';
+        assert!(matches!(load(&damaged), Err(Error::NotCompiled)));
+        damaged = compiled.clone();
+        damaged[4] = VERSION + 1;
+        assert!(matches!(load(&damaged), Err(Error::Version(v)) if v == VERSION + 1));
+        assert!(matches!(
+            Program::load(Cursor::new(compiled.clone()), Some("de")),
+            Err(Error::Layout(layout)) if layout == "us"
+        ));
+        assert!(Program::load(Cursor::new(compiled), None).is_ok());
+    }
+
+    // A file with valid checksum around `code`
+    fn with_code(code: &[u8], variables: &[&str]) -> Vec<u8> {
+        let header = Header {
+            version: VERSION,
+            layout: "us".into(),
+            files: vec!["test.ducky".into()],
+            variables: variables.iter().map(|name| name.to_string()).collect(),
+            code_length: code.len() as u32,
+            checksum: crc32(0, code),
+        };
+        let mut out = Vec::new();
+        header.write(&mut out).unwrap();
+        out.extend_from_slice(code);
+        out
+    }
+
+    #[test]
+    fn rejects_invalid_code() {
+        let line = [op::LINE, 0, 1, 0, 0, 0];
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xff], "unknown instruction"),
+            (&[op::PUSH, 1, 0], "code runs past its end"),
+            (&[op::JUMP, 0xff, 0, 0, 0], "jump past the end of the code"),
+            (&[op::DELAY, op::END], "stack underflow"),
+            (&[op::LOAD, 3, 0, op::END], "unknown variable"),
+            (&[op::LINE, 7, 1, 0, 0, 0, op::END], "unknown file"),
+            (
+                &[op::PUSH, 0, 0, 0, 0, op::UNARY, 9, op::END],
+                "unknown operand",
+            ),
+            (
+                &[op::TYPE, 0, 2, 0, 0, 0, 0xc3, b'(', op::END],
+                "text is not UTF-8",
+            ),
+            (
+                &[op::TYPE, 0, 1, 0, 0, 0, 0xc3, op::END],
+                "text is not UTF-8",
+            ),
+        ];
+        for (code, expected) in cases {
+            let code = [&line[..], code].concat();
+            let mut program = load(with_code(&code, &["a"]));
+            let result = run(&mut program, &mut Recorder::default(), Limits::DEFAULT);
+            match result {
+                Err(RunError::<Infallible>::Program(Error::Corrupt(what))) => {
+                    assert_eq!(what, *expected, "{code:?}")
+                }
+                result => panic!("{code:?}: {result:?}"),
+            }
+        }
+
+        // Pushing forever overflows the stack instead of memory
+        let push_loop = [&line[..], &[op::PUSH, 1, 0, 0, 0, op::JUMP, 6, 0, 0, 0]].concat();
+        let mut program = load(with_code(&push_loop, &[]));
+        assert!(matches!(
+            run(&mut program, &mut Recorder::default(), Limits::DEFAULT),
+            Err(RunError::Program(Error::Corrupt("stack overflow")))
+        ));
+    }
+}
@@ -0,0 +1,308 @@
+// Device configuration stored as `config.txt` on the storage partition.
+//
+// The format is a flat list of `key = value` lines. Empty lines and lines
+// starting with `#` are ignored, e.g.
+//
+//     # expose the drive read-only when started in MSC mode
+//     read_only = true
+//     slot.1.file = hostname.txt
+//     var.domain = example.com
+//     slot.2.mode = hotp
+//     slot.0.autorun = true
+
+use std::collections::HashMap;
+
+pub const FILE_NAME: &str = "config.txt";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotMode {
+    // Type the payload file
+    Payload,
+    // Type a one-time password, see `crate::hotp`
+    Hotp,
+    // Run the payload file as a script, see `crate::script`
+    Script,
+}
+
+// Type the slot automatically once per power cycle, without a button press
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Autorun {
+    // Settle time after the host has enumerated the keyboard
+    pub delay_ms: u32,
+    // Also wait for the host to set the keyboard LEDs
+    pub wait_for_leds: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Self {
+        let (config, problems) = Self::parse_lines(text);
+        for problem in problems {
+            log::warn!("{problem}");
+        }
+        config
+    }
+
+    // Returns the problems with `text` instead of logging them: lines that are not `key = value`,
+    // and values the firmware ignores, which are otherwise only reported once they are used
+    pub fn check(text: &str) -> (Self, Vec<String>) {
+        let (config, mut problems) = Self::parse_lines(text);
+        let mut keys: Vec<&String> = config.entries.keys().collect();
+        keys.sort();
+        problems.extend(keys.into_iter().filter_map(|key| config.value_problem(key)));
+        (config, problems)
+    }
+
+    fn parse_lines(text: &str) -> (Self, Vec<String>) {
+        let mut entries = HashMap::new();
+        let mut problems = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    entries.insert(key.trim().to_lowercase(), value.trim().to_string());
+                }
+                None => problems.push(format!("config line {}: missing '=': {line:?}", number + 1)),
+            }
+        }
+
+        (Self { entries }, problems)
+    }
+
+    // What reading `key` with its getter would warn about
+    fn value_problem(&self, key: &str) -> Option<String> {
+        let value = self.get(key)?;
+        let kind = Kind::of(key)?;
+        (!kind.accepts(value)).then(|| expected(key, kind.description(), value))
+    }
+
+    // A missing file is not an error: every key has a default value
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        let value = self.get(key)?;
+        let parsed = parse_bool(value);
+        if parsed.is_none() {
+            log::warn!("{}", expected(key, Kind::Boolean.description(), value));
+        }
+        parsed
+    }
+
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        let value = self.get(key)?;
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            log::warn!("{}", expected(key, Kind::Number.description(), value));
+        }
+        parsed
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.get_bool("read_only").unwrap_or(false)
+    }
+
+    // Lets the host tool write files and the configuration over the host channel;
+    // `read_only` takes precedence
+    pub fn host_writes(&self) -> bool {
+        self.get_bool("host_writes").unwrap_or(false) && !self.read_only()
+    }
+
+    // Lets the host tool type text over the host channel; typing the selected slot is always
+    // allowed, like pressing the button
+    pub fn host_typing(&self) -> bool {
+        self.get_bool("host_typing").unwrap_or(false)
+    }
+
+    // Size cap of `logs/boot.log` in bytes; 0 disables the log file
+    pub fn log_max_size(&self) -> u32 {
+        self.get_u32("log.max_size").unwrap_or(64 * 1024)
+    }
+
+    pub fn templates(&self) -> bool {
+        self.get_bool("templates").unwrap_or(false)
+    }
+
+    // Inline escapes in every payload, see `crate::escape`
+    pub fn escapes(&self) -> bool {
+        self.get_bool("escapes").unwrap_or(false)
+    }
+
+    // User-defined template variables, `var.NAME = value`
+    pub fn variables(&self) -> HashMap<String, String> {
+        self.entries
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("var.")?.into(), value.clone())))
+            .collect()
+    }
+
+    // Payload file typed for the given slot, relative to the drive root
+    pub fn payload_file(&self, slot: u8) -> String {
+        match self.get(&format!("slot.{slot}.file")) {
+            Some(file) => file.to_string(),
+            None if slot == 0 => "input.txt".into(),
+            None => format!("input{slot}.txt"),
+        }
+    }
+
+    pub fn slot_mode(&self, slot: u8) -> SlotMode {
+        let key = format!("slot.{slot}.mode");
+        let Some(value) = self.get(&key) else {
+            return SlotMode::Payload;
+        };
+        parse_slot_mode(value).unwrap_or_else(|| {
+            log::warn!("{}", expected(&key, Kind::SlotMode.description(), value));
+            SlotMode::Payload
+        })
+    }
+
+    pub fn autorun(&self, slot: u8) -> Option<Autorun> {
+        if !self.get_bool(&format!("slot.{slot}.autorun")).unwrap_or(false) {
+            return None;
+        }
+        Some(Autorun {
+            delay_ms: self
+                .get_u32(&format!("slot.{slot}.autorun_delay"))
+                .unwrap_or(1000),
+            wait_for_leds: self
+                .get_bool(&format!("slot.{slot}.autorun_wait_leds"))
+                .unwrap_or(false),
+        })
+    }
+}
+
+// Values of known keys that can be wrong
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Boolean,
+    Number,
+    SlotMode,
+    Color,
+}
+
+impl Kind {
+    fn of(key: &str) -> Option<Self> {
+        // The part after `slot.N.` of slot keys
+        let slot_key = key
+            .strip_prefix("slot.")
+            .and_then(|rest| rest.split_once('.'))
+            .filter(|(slot, _)| slot.parse::<u8>().is_ok())
+            .map(|(_, key)| key);
+        match (key, slot_key) {
+            ("read_only" | "host_writes" | "host_typing" | "templates" | "escapes", _)
+            | (_, Some("autorun" | "autorun_wait_leds")) => Some(Self::Boolean),
+            ("log.max_size", _) | (_, Some("autorun_delay")) => Some(Self::Number),
+            (_, Some("mode")) => Some(Self::SlotMode),
+            _ if key.starts_with("led.") => Some(Self::Color),
+            _ => None,
+        }
+    }
+
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            Self::Boolean => parse_bool(value).is_some(),
+            Self::Number => value.parse::<u32>().is_ok(),
+            Self::SlotMode => parse_slot_mode(value).is_some(),
+            Self::Color => crate::led::pattern::parse_color(value).is_some(),
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Boolean => "a boolean",
+            Self::Number => "a number",
+            Self::SlotMode => "`payload`, `hotp` or `script`",
+            Self::Color => COLOR,
+        }
+    }
+}
+
+pub const COLOR: &str = "`r,g,b` or `#rrggbb`";
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_slot_mode(value: &str) -> Option<SlotMode> {
+    match value {
+        "payload" => Some(SlotMode::Payload),
+        "hotp" => Some(SlotMode::Hotp),
+        "script" => Some(SlotMode::Script),
+        _ => None,
+    }
+}
+
+// How a value the firmware ignores is reported
+pub fn expected(key: &str, expectation: &str, value: &str) -> String {
+    format!("config {key}: expected {expectation}, got {value:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse("# comment\n\n Read_Only = yes \nvar.domain = a = b\n");
+        assert!(config.read_only());
+        assert_eq!(config.variables()["domain"], "a = b");
+        assert_eq!(config.payload_file(0), "input.txt");
+        assert_eq!(config.payload_file(3), "input3.txt");
+        assert_eq!(config.slot_mode(0), SlotMode::Payload);
+        assert_eq!(config.autorun(0), None);
+    }
+
+    #[test]
+    fn host_writes_yield_to_read_only() {
+        assert!(!Config::parse("").host_writes());
+        assert!(Config::parse("host_writes = true").host_writes());
+        assert!(!Config::parse("host_writes = true\nread_only = true").host_writes());
+    }
+
+    #[test]
+    fn check_reports_problems_as_values() {
+        let (_, problems) =
+            Config::check("read_only = true\nslot.1.mode = script\nled.ready = #00ff00");
+        assert_eq!(problems, Vec::<String>::new());
+
+        let (config, problems) = Config::check(
+            "templates = maybe\nbroken line\nlog.max_size = -1\nslot.2.mode = typing\n\
+             slot.0.autorun_wait_leds = 2\nled.done = green\nslot.x.mode = any\nunknown = ?",
+        );
+        assert_eq!(
+            problems,
+            [
+                "config line 2: missing '=': \"broken line\"",
+                "config led.done: expected `r,g,b` or `#rrggbb`, got \"green\"",
+                "config log.max_size: expected a number, got \"-1\"",
+                "config slot.0.autorun_wait_leds: expected a boolean, got \"2\"",
+                "config slot.2.mode: expected `payload`, `hotp` or `script`, got \"typing\"",
+                "config templates: expected a boolean, got \"maybe\"",
+            ]
+        );
+        // Ignored values fall back to their defaults
+        assert!(!config.templates());
+        assert_eq!(config.slot_mode(2), SlotMode::Payload);
+    }
+}
@@ -0,0 +1,481 @@
+// Line-based development console. Parsing is kept apart from the UART so that it only deals
+// with text; `main` reads the lines and executes the commands.
+//
+// Text arguments (`type`, `write`, `append`) take the rest of the line and understand the
+// escapes `\n`, `\t`, `\\` and `\xHH`.
+
+// Longer lines are discarded as a whole
+pub const MAX_LINE_LENGTH: usize = 256;
+
+pub const HELP: &str = "\
+help                          show this help
+ls [DIR]                      list files on the drive
+cat FILE                      show a file
+write FILE TEXT               replace a file with TEXT
+append FILE TEXT              append TEXT to a file
+rm FILE                       remove a file
+type TEXT                     type TEXT right now
+key CHORD...                  tap keys by name, with modifiers (e.g. F5, Ctrl+Alt+Delete)
+layout [NAME]                 show or change the keyboard layout
+timing [HOLD_MS RELEASE_MS]   show or change how long keys are held and released
+slot [N]                      show or select the payload slot
+brightness [LEVEL]            show or change the LED brightness (0-255)
+descriptors                   dump the USB descriptors
+status                        show USB bus state and keyboard LEDs
+reboot [msc|keyboard]         restart, optionally into the given mode
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    List { dir: Option<String> },
+    Show { file: String },
+    Write { file: String, text: String },
+    Append { file: String, text: String },
+    Remove { file: String },
+    Type { text: String },
+    // `usb::keycode::KeyChord`s, parsed when executed
+    Key { chords: Vec<String> },
+    Layout { name: Option<String> },
+    Timing { set: Option<(u32, u32)> },
+    Slot { set: Option<u8> },
+    Brightness { set: Option<u8> },
+    Descriptors,
+    Status,
+    Reboot { mode: Option<RebootMode> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMode {
+    Keyboard,
+    Msc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    UnexpectedArgument(String),
+    InvalidArgument { name: &'static str, value: String },
+    // Absolute paths and `..` would escape the drive
+    InvalidPath(String),
+    InvalidEscape { offset: usize },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownCommand(name) => write!(f, "unknown command {name:?}, try `help`"),
+            Self::MissingArgument(name) => write!(f, "missing argument {name}"),
+            Self::UnexpectedArgument(value) => write!(f, "unexpected argument {value:?}"),
+            Self::InvalidArgument { name, value } => write!(f, "invalid {name}: {value:?}"),
+            Self::InvalidPath(path) => write!(
+                f,
+                "invalid path {path:?}, expected a path relative to the drive root"
+            ),
+            Self::InvalidEscape { offset } => write!(f, "invalid escape at byte {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Returns None for blank lines and `#` comments
+pub fn parse(line: &str) -> Result<Option<Command>, Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (name, rest) = split_word(line);
+
+    let command = match name {
+        "help" | "?" => no_arguments(rest, Command::Help)?,
+        "ls" => {
+            let (dir, rest) = split_word(rest);
+            no_arguments(rest, ())?;
+            Command::List {
+                dir: match dir {
+                    "" => None,
+                    dir => Some(relative_path(dir)?),
+                },
+            }
+        }
+        "cat" => Command::Show {
+            file: single_path(rest)?,
+        },
+        "rm" => Command::Remove {
+            file: single_path(rest)?,
+        },
+        "write" | "append" => {
+            let (file, text) = split_word(rest);
+            if file.is_empty() {
+                return Err(Error::MissingArgument("FILE"));
+            }
+            let file = relative_path(file)?;
+            let text = unescape(text)?;
+            if name == "write" {
+                Command::Write { file, text }
+            } else {
+                Command::Append { file, text }
+            }
+        }
+        "type" => match rest {
+            "" => return Err(Error::MissingArgument("TEXT")),
+            text => Command::Type {
+                text: unescape(text)?,
+            },
+        },
+        "key" => {
+            let chords: Vec<String> = rest.split_whitespace().map(String::from).collect();
+            if chords.is_empty() {
+                return Err(Error::MissingArgument("CHORD"));
+            }
+            Command::Key { chords }
+        }
+        "layout" => {
+            let (layout, rest) = split_word(rest);
+            no_arguments(rest, ())?;
+            Command::Layout {
+                name: (!layout.is_empty()).then(|| layout.to_lowercase()),
+            }
+        }
+        "timing" => {
+            let (hold, rest) = split_word(rest);
+            let (release, rest) = split_word(rest);
+            no_arguments(rest, ())?;
+            Command::Timing {
+                set: match (hold, release) {
+                    ("", _) => None,
+                    (_, "") => return Err(Error::MissingArgument("RELEASE_MS")),
+                    (hold, release) => {
+                        Some((number("HOLD_MS", hold)?, number("RELEASE_MS", release)?))
+                    }
+                },
+            }
+        }
+        "slot" => Command::Slot {
+            set: optional_byte("N", rest)?,
+        },
+        "brightness" => Command::Brightness {
+            set: optional_byte("LEVEL", rest)?,
+        },
+        "descriptors" => no_arguments(rest, Command::Descriptors)?,
+        "status" => no_arguments(rest, Command::Status)?,
+        "reboot" => {
+            let (mode, rest) = split_word(rest);
+            no_arguments(rest, ())?;
+            Command::Reboot {
+                mode: match mode {
+                    "" => None,
+                    "msc" => Some(RebootMode::Msc),
+                    "keyboard" => Some(RebootMode::Keyboard),
+                    mode => {
+                        return Err(Error::InvalidArgument {
+                            name: "mode",
+                            value: mode.into(),
+                        })
+                    }
+                },
+            }
+        }
+        name => return Err(Error::UnknownCommand(name.into())),
+    };
+    Ok(Some(command))
+}
+
+// Splits off the first whitespace-separated word; the rest keeps its inner whitespace
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn no_arguments<T>(rest: &str, value: T) -> Result<T, Error> {
+    match rest.trim() {
+        "" => Ok(value),
+        rest => Err(Error::UnexpectedArgument(rest.into())),
+    }
+}
+
+fn number(name: &'static str, value: &str) -> Result<u32, Error> {
+    value.parse().map_err(|_| Error::InvalidArgument {
+        name,
+        value: value.into(),
+    })
+}
+
+// A single optional argument in 0..=255
+fn optional_byte(name: &'static str, rest: &str) -> Result<Option<u8>, Error> {
+    let (value, rest) = split_word(rest);
+    no_arguments(rest, ())?;
+    if value.is_empty() {
+        return Ok(None);
+    }
+    u8::try_from(number(name, value)?)
+        .map(Some)
+        .map_err(|_| Error::InvalidArgument {
+            name,
+            value: value.into(),
+        })
+}
+
+fn single_path(rest: &str) -> Result<String, Error> {
+    let (file, rest) = split_word(rest);
+    if file.is_empty() {
+        return Err(Error::MissingArgument("FILE"));
+    }
+    no_arguments(rest, ())?;
+    relative_path(file)
+}
+
+// Paths are relative to the drive root and cannot leave it
+pub fn relative_path(path: &str) -> Result<String, Error> {
+    let escapes = path.starts_with('/') || path.split('/').any(|component| component == "..");
+    if escapes {
+        return Err(Error::InvalidPath(path.into()));
+    }
+    Ok(path.into())
+}
+
+pub fn unescape(text: &str) -> Result<String, Error> {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next().map(|(_, c)| c) {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some('\\') => output.push('\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 && byte.is_ascii() => output.push(byte as char),
+                    _ => return Err(Error::InvalidEscape { offset }),
+                }
+            }
+            _ => return Err(Error::InvalidEscape { offset }),
+        }
+    }
+    Ok(output)
+}
+
+// 16 bytes per line, prefixed with the offset
+pub fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            format!("{:04x}  {}\n", line * 16, hex.join(" "))
+        })
+        .collect()
+}
+
+// Assembles lines from the bytes received, with backspace support
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    line: Vec<u8>,
+    overflow: bool,
+}
+
+impl LineBuffer {
+    // Returns a line once `\r` or `\n` is received
+    pub fn push(&mut self, byte: u8) -> Option<String> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = std::mem::take(&mut self.line);
+                if std::mem::take(&mut self.overflow) {
+                    log::warn!("console: line longer than {MAX_LINE_LENGTH} bytes discarded");
+                    return None;
+                }
+                Some(String::from_utf8_lossy(&line).into_owned())
+            }
+            // Backspace and DEL
+            0x08 | 0x7f => {
+                self.line.pop();
+                None
+            }
+            _ if self.line.len() >= MAX_LINE_LENGTH => {
+                self.overflow = true;
+                None
+            }
+            _ => {
+                self.line.push(byte);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("  "), Ok(None));
+        assert_eq!(parse("# comment"), Ok(None));
+        assert_eq!(command("help"), Command::Help);
+        assert_eq!(command("?"), Command::Help);
+        assert_eq!(command("ls"), Command::List { dir: None });
+        assert_eq!(
+            command("ls logs"),
+            Command::List {
+                dir: Some("logs".into())
+            }
+        );
+        assert_eq!(
+            command("cat  logs/boot.log "),
+            Command::Show {
+                file: "logs/boot.log".into()
+            }
+        );
+        assert_eq!(
+            command("write a.txt  two  spaces\\n"),
+            Command::Write {
+                file: "a.txt".into(),
+                text: "two  spaces\n".into()
+            }
+        );
+        assert_eq!(
+            command("append a.txt"),
+            Command::Append {
+                file: "a.txt".into(),
+                text: "".into()
+            }
+        );
+        assert_eq!(
+            command("key Ctrl+Alt+Delete F5"),
+            Command::Key {
+                chords: vec!["Ctrl+Alt+Delete".into(), "F5".into()]
+            }
+        );
+        assert_eq!(
+            command("layout DE"),
+            Command::Layout {
+                name: Some("de".into())
+            }
+        );
+        assert_eq!(
+            command("timing 10 20"),
+            Command::Timing {
+                set: Some((10, 20))
+            }
+        );
+        assert_eq!(command("timing"), Command::Timing { set: None });
+        assert_eq!(command("slot 3"), Command::Slot { set: Some(3) });
+        assert_eq!(command("brightness"), Command::Brightness { set: None });
+        assert_eq!(
+            command("reboot msc"),
+            Command::Reboot {
+                mode: Some(RebootMode::Msc)
+            }
+        );
+    }
+
+    #[test]
+    fn argument_errors() {
+        assert_eq!(parse("format"), Err(Error::UnknownCommand("format".into())));
+        assert_eq!(parse("cat"), Err(Error::MissingArgument("FILE")));
+        assert_eq!(parse("write"), Err(Error::MissingArgument("FILE")));
+        assert_eq!(parse("type"), Err(Error::MissingArgument("TEXT")));
+        assert_eq!(parse("key"), Err(Error::MissingArgument("CHORD")));
+        assert_eq!(
+            parse("timing 10"),
+            Err(Error::MissingArgument("RELEASE_MS"))
+        );
+        assert_eq!(
+            parse("timing 10 x"),
+            Err(Error::InvalidArgument {
+                name: "RELEASE_MS",
+                value: "x".into()
+            })
+        );
+        assert_eq!(
+            parse("brightness 256"),
+            Err(Error::InvalidArgument {
+                name: "LEVEL",
+                value: "256".into()
+            })
+        );
+        assert_eq!(
+            parse("slot 1 2"),
+            Err(Error::UnexpectedArgument("2".into()))
+        );
+        assert_eq!(
+            parse("status now"),
+            Err(Error::UnexpectedArgument("now".into()))
+        );
+        assert_eq!(parse("rm a b"), Err(Error::UnexpectedArgument("b".into())));
+        assert_eq!(
+            parse("reboot later"),
+            Err(Error::InvalidArgument {
+                name: "mode",
+                value: "later".into()
+            })
+        );
+        assert_eq!(parse("type \\q"), Err(Error::InvalidEscape { offset: 0 }));
+    }
+
+    #[test]
+    fn paths_stay_on_the_drive() {
+        assert_eq!(relative_path("logs/boot.log"), Ok("logs/boot.log".into()));
+        assert_eq!(relative_path("a..b"), Ok("a..b".into()));
+        for path in ["/etc", "..", "../x", "logs/../../x", "logs/.."] {
+            assert_eq!(relative_path(path), Err(Error::InvalidPath(path.into())));
+        }
+        assert_eq!(
+            parse("rm ../config.txt"),
+            Err(Error::InvalidPath("../config.txt".into()))
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape("a\\tb\\nc\\\\"), Ok("a\tb\nc\\".into()));
+        assert_eq!(unescape("\\x41\\x7e"), Ok("A~".into()));
+        assert_eq!(unescape("ü\\x"), Err(Error::InvalidEscape { offset: 2 }));
+        assert_eq!(unescape("\\x4"), Err(Error::InvalidEscape { offset: 0 }));
+        assert_eq!(unescape("\\xff"), Err(Error::InvalidEscape { offset: 0 }));
+        assert_eq!(unescape("a\\"), Err(Error::InvalidEscape { offset: 1 }));
+    }
+
+    fn lines(buffer: &mut LineBuffer, bytes: &[u8]) -> Vec<String> {
+        bytes.iter().filter_map(|&byte| buffer.push(byte)).collect()
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(lines(&mut buffer, b"lx\x08s\n"), ["ls"]);
+        assert_eq!(
+            lines(&mut buffer, b"\x7f\x7fcat\x7f\x7f\x7f\x7fok\r"),
+            ["ok"]
+        );
+        // The `\n` of CRLF ends an empty line, which `parse` ignores
+        assert_eq!(lines(&mut buffer, b"help\r\n"), ["help", ""]);
+
+        let long = vec![b'a'; MAX_LINE_LENGTH + 1];
+        assert!(lines(&mut buffer, &long).is_empty());
+        assert!(lines(&mut buffer, b"\n").is_empty());
+        assert_eq!(lines(&mut buffer, b"status\n"), ["status"]);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_dump(b""), "");
+        assert_eq!(
+            hex_dump(&[0xab; 17]),
+            format!("0000  {}\n0010  ab\n", ["ab"; 16].join(" "))
+        );
+    }
+}
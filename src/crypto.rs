@@ -0,0 +1,316 @@
+// At-rest encryption of payload files
+//
+// An encrypted payload is laid out as
+//
+//     magic (4) | PBKDF2 iterations, u32 LE (4) | salt (16) | nonce (12) | ciphertext + tag
+//
+// The key is derived from the PIN with PBKDF2-HMAC-SHA256 and the payload is sealed with
+// ChaCha20-Poly1305, using everything before the ciphertext as associated data.
+// Files are produced by `autokbd encrypt` and only ever decrypted into RAM right before typing.
+//
+// A 4 to 8 digit PIN alone is quickly brute-forced by anyone holding a copy of the file, so the
+// first time the device unlocks a payload it seals it again (`DEVICE_MAGIC`), mixing a random key
+// that never leaves its NVS into the derived key. From then on the file is useless elsewhere.
+use chacha20poly1305::aead::{Aead as _, KeyInit as _, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use zeroize::Zeroizing;
+
+pub const MAGIC: &[u8; 4] = b"AKE1";
+// Sealed with the device key
+pub const DEVICE_MAGIC: &[u8; 4] = b"AKE2";
+pub const SALT_LENGTH: usize = 16;
+pub const NONCE_LENGTH: usize = 12;
+pub const HEADER_LENGTH: usize = MAGIC.len() + 4 + SALT_LENGTH + NONCE_LENGTH;
+
+pub const DEFAULT_ITERATIONS: u32 = 10_000;
+// The count is read from the file before the PIN is checked, so it must not be able to keep
+// the device busy for hours
+pub const MAX_ITERATIONS: u32 = 100_000;
+
+pub const DEVICE_KEY_LENGTH: usize = 32;
+pub type DeviceKey = [u8; DEVICE_KEY_LENGTH];
+
+pub const PIN_LENGTH: std::ops::RangeInclusive<usize> = 4..=8;
+
+// Failed PIN attempts tolerated before every further attempt is delayed
+pub const FREE_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    NotEncrypted,
+    Truncated,
+    InvalidIterations,
+    // Wrong PIN or tampered file; AEAD cannot tell them apart
+    Decryption,
+    InvalidPin,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotEncrypted => write!(f, "not an encrypted payload"),
+            Self::Truncated => write!(f, "encrypted payload is truncated"),
+            Self::InvalidIterations => {
+                write!(f, "PBKDF2 iteration count must be 1 to {MAX_ITERATIONS}")
+            }
+            Self::Decryption => write!(f, "wrong PIN or corrupted payload"),
+            Self::InvalidPin => write!(
+                f,
+                "PIN must be {} to {} digits",
+                PIN_LENGTH.start(),
+                PIN_LENGTH.end()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || is_device_bound(data)
+}
+
+// Decrypts only on the device that sealed it
+pub fn is_device_bound(data: &[u8]) -> bool {
+    data.starts_with(DEVICE_MAGIC)
+}
+
+pub fn validate_pin(pin: &str) -> Result<(), Error> {
+    if PIN_LENGTH.contains(&pin.len()) && pin.bytes().all(|b| b.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidPin)
+    }
+}
+
+fn validate_iterations(iterations: u32) -> Result<(), Error> {
+    if (1..=MAX_ITERATIONS).contains(&iterations) {
+        Ok(())
+    } else {
+        Err(Error::InvalidIterations)
+    }
+}
+
+// With a device key, the key is HMAC-SHA256 of the device key under the stretched PIN
+pub fn derive_key(
+    pin: &str,
+    salt: &[u8],
+    iterations: u32,
+    device_key: Option<&DeviceKey>,
+) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(pin.as_bytes(), salt, iterations, key.as_mut());
+    if let Some(device_key) = device_key {
+        let mut mac = <hmac::Hmac<sha2::Sha256> as hmac::Mac>::new_from_slice(key.as_ref())
+            .expect("HMAC accepts any key length");
+        hmac::Mac::update(&mut mac, device_key);
+        key.copy_from_slice(&hmac::Mac::finalize(mac).into_bytes());
+    }
+    key
+}
+
+// Sealed for any device unless `device_key` is given
+pub fn encrypt(
+    plaintext: &[u8],
+    pin: &str,
+    salt: &[u8; SALT_LENGTH],
+    nonce: &[u8; NONCE_LENGTH],
+    iterations: u32,
+    device_key: Option<&DeviceKey>,
+) -> Result<Vec<u8>, Error> {
+    validate_pin(pin)?;
+    validate_iterations(iterations)?;
+
+    let mut output = Vec::with_capacity(HEADER_LENGTH + plaintext.len() + 16);
+    output.extend_from_slice(match device_key {
+        None => MAGIC,
+        Some(_) => DEVICE_MAGIC,
+    });
+    output.extend_from_slice(&iterations.to_le_bytes());
+    output.extend_from_slice(salt);
+    output.extend_from_slice(nonce);
+
+    let key = derive_key(pin, salt, iterations, device_key);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad: &output,
+            },
+        )
+        .map_err(|_| Error::Decryption)?;
+
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+// `device_key` is only used for payloads sealed with it
+pub fn decrypt(
+    data: &[u8],
+    pin: &str,
+    device_key: &DeviceKey,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if !is_encrypted(data) {
+        return Err(Error::NotEncrypted);
+    }
+    if data.len() < HEADER_LENGTH {
+        return Err(Error::Truncated);
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LENGTH);
+    let iterations = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let salt = &header[8..8 + SALT_LENGTH];
+    let nonce = &header[8 + SALT_LENGTH..];
+    validate_iterations(iterations)?;
+
+    let device_key = is_device_bound(data).then_some(device_key);
+    let key = derive_key(pin, salt, iterations, device_key);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| Error::Decryption)
+}
+
+// Seals a payload encrypted for any device with `device_key` instead, keeping its iteration count
+pub fn bind_to_device(
+    data: &[u8],
+    pin: &str,
+    device_key: &DeviceKey,
+    salt: &[u8; SALT_LENGTH],
+    nonce: &[u8; NONCE_LENGTH],
+) -> Result<Vec<u8>, Error> {
+    let plaintext = decrypt(data, pin, device_key)?;
+    let iterations = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    encrypt(&plaintext, pin, salt, nonce, iterations, Some(device_key))
+}
+
+// Delay enforced before accepting another PIN after `failures` consecutive failed attempts
+pub fn lockout_delay(failures: u32) -> std::time::Duration {
+    const BASE_SECONDS: u64 = 30;
+    const MAX_SECONDS: u64 = 60 * 60;
+
+    match failures.checked_sub(FREE_ATTEMPTS) {
+        None => std::time::Duration::ZERO,
+        Some(exponent) => std::time::Duration::from_secs(
+            BASE_SECONDS
+                .saturating_mul(1 << exponent.min(16))
+                .min(MAX_SECONDS),
+        ),
+    }
+}
+
+// Decodes a PIN entered with the device button: each digit is a run of clicks
+// (ten clicks for 0), digits are separated by a pause and a long press finishes the entry
+#[derive(Default)]
+pub struct PinEntry {
+    digits: Zeroizing<String>,
+    clicks: u8,
+}
+
+impl PinEntry {
+    pub fn click(&mut self) {
+        self.clicks = self.clicks.saturating_add(1);
+    }
+
+    // Commits the pending clicks as a digit, if any
+    pub fn pause(&mut self) {
+        match self.clicks {
+            0 => {}
+            clicks @ 1..=10 => self.digits.push(char::from(b'0' + clicks % 10)),
+            // Not a digit: poison the entry so that validation fails
+            _ => self.digits.push('x'),
+        }
+        self.clicks = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.digits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digits.is_empty() && self.clicks == 0
+    }
+
+    pub fn finish(mut self) -> Zeroizing<String> {
+        self.pause();
+        std::mem::take(&mut self.digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; SALT_LENGTH] = [1; SALT_LENGTH];
+    const NONCE: [u8; NONCE_LENGTH] = [2; NONCE_LENGTH];
+    const DEVICE_KEY: DeviceKey = [3; DEVICE_KEY_LENGTH];
+
+    #[test]
+    fn round_trip() {
+        let sealed = encrypt(b"hello", "1234", &SALT, &NONCE, 10, None).unwrap();
+        assert!(is_encrypted(&sealed) && !is_device_bound(&sealed));
+        assert_eq!(*decrypt(&sealed, "1234", &DEVICE_KEY).unwrap(), b"hello");
+        assert_eq!(
+            decrypt(&sealed, "4321", &DEVICE_KEY),
+            Err(Error::Decryption)
+        );
+    }
+
+    #[test]
+    fn device_bound_payloads_need_the_device_key() {
+        let sealed = encrypt(b"hello", "1234", &SALT, &NONCE, 10, None).unwrap();
+        let bound =
+            bind_to_device(&sealed, "1234", &DEVICE_KEY, &[4; SALT_LENGTH], &NONCE).unwrap();
+        assert!(is_encrypted(&bound) && is_device_bound(&bound));
+        assert_eq!(bound[4..8], sealed[4..8]);
+        assert_eq!(*decrypt(&bound, "1234", &DEVICE_KEY).unwrap(), b"hello");
+        let other = [5; DEVICE_KEY_LENGTH];
+        assert_eq!(decrypt(&bound, "1234", &other), Err(Error::Decryption));
+    }
+
+    #[test]
+    fn iterations_are_bounded() {
+        for iterations in [0, MAX_ITERATIONS + 1] {
+            assert_eq!(
+                encrypt(b"", "1234", &SALT, &NONCE, iterations, None),
+                Err(Error::InvalidIterations)
+            );
+        }
+        // Checked before any key is derived
+        let mut sealed = encrypt(b"", "1234", &SALT, &NONCE, 1, None).unwrap();
+        sealed[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            decrypt(&sealed, "1234", &DEVICE_KEY),
+            Err(Error::InvalidIterations)
+        );
+    }
+
+    #[test]
+    fn truncated_and_plain_payloads() {
+        assert_eq!(
+            decrypt(b"hello", "1234", &DEVICE_KEY),
+            Err(Error::NotEncrypted)
+        );
+        assert_eq!(
+            decrypt(b"AKE2\0", "1234", &DEVICE_KEY),
+            Err(Error::Truncated)
+        );
+    }
+
+    #[test]
+    fn lockout_grows_and_is_capped() {
+        assert!(lockout_delay(FREE_ATTEMPTS).as_secs() == 30);
+        assert!(lockout_delay(FREE_ATTEMPTS - 1).is_zero());
+        assert_eq!(lockout_delay(FREE_ATTEMPTS + 1).as_secs(), 60);
+        assert_eq!(lockout_delay(u32::MAX).as_secs(), 60 * 60);
+    }
+}
@@ -0,0 +1,346 @@
+// AutoHotkey-style inline escapes in otherwise literal payload text
+//
+//   {Enter}               tap a named key or chord, e.g. {F5}, {Ctrl+Alt+Delete}
+//   {Tab 3}               tap it 3 times
+//   {Ctrl down}{Ctrl up}  press and release separately, e.g. to hold Shift across text
+//   {Sleep 500}           wait 500 ms
+//   {{ and }}             literal braces
+//
+// Enabled by `escapes = true` in the config file or by the `.keys` payload extension. Templates
+// are expanded first.
+
+use crate::usb::keycode::{KeyChord, KeycodeError};
+use std::time::Duration;
+
+// Payload files with this extension always use escapes
+pub const FILE_EXTENSION: &str = "keys";
+
+// Upper bound for {KEY N}, so a typo cannot type for minutes
+pub const MAX_REPEAT: u32 = 1000;
+const REPEAT_EXPECTED: &str = "a count up to 1000, `down` or `up`";
+
+// Upper bound for {Sleep MS}, one hour
+pub const MAX_SLEEP_MS: u64 = 3_600_000;
+const SLEEP_EXPECTED: &str = "a duration up to 3600000 ms";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    // Typed character by character
+    Text(String),
+    Tap { chord: KeyChord, count: u32 },
+    Press(KeyChord),
+    Release(KeyChord),
+    Sleep(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // `{` without `}` on the same line
+    Unterminated {
+        offset: usize,
+    },
+    Empty {
+        offset: usize,
+    },
+    InvalidKey {
+        offset: usize,
+        error: KeycodeError,
+    },
+    InvalidArgument {
+        offset: usize,
+        escape: String,
+        expected: &'static str,
+    },
+}
+
+impl Error {
+    // Byte offset of the escape in the text
+    pub fn offset(&self) -> usize {
+        match *self {
+            Self::Unterminated { offset }
+            | Self::Empty { offset }
+            | Self::InvalidKey { offset, .. }
+            | Self::InvalidArgument { offset, .. } => offset,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unterminated { offset } => {
+                write!(
+                    f,
+                    "unterminated escape at byte {offset} (write `{{{{` for a literal brace)"
+                )
+            }
+            Self::Empty { offset } => write!(f, "empty escape at byte {offset}"),
+            Self::InvalidKey { offset, error } => write!(f, "{error} at byte {offset}"),
+            Self::InvalidArgument {
+                offset,
+                escape,
+                expected,
+            } => write!(
+                f,
+                "invalid escape {{{escape}}} at byte {offset}, expected {expected}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn tokenize(text: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((offset, char)) = chars.next() {
+        match char {
+            '{' | '}' if chars.peek().map(|&(_, next)| next) == Some(char) => {
+                chars.next();
+                literal.push(char);
+            }
+            '{' => {
+                let after = &text[offset + 1..];
+                let end = after
+                    .find(['}', '\n'])
+                    .filter(|&end| after[end..].starts_with('}'))
+                    .ok_or(Error::Unterminated { offset })?;
+                let escape = &after[..end];
+                // Skip the escape and its closing brace
+                let close = offset + 1 + end;
+                while chars.next_if(|&(next, _)| next <= close).is_some() {}
+
+                if !literal.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut literal)));
+                }
+                tokens.push(parse_escape(escape, offset)?);
+            }
+            // A lone `}` cannot be confused with anything
+            char => literal.push(char),
+        }
+    }
+
+    if !literal.is_empty() {
+        tokens.push(Token::Text(literal));
+    }
+    Ok(tokens)
+}
+
+// `escape` is what is between the braces
+fn parse_escape(escape: &str, offset: usize) -> Result<Token, Error> {
+    let invalid = |expected| Error::InvalidArgument {
+        offset,
+        escape: escape.into(),
+        expected,
+    };
+    let mut words = escape.split_whitespace();
+    let (Some(name), argument) = (words.next(), words.next()) else {
+        return Err(Error::Empty { offset });
+    };
+    if words.next().is_some() {
+        return Err(invalid("a single argument"));
+    }
+
+    if name.eq_ignore_ascii_case("sleep") {
+        let ms = argument
+            .and_then(|ms| ms.parse().ok())
+            .filter(|&ms| ms <= MAX_SLEEP_MS)
+            .ok_or_else(|| invalid(SLEEP_EXPECTED))?;
+        return Ok(Token::Sleep(Duration::from_millis(ms)));
+    }
+
+    let chord = name
+        .parse()
+        .map_err(|error| Error::InvalidKey { offset, error })?;
+    Ok(match argument.map(str::to_lowercase).as_deref() {
+        None => Token::Tap { chord, count: 1 },
+        Some("down") => Token::Press(chord),
+        Some("up") => Token::Release(chord),
+        Some(count) => match count.parse() {
+            Ok(count) if count <= MAX_REPEAT => Token::Tap { chord, count },
+            _ => return Err(invalid(REPEAT_EXPECTED)),
+        },
+    })
+}
+
+// 1-based line and column (in characters) of a byte offset, for error messages
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::keycode::{Key, Modifiers};
+
+    fn chord(text: &str) -> KeyChord {
+        text.parse().unwrap()
+    }
+
+    fn text(text: &str) -> Token {
+        Token::Text(text.into())
+    }
+
+    fn tap(name: &str, count: u32) -> Token {
+        Token::Tap {
+            chord: chord(name),
+            count,
+        }
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(tokenize("{{}}").unwrap(), [text("{}")]);
+        assert_eq!(tokenize("a{{Enter}}b").unwrap(), [text("a{Enter}b")]);
+        assert_eq!(tokenize("a}b").unwrap(), [text("a}b")]);
+        assert_eq!(
+            tokenize("{{{Enter}}}").unwrap(),
+            [text("{"), tap("Enter", 1), text("}")]
+        );
+        assert_eq!(tokenize("").unwrap(), []);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            tokenize("a{Enter}b{Tab 3}").unwrap(),
+            [text("a"), tap("Enter", 1), text("b"), tap("Tab", 3)]
+        );
+        assert_eq!(
+            tokenize("{Ctrl+Alt+Delete}").unwrap(),
+            [Token::Tap {
+                chord: KeyChord::single(
+                    Modifiers::LEFT_CTRL | Modifiers::LEFT_ALT,
+                    Key::Delete.usage()
+                ),
+                count: 1
+            }]
+        );
+        assert_eq!(
+            tokenize("{Shift down}abc{SHIFT UP}").unwrap(),
+            [
+                Token::Press(chord("Shift")),
+                text("abc"),
+                Token::Release(chord("Shift"))
+            ]
+        );
+        assert_eq!(
+            tokenize("{Ctrl down}{Ctrl up}").unwrap(),
+            [Token::Press(chord("Ctrl")), Token::Release(chord("Ctrl"))]
+        );
+        assert_eq!(
+            tokenize("{ Sleep  500 }").unwrap(),
+            [Token::Sleep(Duration::from_millis(500))]
+        );
+        assert_eq!(
+            tokenize("{sleep 0}").unwrap(),
+            [Token::Sleep(Duration::ZERO)]
+        );
+    }
+
+    #[test]
+    fn repeat_limit() {
+        assert_eq!(
+            tokenize(&format!("{{Tab {MAX_REPEAT}}}")).unwrap(),
+            [tap("Tab", MAX_REPEAT)]
+        );
+        let escape = format!("Tab {}", MAX_REPEAT + 1);
+        assert_eq!(
+            tokenize(&format!("ab{{{escape}}}")),
+            Err(Error::InvalidArgument {
+                offset: 2,
+                escape,
+                expected: REPEAT_EXPECTED
+            })
+        );
+        assert!(tokenize("{Tab -1}").is_err());
+
+        assert_eq!(
+            tokenize(&format!("{{Sleep {MAX_SLEEP_MS}}}")).unwrap(),
+            [Token::Sleep(Duration::from_millis(MAX_SLEEP_MS))]
+        );
+        let escape = format!("Sleep {}", MAX_SLEEP_MS + 1);
+        assert_eq!(
+            tokenize(&format!("{{{escape}}}")),
+            Err(Error::InvalidArgument {
+                offset: 0,
+                escape,
+                expected: SLEEP_EXPECTED
+            })
+        );
+        assert!(tokenize(&format!("{{Sleep {}}}", u64::MAX)).is_err());
+        assert!(tokenize("{Tab sideways}").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            tokenize("{Sleep}"),
+            Err(Error::InvalidArgument {
+                offset: 0,
+                escape: "Sleep".into(),
+                expected: SLEEP_EXPECTED
+            })
+        );
+        assert!(matches!(
+            tokenize("{Sleep soon}"),
+            Err(Error::InvalidArgument { offset: 0, .. })
+        ));
+        assert!(matches!(
+            tokenize("{Tab 3 4}"),
+            Err(Error::InvalidArgument { offset: 0, .. })
+        ));
+        assert_eq!(tokenize("a{ }"), Err(Error::Empty { offset: 1 }));
+        assert_eq!(
+            tokenize("{Hyper}"),
+            Err(Error::InvalidKey {
+                offset: 0,
+                error: KeycodeError::UnknownKey("Hyper".into())
+            })
+        );
+
+        // The closing brace must be on the same line
+        assert_eq!(
+            tokenize("ab{Enter\n}"),
+            Err(Error::Unterminated { offset: 2 })
+        );
+        assert_eq!(
+            tokenize("x\n{Enter"),
+            Err(Error::Unterminated { offset: 2 })
+        );
+        // Other escapes on later lines do not close it
+        assert_eq!(
+            tokenize("{Tab\n{Enter}"),
+            Err(Error::Unterminated { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn line_columns() {
+        let text = "ab\ncd{x";
+        assert_eq!(line_column(text, 0), (1, 1));
+        assert_eq!(line_column(text, 2), (1, 3));
+        assert_eq!(line_column(text, 3), (2, 1));
+        assert_eq!(line_column(text, 5), (2, 3));
+        // Past the end counts as the end
+        assert_eq!(line_column(text, 100), (2, 5));
+
+        // Columns count characters, not bytes
+        let text = "grüße\n€ {x";
+        let offset = text.find('{').unwrap();
+        assert_eq!(offset, 12);
+        assert_eq!(line_column(text, offset), (2, 3));
+        assert_eq!(line_column(text, text.find('e').unwrap()), (1, 5));
+
+        let error = tokenize(text).unwrap_err();
+        assert_eq!(error, Error::Unterminated { offset });
+        assert_eq!(line_column(text, error.offset()), (2, 3));
+    }
+}
@@ -0,0 +1,216 @@
+// HMAC-based one-time passwords (RFC 4226)
+//
+// The secret is provisioned by putting `hotp.txt` on the drive:
+//
+//     secret = JBSWY3DPEHPK3PXP
+//     digits = 6
+//     enter = true
+//
+// On the next boot in keyboard mode the firmware moves it into NVS and deletes the file,
+// so the secret is never exposed over MSC afterwards.
+
+use hmac::{Hmac, Mac as _};
+
+pub const FILE_NAME: &str = "hotp.txt";
+
+pub const DIGITS: std::ops::RangeInclusive<u8> = 6..=8;
+
+// Largest blob the settings store reads back
+pub const MAX_SECRET_LENGTH: usize = 64;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Hotp {
+    pub secret: Vec<u8>,
+    pub digits: u8,
+    // Press Enter after typing the code
+    pub enter: bool,
+}
+
+// Do not leak the secret into logs
+impl std::fmt::Debug for Hotp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hotp")
+            .field("secret", &"(hidden)")
+            .field("digits", &self.digits)
+            .field("enter", &self.enter)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    MissingSecret,
+    InvalidSecret,
+    InvalidDigits(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSecret => write!(f, "missing `secret`"),
+            Self::InvalidSecret => write!(
+                f,
+                "`secret` must be base32 of 1 to {MAX_SECRET_LENGTH} bytes"
+            ),
+            Self::InvalidDigits(value) => write!(
+                f,
+                "`digits` must be between {} and {}, got {value:?}",
+                DIGITS.start(),
+                DIGITS.end()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Hotp {
+    // Parses the content of `hotp.txt`, which uses the same syntax as `config.txt`
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let config = crate::config::Config::parse(text);
+
+        let secret = config.get("secret").ok_or(Error::MissingSecret)?;
+        let secret = decode_base32(secret).ok_or(Error::InvalidSecret)?;
+        if secret.is_empty() || secret.len() > MAX_SECRET_LENGTH {
+            return Err(Error::InvalidSecret);
+        }
+
+        let digits = match config.get("digits") {
+            None => *DIGITS.start(),
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|digits| DIGITS.contains(digits))
+                .ok_or_else(|| Error::InvalidDigits(value.into()))?,
+        };
+
+        Ok(Self {
+            secret,
+            digits,
+            enter: config.get_bool("enter").unwrap_or(false),
+        })
+    }
+
+    // Zero-padded code for the given moving counter
+    pub fn code(&self, counter: u64) -> String {
+        format!(
+            "{:0width$}",
+            hotp(&self.secret, counter, self.digits as u32),
+            width = self.digits as usize
+        )
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc4226#section-5.3
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    binary % 10u32.pow(digits)
+}
+
+// RFC 4648 alphabet; case-insensitive, ignoring spaces, dashes and `=` padding
+pub fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for char in text.chars() {
+        let value = match char.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            ' ' | '-' | '=' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226 appendix D
+    const SECRET: &[u8] = b"12345678901234567890";
+    const CODES: [u32; 10] = [
+        755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+    ];
+
+    #[test]
+    fn rfc_4226_vectors() {
+        for (counter, code) in CODES.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64, 6), code, "counter {counter}");
+        }
+    }
+
+    #[test]
+    fn parse_and_code() {
+        let otp = Hotp::parse("secret = GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\nenter = true").unwrap();
+        assert_eq!(otp.secret, SECRET);
+        assert_eq!(otp.digits, 6);
+        assert!(otp.enter);
+        assert_eq!(otp.code(0), "755224");
+        assert_eq!(otp.code(7), "162583");
+    }
+
+    #[test]
+    fn code_is_zero_padded() {
+        let otp = Hotp {
+            secret: SECRET.to_vec(),
+            digits: 8,
+            enter: false,
+        };
+        // The 8-digit truncation of counter 0 is 84755224
+        assert_eq!(otp.code(0), "84755224");
+        let code = otp.code(3);
+        assert_eq!(code.len(), 8);
+        assert_eq!(code.parse::<u32>().unwrap(), hotp(SECRET, 3, 8));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Hotp::parse("digits = 6"), Err(Error::MissingSecret));
+        assert_eq!(Hotp::parse("secret = 1234"), Err(Error::InvalidSecret));
+        assert_eq!(Hotp::parse("secret ="), Err(Error::InvalidSecret));
+        assert_eq!(
+            Hotp::parse("secret = JBSWY3DPEHPK3PXP\ndigits = 9"),
+            Err(Error::InvalidDigits("9".into()))
+        );
+        let long = "A".repeat((MAX_SECRET_LENGTH + 1) * 8 / 5 + 1);
+        assert_eq!(
+            Hotp::parse(&format!("secret = {long}")),
+            Err(Error::InvalidSecret)
+        );
+    }
+
+    #[test]
+    fn base32_ignores_case_and_separators() {
+        assert_eq!(
+            decode_base32("jbsw-y3dp ehpk3pxp===="),
+            decode_base32("JBSWY3DPEHPK3PXP")
+        );
+        assert_eq!(
+            decode_base32("JBSWY3DPEHPK3PXP").unwrap(),
+            b"Hello!\xde\xad\xbe\xef"
+        );
+        assert_eq!(decode_base32("JBSW1"), None);
+    }
+}
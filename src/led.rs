@@ -0,0 +1,185 @@
+// Status LED rendered from its own thread, so that patterns keep running while the main task
+// is blocked (e.g. typing a long payload)
+
+pub mod pattern;
+
+pub use pattern::{Palette, State};
+
+use smart_leds_trait::SmartLedsWrite as _;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use ws2812_esp32_rmt_driver::lib_smart_leds::Ws2812Esp32Rmt;
+
+const TICK: Duration = Duration::from_millis(20);
+
+// Number of blinks shown by `State::Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    Unknown = 1,
+    UsbInstall = 2,
+    Storage = 3,
+    HidSend = 4,
+    // Payload cannot be typed: unmappable key, template or decryption failure
+    Payload = 5,
+    Pin = 6,
+    Hotp = 7,
+}
+
+impl ErrorCode {
+    pub fn of(error: &anyhow::Error) -> Self {
+        use crate::usb;
+
+        if error.is::<usb::InstallError>() || error.is::<usb::descriptor::DescriptorError>() {
+            Self::UsbInstall
+        } else if error.is::<usb::storage::StorageError>() {
+            Self::Storage
+        } else if let Some(e) = error.downcast_ref::<crate::payload::Error>() {
+            match e {
+                crate::payload::Error::Io(_) => Self::Storage,
+                crate::payload::Error::TooLarge => Self::Payload,
+            }
+        } else if let Some(e) = error.downcast_ref::<crate::bytecode::Error>() {
+            match e {
+                crate::bytecode::Error::Io(_) => Self::Storage,
+                _ => Self::Payload,
+            }
+        } else if error.is::<usb::SendError>() {
+            Self::HidSend
+        } else if let Some(e) = error.downcast_ref::<usb::controller::ControllerError>() {
+            match e {
+                usb::controller::ControllerError::Send(_) => Self::HidSend,
+                usb::controller::ControllerError::Keycode(_) => Self::Payload,
+            }
+        } else if error.is::<usb::keycode::KeycodeError>()
+            || error.is::<crate::template::Error>()
+            || error.is::<crate::crypto::Error>()
+            || error.is::<crate::escape::Error>()
+            || error.is::<crate::script::ParseError>()
+            || error.is::<crate::script::RunError<usb::controller::ControllerError>>()
+            || error.is::<crate::bytecode::RunError<usb::controller::ControllerError>>()
+        {
+            Self::Payload
+        } else if error.is::<crate::hotp::Error>() {
+            Self::Hotp
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl From<ErrorCode> for State {
+    fn from(code: ErrorCode) -> Self {
+        State::Error(code as u8)
+    }
+}
+
+struct Shared {
+    state: State,
+    since: Instant,
+    // Shown instead of `state` until the deadline: (state, since, until)
+    overlay: Option<(State, Instant, Instant)>,
+    palette: Palette,
+    brightness: u8,
+}
+
+impl Shared {
+    fn frame(&mut self, now: Instant) -> smart_leds_trait::RGB8 {
+        if let Some((_, _, until)) = self.overlay {
+            if now >= until {
+                self.overlay = None;
+            }
+        }
+        let (mut state, since) = match self.overlay {
+            Some((state, since, _)) => (state, since),
+            None => (self.state, self.since),
+        };
+        // The typing engine publishes progress while the main task is blocked,
+        // and waits (so does the LED) whenever the bus is not usable
+        if let State::Typing { ref mut percent } = state {
+            *percent = crate::progress::PROGRESS.percent();
+            match crate::usb::bus::state() {
+                crate::usb::bus::State::Mounted => {}
+                crate::usb::bus::State::NotMounted => state = State::NotMounted,
+                crate::usb::bus::State::Suspended => state = State::Suspended,
+            }
+        }
+        let elapsed_ms = now.duration_since(since).as_millis() as u32;
+        pattern::frame(state, &self.palette, self.brightness, elapsed_ms)
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusLed {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl StatusLed {
+    pub fn spawn(mut led: Ws2812Esp32Rmt<'static>) -> std::io::Result<Self> {
+        let status = Self {
+            shared: Arc::new(Mutex::new(Shared {
+                state: State::Booting,
+                since: Instant::now(),
+                overlay: None,
+                palette: Palette::default(),
+                brightness: 255,
+            })),
+        };
+
+        let shared = status.shared.clone();
+        std::thread::Builder::new()
+            .name("status-led".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let mut last = None;
+                loop {
+                    let frame = shared
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .frame(Instant::now());
+                    // Writing the same color again is pointless and costs an RMT transaction
+                    if last != Some(frame) {
+                        if let Err(e) = led.write([frame].into_iter()) {
+                            log::warn!("cannot update LED: {e:?}");
+                        }
+                        last = Some(frame);
+                    }
+                    std::thread::sleep(TICK);
+                }
+            })?;
+
+        Ok(status)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // The pattern restarts only when the kind of state changes, so that updating
+    // e.g. the typing progress does not reset a blink
+    pub fn set(&self, state: State) {
+        let mut shared = self.lock();
+        if std::mem::discriminant(&shared.state) != std::mem::discriminant(&state) {
+            shared.since = Instant::now();
+        }
+        shared.state = state;
+    }
+
+    pub fn state(&self) -> State {
+        self.lock().state
+    }
+
+    // Shows `state` for `duration`, then falls back to the state set by `set`
+    pub fn show_for(&self, state: State, duration: Duration) {
+        let now = Instant::now();
+        self.lock().overlay = Some((state, now, now + duration));
+    }
+
+    pub fn set_palette(&self, palette: Palette) {
+        self.lock().palette = palette;
+    }
+
+    pub fn set_brightness(&self, brightness: u8) {
+        self.lock().brightness = brightness;
+    }
+}
@@ -0,0 +1,361 @@
+// What the status LED shows for each state, as a pure function of the time spent in it
+
+use smart_leds_trait::RGB8;
+
+use crate::config;
+
+const OFF: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Booting,
+    // Keyboard mode, waiting for a button press
+    Ready,
+    // Fades from the `typing` to the `done` color as the payload progresses
+    Typing { percent: u8 },
+    // Typing finished; safe to unplug
+    Done,
+    Paused,
+    // Blinks `code` times, then stays dark for a moment
+    Error(u8),
+    MscIdle,
+    // The host has the drive mounted
+    MscActive,
+    // USB is not enumerated by the host (yet)
+    NotMounted,
+    Suspended,
+    // Button held at boot, waiting for the read-only gesture
+    Gesture,
+    // Short confirmation, e.g. a recognized gesture or a PIN click
+    Acknowledge,
+    PinEntry,
+    // Too many failed PIN attempts
+    Locked,
+    // A script waits for a button press, see `WAIT_FOR_BUTTON`
+    Waiting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Solid,
+    Blink { on_ms: u32, off_ms: u32 },
+    // Fades in and out over `period_ms`
+    Breathe { period_ms: u32 },
+    // Short flash at the start of every period
+    Pulse { period_ms: u32 },
+    // `count` short flashes followed by a pause
+    Code { count: u8 },
+}
+
+impl Pattern {
+    // Brightness level (0-255) of the pattern `elapsed_ms` after it started
+    pub fn level(&self, elapsed_ms: u32) -> u8 {
+        match *self {
+            Self::Solid => 255,
+            Self::Blink { on_ms, off_ms } => {
+                if elapsed_ms % (on_ms + off_ms).max(1) < on_ms {
+                    255
+                } else {
+                    0
+                }
+            }
+            Self::Breathe { period_ms } => {
+                let half = (period_ms / 2).max(1);
+                let phase = elapsed_ms % (half * 2);
+                let distance = if phase < half { phase } else { half * 2 - phase };
+                (distance * 255 / half) as u8
+            }
+            Self::Pulse { period_ms } => {
+                if elapsed_ms % period_ms.max(1) < PULSE_MS {
+                    255
+                } else {
+                    0
+                }
+            }
+            Self::Code { count } => {
+                let flashes = CODE_FLASH_MS * 2 * count as u32;
+                let phase = elapsed_ms % (flashes + CODE_PAUSE_MS);
+                if phase < flashes && phase % (CODE_FLASH_MS * 2) < CODE_FLASH_MS {
+                    255
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+const PULSE_MS: u32 = 100;
+const CODE_FLASH_MS: u32 = 200;
+const CODE_PAUSE_MS: u32 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub booting: RGB8,
+    pub ready: RGB8,
+    pub typing: RGB8,
+    pub done: RGB8,
+    pub paused: RGB8,
+    pub error: RGB8,
+    pub msc_idle: RGB8,
+    pub msc_active: RGB8,
+    pub not_mounted: RGB8,
+    pub suspended: RGB8,
+    pub gesture: RGB8,
+    pub acknowledge: RGB8,
+    pub pin_entry: RGB8,
+    pub locked: RGB8,
+    pub waiting: RGB8,
+}
+
+impl Default for Palette {
+    #[rustfmt::skip]
+    fn default() -> Self {
+        Self {
+            booting:     RGB8 { r: 20,  g: 20, b: 20 },
+            ready:       RGB8 { r: 0,   g: 20, b: 50 },
+            typing:      RGB8 { r: 0,   g: 20, b: 50 },
+            done:        RGB8 { r: 0,   g: 60, b: 0  },
+            paused:      RGB8 { r: 0,   g: 20, b: 50 },
+            error:       RGB8 { r: 128, g: 0,  b: 0  },
+            msc_idle:    RGB8 { r: 40,  g: 0,  b: 0  },
+            msc_active:  RGB8 { r: 128, g: 0,  b: 0  },
+            not_mounted: RGB8 { r: 0,   g: 20, b: 50 },
+            suspended:   RGB8 { r: 0,   g: 5,  b: 10 },
+            gesture:     RGB8 { r: 50,  g: 40, b: 0  },
+            acknowledge: RGB8 { r: 50,  g: 50, b: 50 },
+            pin_entry:   RGB8 { r: 40,  g: 0,  b: 40 },
+            locked:      RGB8 { r: 128, g: 0,  b: 0  },
+            waiting:     RGB8 { r: 60,  g: 20, b: 0  },
+        }
+    }
+}
+
+impl Palette {
+    // Overrides colors with `led.<state> = r,g,b` or `led.<state> = #rrggbb` from the config
+    pub fn from_config(config: &config::Config) -> Self {
+        let mut palette = Self::default();
+        for (name, color) in [
+            ("booting", &mut palette.booting),
+            ("ready", &mut palette.ready),
+            ("typing", &mut palette.typing),
+            ("done", &mut palette.done),
+            ("paused", &mut palette.paused),
+            ("error", &mut palette.error),
+            ("msc_idle", &mut palette.msc_idle),
+            ("msc_active", &mut palette.msc_active),
+            ("not_mounted", &mut palette.not_mounted),
+            ("suspended", &mut palette.suspended),
+            ("gesture", &mut palette.gesture),
+            ("acknowledge", &mut palette.acknowledge),
+            ("pin_entry", &mut palette.pin_entry),
+            ("locked", &mut palette.locked),
+            ("waiting", &mut palette.waiting),
+        ] {
+            let key = format!("led.{name}");
+            if let Some(value) = config.get(&key) {
+                match parse_color(value) {
+                    Some(parsed) => *color = parsed,
+                    None => log::warn!("{}", config::expected(&key, config::COLOR, value)),
+                }
+            }
+        }
+        palette
+    }
+}
+
+pub fn parse_color(text: &str) -> Option<RGB8> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        return Some(RGB8 {
+            r: (value >> 16) as u8,
+            g: (value >> 8) as u8,
+            b: value as u8,
+        });
+    }
+
+    let mut channels = text.split(',').map(|c| c.trim().parse::<u8>());
+    let color = RGB8 {
+        r: channels.next()?.ok()?,
+        g: channels.next()?.ok()?,
+        b: channels.next()?.ok()?,
+    };
+    channels.next().is_none().then_some(color)
+}
+
+pub fn appearance(state: State, palette: &Palette) -> (RGB8, Pattern) {
+    match state {
+        State::Booting => (palette.booting, Pattern::Breathe { period_ms: 1000 }),
+        State::Ready => (palette.ready, Pattern::Solid),
+        State::Typing { percent } => (
+            mix(palette.typing, palette.done, percent),
+            Pattern::Blink { on_ms: 100, off_ms: 100 },
+        ),
+        State::Done => (palette.done, Pattern::Blink { on_ms: 50, off_ms: 50 }),
+        State::Paused => (palette.paused, Pattern::Breathe { period_ms: 2000 }),
+        State::Error(code) => (palette.error, Pattern::Code { count: code.max(1) }),
+        State::MscIdle => (palette.msc_idle, Pattern::Breathe { period_ms: 3000 }),
+        State::MscActive => (palette.msc_active, Pattern::Solid),
+        State::NotMounted => (palette.not_mounted, Pattern::Blink { on_ms: 500, off_ms: 500 }),
+        State::Suspended => (palette.suspended, Pattern::Pulse { period_ms: 3000 }),
+        State::Gesture => (palette.gesture, Pattern::Solid),
+        State::Acknowledge => (palette.acknowledge, Pattern::Solid),
+        State::PinEntry => (palette.pin_entry, Pattern::Solid),
+        State::Locked => (palette.locked, Pattern::Pulse { period_ms: 1000 }),
+        State::Waiting => (palette.waiting, Pattern::Pulse { period_ms: 500 }),
+    }
+}
+
+// Color of the LED `elapsed_ms` after entering `state`, scaled by the global `brightness`
+pub fn frame(state: State, palette: &Palette, brightness: u8, elapsed_ms: u32) -> RGB8 {
+    let (color, pattern) = appearance(state, palette);
+    match pattern.level(elapsed_ms) {
+        0 => OFF,
+        level => scale(scale(color, level), brightness),
+    }
+}
+
+pub fn scale(color: RGB8, level: u8) -> RGB8 {
+    let channel = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    RGB8 {
+        r: channel(color.r),
+        g: channel(color.g),
+        b: channel(color.b),
+    }
+}
+
+// Linear interpolation from `from` (0%) to `to` (100%)
+pub fn mix(from: RGB8, to: RGB8, percent: u8) -> RGB8 {
+    let percent = percent.min(100) as i16;
+    let channel = |a: u8, b: u8| (a as i16 + (b as i16 - a as i16) * percent / 100) as u8;
+    RGB8 {
+        r: channel(from.r, to.r),
+        g: channel(from.g, to.g),
+        b: channel(from.b, to.b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rising edges of `pattern` within its first `duration_ms`
+    fn flashes(pattern: Pattern, duration_ms: u32) -> usize {
+        (1..duration_ms)
+            .filter(|&ms| pattern.level(ms - 1) == 0 && pattern.level(ms) > 0)
+            .count()
+            + (pattern.level(0) > 0) as usize
+    }
+
+    #[test]
+    fn solid_and_blink() {
+        assert!((0..5000).all(|ms| Pattern::Solid.level(ms) == 255));
+
+        let blink = Pattern::Blink {
+            on_ms: 100,
+            off_ms: 300,
+        };
+        assert_eq!(blink.level(0), 255);
+        assert_eq!(blink.level(99), 255);
+        assert_eq!(blink.level(100), 0);
+        assert_eq!(blink.level(399), 0);
+        assert_eq!(blink.level(400), 255);
+        assert_eq!(flashes(blink, 4000), 10);
+    }
+
+    #[test]
+    fn breathe_fades_in_and_out() {
+        let breathe = Pattern::Breathe { period_ms: 1000 };
+        assert_eq!(breathe.level(0), 0);
+        assert_eq!(breathe.level(250), 127);
+        assert_eq!(breathe.level(500), 255);
+        assert_eq!(breathe.level(750), 127);
+        assert_eq!(breathe.level(1000), 0);
+        assert!((0..500).all(|ms| breathe.level(ms) <= breathe.level(ms + 1)));
+    }
+
+    #[test]
+    fn pulse_flashes_once_per_period() {
+        let pulse = Pattern::Pulse { period_ms: 1000 };
+        assert_eq!(pulse.level(0), 255);
+        assert_eq!(pulse.level(PULSE_MS), 0);
+        assert_eq!(pulse.level(1000), 255);
+        assert_eq!(flashes(pulse, 5000), 5);
+    }
+
+    #[test]
+    fn error_codes_blink_their_count() {
+        for code in 1..=6 {
+            let (color, pattern) = appearance(State::Error(code), &Palette::default());
+            assert_eq!(color, Palette::default().error);
+            let cycle = CODE_FLASH_MS * 2 * code as u32 + CODE_PAUSE_MS;
+            assert_eq!(flashes(pattern, cycle), code as usize, "code {code}");
+            assert_eq!(flashes(pattern, cycle * 3), code as usize * 3);
+        }
+        // Never dark forever
+        let (_, pattern) = appearance(State::Error(0), &Palette::default());
+        assert_eq!(pattern, Pattern::Code { count: 1 });
+    }
+
+    #[test]
+    fn appearance_of_states() {
+        let palette = Palette::default();
+        assert_eq!(
+            appearance(State::Ready, &palette),
+            (palette.ready, Pattern::Solid)
+        );
+        assert_eq!(
+            appearance(State::Typing { percent: 0 }, &palette).0,
+            palette.typing
+        );
+        assert_eq!(
+            appearance(State::Typing { percent: 100 }, &palette).0,
+            palette.done
+        );
+        assert_eq!(
+            appearance(State::Typing { percent: 50 }, &palette).0,
+            mix(palette.typing, palette.done, 50)
+        );
+        assert!(matches!(
+            appearance(State::Paused, &palette).1,
+            Pattern::Breathe { .. }
+        ));
+        assert!(matches!(
+            appearance(State::Waiting, &palette).1,
+            Pattern::Pulse { .. }
+        ));
+    }
+
+    #[test]
+    fn frames_are_scaled_and_dark_when_off() {
+        let palette = Palette::default();
+        assert_eq!(frame(State::Ready, &palette, 255, 0), palette.ready);
+        assert_eq!(
+            frame(State::MscActive, &palette, 128, 0),
+            scale(palette.msc_active, 128)
+        );
+        assert_eq!(frame(State::Done, &palette, 255, 75), OFF);
+        assert_eq!(frame(State::Ready, &palette, 0, 0), OFF);
+    }
+
+    #[test]
+    fn colors() {
+        let orange = RGB8 {
+            r: 255,
+            g: 128,
+            b: 0,
+        };
+        assert_eq!(parse_color("#ff8000"), Some(orange));
+        assert_eq!(parse_color(" 255, 128 ,0 "), Some(orange));
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("255,128"), None);
+        assert_eq!(parse_color("255,128,0,0"), None);
+        assert_eq!(parse_color("256,0,0"), None);
+        assert_eq!(mix(OFF, orange, 100), orange);
+        assert_eq!(mix(orange, OFF, 200), OFF);
+    }
+}
@@ -1,3 +1,18 @@
 #![feature(cstr_count_bytes)]
 
+pub mod bytecode;
+pub mod config;
+pub mod console;
+pub mod crypto;
+pub mod escape;
+pub mod hotp;
+pub mod led;
+pub mod lint;
+pub mod logger;
+pub mod payload;
+pub mod progress;
+pub mod protocol;
+pub mod script;
+pub mod settings;
+pub mod template;
 pub mod usb;
@@ -0,0 +1,555 @@
+// Checks a payload before it is typed: untypeable characters, unknown commands, typing into a
+// window that has no time to open, keys held and never released, and how long typing takes.
+//
+// Scripts are also run dry, which finds runtime errors and gives the run time of loops. The
+// device writes the report of an invalid script to `lint.txt` on boot, and `autokbd lint`
+// prints it on the host.
+
+use crate::escape;
+use crate::script::{self, Expr, Script, Statement, StatementKind};
+use crate::usb::keycode::{self, KeyChord};
+use std::time::Duration;
+
+pub const FILE_NAME: &str = "lint.txt";
+
+// Chords opening a window or menu, which takes a moment to accept input
+const OPENERS: &[&str] = &[
+    "GUI",
+    "GUI+r",
+    "GUI+x",
+    "GUI+SPACE",
+    "CTRL+ESC",
+    "CTRL+SHIFT+ESC",
+    "CTRL+ALT+t",
+    "ALT+F2",
+];
+
+// Delay expected after an opener
+const OPEN_DELAY: Duration = Duration::from_millis(300);
+
+// Longer delays are more likely a typo than intended
+const LONG_DELAY: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    // Unknown commands and other parse errors
+    Syntax,
+    // Errors of the dry run, e.g. endless loops
+    Runtime,
+    // Dry runs still going while reading the keyboard LEDs, which the host may end
+    Polling,
+    Untypeable,
+    Delay,
+    Unbalanced,
+}
+
+impl Check {
+    // Whether the payload cannot be typed as intended at all
+    pub fn is_error(self) -> bool {
+        matches!(self, Self::Syntax | Self::Runtime)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub check: Check,
+    // `file:line`, `file:line:column`, or just `file`
+    pub location: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = if self.check.is_error() {
+            "error"
+        } else {
+            "warning"
+        };
+        write!(f, "{}: {severity}: {}", self.location, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    // Time typing takes, unknown when the payload does not finish
+    pub estimate: Option<Duration>,
+    // WAIT_FOR_* commands run, which take as long as the host or operator does
+    pub waits: u32,
+}
+
+impl Report {
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|finding| finding.check.is_error())
+    }
+
+    // The last line of the report
+    pub fn summary(&self) -> String {
+        let estimate = match self.estimate {
+            Some(estimate) => format!("{estimate:.1?}"),
+            None => "unknown".into(),
+        };
+        match self.waits {
+            0 => format!("estimated run time: {estimate}"),
+            waits => format!("estimated run time: {estimate} plus {waits} wait(s)"),
+        }
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "{finding}")?;
+        }
+        writeln!(f, "{}", self.summary())
+    }
+}
+
+// `tap` is the time a key takes to type, i.e. hold and release time of `usb::Timing`
+pub fn plain(text: &str, file: &str, tap: Duration) -> Report {
+    let mut report = Report::default();
+    let mut typed = 0;
+    for (number, line) in text.split('\n').enumerate() {
+        for (column, char) in line.chars().enumerate() {
+            if keycode::character_to_chord(char).is_some() {
+                typed += 1;
+                continue;
+            }
+            report.findings.push(Finding {
+                check: Check::Untypeable,
+                location: format!("{file}:{}:{}", number + 1, column + 1),
+                message: untypeable(char),
+            });
+        }
+    }
+    // Newlines are typed too
+    typed += text.matches('\n').count() as u32;
+    report.estimate = Some(tap * typed);
+    report
+}
+
+// Payloads with inline escapes; tokens have no position, so only syntax errors have one
+pub fn escaped(text: &str, file: &str, tap: Duration) -> Report {
+    let tokens = match escape::tokenize(text) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            let (line, column) = escape::line_column(text, e.offset());
+            return Report {
+                findings: vec![Finding {
+                    check: Check::Syntax,
+                    location: format!("{file}:{line}:{column}"),
+                    message: e.to_string(),
+                }],
+                ..Report::default()
+            };
+        }
+    };
+
+    let mut checker = Checker::default();
+    let mut estimate = Duration::ZERO;
+    for token in &tokens {
+        let event = match *token {
+            escape::Token::Text(ref text) => {
+                estimate += tap * typeable(text);
+                Event::Type(text)
+            }
+            escape::Token::Tap { chord, count } => {
+                estimate += tap * count;
+                Event::Tap(chord)
+            }
+            escape::Token::Press(chord) => Event::Press(chord),
+            escape::Token::Release(chord) => Event::Release(chord),
+            escape::Token::Sleep(duration) => {
+                estimate += duration;
+                Event::Sleep(Some(duration))
+            }
+        };
+        checker.event(file, event);
+    }
+    checker.finish();
+    Report {
+        findings: checker.findings,
+        estimate: Some(estimate),
+        waits: 0,
+    }
+}
+
+// Scripts; `load` reads included files as for `script::parse`
+pub fn script(
+    text: &str,
+    file: &str,
+    load: &mut dyn FnMut(&str) -> std::io::Result<String>,
+    tap: Duration,
+) -> Report {
+    let script = match script::parse(text, file, load) {
+        Ok(script) => script,
+        Err(e) => {
+            return Report {
+                findings: vec![Finding {
+                    check: Check::Syntax,
+                    location: e.location.to_string(),
+                    message: e.kind.to_string(),
+                }],
+                ..Report::default()
+            }
+        }
+    };
+
+    let mut checker = Checker::default();
+    checker.walk(&script, &script.statements, &mut Vec::new());
+    checker.finish();
+    let mut report = Report {
+        findings: checker.findings,
+        ..Report::default()
+    };
+
+    let mut estimator = Estimator {
+        tap,
+        ..Estimator::default()
+    };
+    match script::run(&script, &mut estimator, script::Limits::DEFAULT) {
+        Ok(()) => report.estimate = Some(estimator.elapsed),
+        Err(script::RunError::Runtime { location, error }) => {
+            use script::RuntimeError;
+            // E.g. `WHILE CAPS_LOCK == 0`, which ends once a program on the host toggles it
+            let (check, message) = match error {
+                RuntimeError::StepLimit(_) | RuntimeError::Timeout(_) if estimator.reads_leds => (
+                    Check::Polling,
+                    format!("{error} unless the host changes the keyboard LEDs"),
+                ),
+                error => (Check::Runtime, error.to_string()),
+            };
+            report.findings.push(Finding {
+                check,
+                location: location.to_string(),
+                message,
+            })
+        }
+        Err(script::RunError::Host(never)) => match never {},
+    }
+    report.waits = estimator.waits;
+    report
+}
+
+// What a cooperating host does for a script waiting on the keyboard LEDs: nothing if they
+// already match, otherwise toggle one of them
+pub fn simulate_leds(leds: u8, done: &mut dyn FnMut(u8) -> bool) -> Option<u8> {
+    std::iter::once(leds)
+        .chain((0..3).map(|bit| leds ^ (1 << bit)))
+        .find(|&leds| done(leds))
+}
+
+fn untypeable(char: char) -> String {
+    format!(
+        "{}, skipped when typed",
+        keycode::KeycodeError::Unmappable(char)
+    )
+}
+
+fn typeable(text: &str) -> u32 {
+    text.chars()
+        .filter(|&char| keycode::character_to_chord(char).is_some())
+        .count() as u32
+}
+
+// What the static checks look at, from escapes or script statements
+enum Event<'a> {
+    Type(&'a str),
+    Tap(KeyChord),
+    Press(KeyChord),
+    Release(KeyChord),
+    // None when the duration is only known at run time
+    Sleep(Option<Duration>),
+    Wait,
+}
+
+// Follows a payload in the order it is written; blocks and loops are read once, so these are
+// hints rather than proofs
+#[derive(Default)]
+struct Checker {
+    findings: Vec<Finding>,
+    down: KeyChord,
+    // Location of the last HOLD, for keys never released
+    last_press: Option<String>,
+    // A window being opened, until enough time has passed to type into it
+    opening: Option<(KeyChord, String)>,
+    // DEFAULT_DELAY, if known
+    default_delay: Option<Duration>,
+}
+
+impl Checker {
+    fn walk(&mut self, script: &Script, statements: &[Statement], calls: &mut Vec<String>) {
+        for statement in statements {
+            let location = statement.location.to_string();
+            let event = match &statement.kind {
+                StatementKind::Type { text, .. } => Event::Type(text),
+                StatementKind::Tap(chord) => Event::Tap(*chord),
+                StatementKind::Hold(chord) => Event::Press(*chord),
+                StatementKind::Release(chord) => Event::Release(*chord),
+                StatementKind::Delay(Expr::Number(ms)) => {
+                    Event::Sleep(Some(Duration::from_millis((*ms).max(0) as u64)))
+                }
+                StatementKind::Delay(_) => Event::Sleep(None),
+                StatementKind::DefaultDelay(ms) => {
+                    self.default_delay = match ms {
+                        Expr::Number(ms) => Some(Duration::from_millis((*ms).max(0) as u64)),
+                        _ => None,
+                    };
+                    continue;
+                }
+                StatementKind::Assign { .. } | StatementKind::Return => continue,
+                StatementKind::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (_, body) in branches {
+                        self.walk(script, body, calls);
+                    }
+                    self.walk(script, otherwise, calls);
+                    continue;
+                }
+                StatementKind::While { body, .. } => {
+                    self.walk(script, body, calls);
+                    continue;
+                }
+                // Read where it is called, once per call chain
+                StatementKind::Call(name) => {
+                    if !calls.contains(name) {
+                        calls.push(name.clone());
+                        self.walk(script, &script.functions[name], calls);
+                        calls.pop();
+                    }
+                    continue;
+                }
+                StatementKind::WaitForLock { .. } | StatementKind::WaitForButton { .. } => {
+                    Event::Wait
+                }
+            };
+            self.event(&location, event);
+        }
+    }
+
+    fn event(&mut self, location: &str, event: Event) {
+        match event {
+            Event::Type(text) => {
+                for char in text.chars() {
+                    if keycode::character_to_chord(char).is_none() {
+                        self.report(Check::Untypeable, location, untypeable(char));
+                    }
+                }
+                self.check_opening(location);
+                self.pause();
+            }
+            Event::Tap(chord) => {
+                self.check_opening(location);
+                if OPENERS
+                    .iter()
+                    .any(|opener| opener.parse().ok() == Some(chord))
+                {
+                    self.opening = Some((chord, location.into()));
+                }
+                self.pause();
+            }
+            Event::Press(chord) => {
+                self.check_opening(location);
+                if self.down.merge(&chord).is_err() {
+                    let message = format!("more than {} keys held", keycode::MAX_CHORD_KEYS);
+                    self.report(Check::Unbalanced, location, message);
+                }
+                self.last_press = Some(location.into());
+                self.pause();
+            }
+            Event::Release(chord) => {
+                let not_down = chord.difference(&self.down);
+                if !not_down.is_empty() {
+                    let message = format!("{not_down} released without being held");
+                    self.report(Check::Unbalanced, location, message);
+                }
+                self.down.remove(&chord);
+                self.pause();
+            }
+            Event::Sleep(duration) => {
+                // Unknown durations get the benefit of the doubt
+                let long_enough = match duration {
+                    Some(duration) => duration >= OPEN_DELAY,
+                    None => true,
+                };
+                if long_enough {
+                    self.opening = None;
+                }
+                if let Some(duration) = duration.filter(|&duration| duration > LONG_DELAY) {
+                    let message = format!(
+                        "delay of {} minutes, more than {}",
+                        duration.as_secs() / 60,
+                        LONG_DELAY.as_secs() / 60
+                    );
+                    self.report(Check::Delay, location, message);
+                }
+            }
+            Event::Wait => self.opening = None,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let (false, Some(location)) = (self.down.is_empty(), self.last_press.take()) {
+            let message = format!("{} still held at the end, released then", self.down);
+            self.report(Check::Unbalanced, &location, message);
+        }
+    }
+
+    // Input right after an opener probably goes nowhere
+    fn check_opening(&mut self, location: &str) {
+        if let Some((chord, opened)) = self.opening.take() {
+            // Escapes have no line to point to
+            let opened = if opened == location {
+                String::new()
+            } else {
+                format!(" ({opened})")
+            };
+            let message = format!(
+                "input right after {chord}{opened}, add a delay of {} ms or more",
+                OPEN_DELAY.as_millis()
+            );
+            self.report(Check::Delay, location, message);
+        }
+    }
+
+    // DEFAULT_DELAY after a command, which may give a window enough time
+    fn pause(&mut self) {
+        if self.default_delay.is_some_and(|delay| delay >= OPEN_DELAY) {
+            self.opening = None;
+        }
+    }
+
+    // Commands in loops and functions are read more than once
+    fn report(&mut self, check: Check, location: &str, message: String) {
+        let finding = Finding {
+            check,
+            location: location.into(),
+            message,
+        };
+        if !self.findings.contains(&finding) {
+            self.findings.push(finding);
+        }
+    }
+}
+
+// Runs a script without sending anything, adding up the time it takes
+#[derive(Default)]
+struct Estimator {
+    tap: Duration,
+    elapsed: Duration,
+    waits: u32,
+    leds: u8,
+    // Whether the script has looked at the LEDs
+    reads_leds: bool,
+}
+
+impl script::Host for Estimator {
+    type Error = std::convert::Infallible;
+
+    fn type_text(&mut self, text: &str) -> Result<(), Self::Error> {
+        self.elapsed += self.tap * typeable(text);
+        Ok(())
+    }
+
+    fn tap(&mut self, _: KeyChord) -> Result<(), Self::Error> {
+        self.elapsed += self.tap;
+        Ok(())
+    }
+
+    fn press(&mut self, _: KeyChord) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn release(&mut self, _: KeyChord) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn sleep(&mut self, duration: Duration) -> Result<(), Self::Error> {
+        self.elapsed += duration;
+        Ok(())
+    }
+
+    fn keyboard_leds(&mut self) -> u8 {
+        self.reads_leds = true;
+        self.leds
+    }
+
+    fn wait_for_leds(
+        &mut self,
+        done: &mut dyn FnMut(u8) -> bool,
+        _: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        self.waits += 1;
+        self.reads_leds = true;
+        match simulate_leds(self.leds, done) {
+            Some(leds) => {
+                self.leds = leds;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn wait_for_button(&mut self, _: Option<Duration>) -> Result<bool, Self::Error> {
+        self.waits += 1;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(text: &str) -> Report {
+        script(
+            text,
+            "test.ducky",
+            &mut |path| panic!("included {path}"),
+            Duration::from_millis(80),
+        )
+    }
+
+    fn checks(report: &Report) -> Vec<Check> {
+        report
+            .findings
+            .iter()
+            .map(|finding| finding.check)
+            .collect()
+    }
+
+    #[test]
+    fn dry_run() {
+        let report =
+            lint("STRING ab\nDELAY 100\nWAIT_FOR_CAPS_ON\nIF CAPS_LOCK THEN\nENTER\nEND_IF");
+        assert_eq!(report.findings, []);
+        assert_eq!(report.estimate, Some(Duration::from_millis(340)));
+        assert_eq!(report.waits, 1);
+
+        let report = lint("STRING a\nVAR $n = 0\nDELAY 1 / $n");
+        assert_eq!(checks(&report), [Check::Runtime]);
+        assert_eq!(report.findings[0].location, "test.ducky:3");
+        assert!(report.has_errors());
+        assert_eq!(report.estimate, None);
+    }
+
+    #[test]
+    fn polling() {
+        // Ended by the host toggling Caps Lock, which the dry run does not
+        let report = lint("WHILE CAPS_LOCK == 0\nDELAY 100\nEND_WHILE\nSTRING done");
+        assert_eq!(checks(&report), [Check::Polling]);
+        assert!(!report.has_errors());
+        assert_eq!(report.estimate, None);
+        let report = lint("WHILE TRUE\nWAIT_FOR_SCROLL_CHANGE\nSTRING chunk\nEND_WHILE");
+        assert_eq!(checks(&report), [Check::Polling]);
+
+        // Nothing the host does ends these
+        assert_eq!(
+            checks(&lint("WHILE TRUE\nDELAY 100\nEND_WHILE")),
+            [Check::Runtime]
+        );
+        let report = lint("VAR $i = 0\nWHILE $i >= 0\n$i = $i + 1\nEND_WHILE");
+        assert_eq!(checks(&report), [Check::Runtime]);
+    }
+}
@@ -0,0 +1,198 @@
+// Tees `log` output into a size-capped file on the storage partition, so that users without
+// a UART adapter can read what happened by booting into MSC mode.
+//
+// Records are queued in RAM and written by a thread of their own every `FLUSH_INTERVAL`, so that
+// logging never waits for the flash, whichever thread logs. Records logged before the partition
+// is mounted stay queued until it is `attach`ed. Nothing is written while detached, in particular
+// while the host owns the drive in MSC mode.
+
+use esp_idf_svc::log::EspLogger;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+use std::time::Duration;
+
+pub const FILE_NAME: &str = "boot.log";
+// The log of the previous boot, or the first half of a long session
+pub const PREVIOUS_FILE_NAME: &str = "boot.1.log";
+
+// Records queued at most; more are dropped and counted until the next flush
+const QUEUE_SIZE: usize = 8 * 1024;
+
+// Also what is lost when the device is unplugged without warning
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+static LOGGER: FileLogger = FileLogger {
+    console: EspLogger::new(),
+    queue: Mutex::new(Some(String::new())),
+    file: Mutex::new(None),
+};
+
+// Records that did not fit into the queue since the last flush
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+static FLUSH_THREAD: Once = Once::new();
+
+struct FileLogger {
+    console: EspLogger,
+    // Lines not written yet; `None` once detached
+    queue: Mutex<Option<String>>,
+    // `None` until attached
+    file: Mutex<Option<LogFile>>,
+}
+
+struct LogFile {
+    dir: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    max_size: u64,
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.console.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "[{:>8}ms {:5} {}] {}\n",
+            uptime_ms(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // Nothing logs while holding the queue, so waiting for it cannot deadlock
+        if let Some(queue) = lock_queue().as_mut() {
+            if queue.len() + line.len() <= QUEUE_SIZE {
+                queue.push_str(&line);
+            } else {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        flush();
+    }
+}
+
+impl LogFile {
+    // Fails only if the log cannot be rotated
+    fn write(&mut self, text: &str) -> std::io::Result<()> {
+        if self.size + text.len() as u64 > self.max_size {
+            self.file = rotate(&self.dir)?;
+            self.size = 0;
+        }
+        if self.file.write_all(text.as_bytes()).is_ok() {
+            self.size += text.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn lock_queue() -> MutexGuard<'static, Option<String>> {
+    LOGGER.queue.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_file() -> MutexGuard<'static, Option<LogFile>> {
+    LOGGER.file.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Writes the queued records, if attached. Records logged meanwhile (e.g. by the VFS) are queued
+// for the next flush.
+pub fn flush() {
+    let mut file = lock_file();
+    let Some(log_file) = file.as_mut() else {
+        return;
+    };
+    let Some(text) = lock_queue().as_mut().map(std::mem::take) else {
+        return;
+    };
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if text.is_empty() && dropped == 0 {
+        return;
+    }
+
+    let mut written = Ok(());
+    if dropped > 0 {
+        let line = format!(
+            "[{:>8}ms WARN  logger] {dropped} records dropped\n",
+            uptime_ms()
+        );
+        written = log_file.write(&line);
+    }
+    written = written.and_then(|_| log_file.write(&text));
+    match written {
+        // Synced once per flush rather than per record
+        Ok(()) => {
+            log_file.file.sync_data().ok();
+        }
+        Err(_) => {
+            *file = None;
+            *lock_queue() = None;
+        }
+    }
+}
+
+// Moves the current log aside and starts a new one
+fn rotate(dir: &Path) -> std::io::Result<std::fs::File> {
+    let path = dir.join(FILE_NAME);
+    if path.exists() {
+        let previous = dir.join(PREVIOUS_FILE_NAME);
+        // FAT cannot rename onto an existing file
+        std::fs::remove_file(&previous).ok();
+        std::fs::rename(&path, &previous)?;
+    }
+    std::fs::File::create(path)
+}
+
+fn uptime_ms() -> i64 {
+    unsafe { esp_idf_svc::sys::esp_timer_get_time() / 1000 }
+}
+
+// Replaces `EspLogger::initialize_default`
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+}
+
+// Starts writing `dir/boot.log`, keeping the log of the previous boot as `dir/boot.1.log`
+pub fn attach(dir: impl AsRef<Path>, max_size: u64) -> std::io::Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    let file = rotate(dir)?;
+
+    *lock_file() = Some(LogFile {
+        dir: dir.into(),
+        file,
+        size: 0,
+        max_size,
+    });
+    let mut spawned = Ok(());
+    FLUSH_THREAD.call_once(|| {
+        spawned = std::thread::Builder::new()
+            .name("logger".into())
+            // Writing FAT through the VFS takes more than the usual 4 KiB
+            .stack_size(8 * 1024)
+            .spawn(|| loop {
+                std::thread::sleep(FLUSH_INTERVAL);
+                flush();
+            })
+            .map(|_| ());
+    });
+    flush();
+    spawned
+}
+
+// Stops writing the file and drops queued records, e.g. before the drive is handed to the host
+pub fn detach() {
+    *lock_queue() = None;
+    *lock_file() = None;
+}
@@ -1,23 +1,49 @@
 use esp_idf_svc::{hal, sys};
-use smart_leds_trait::SmartLedsWrite;
-use std::io::Read as _;
+use std::io::Write as _;
+use std::time::Duration;
 use usbd_hid::descriptor::SerializedDescriptor as _;
-use ws2812_esp32_rmt_driver::{lib_smart_leds::Ws2812Esp32Rmt, RGB8};
+use ws2812_esp32_rmt_driver::lib_smart_leds::Ws2812Esp32Rmt;
 
-use m5atom_auto_keyboard::usb;
+use m5atom_auto_keyboard::led::{self, StatusLed};
+use m5atom_auto_keyboard::{
+    bytecode, config, console, crypto, escape, hotp, lint, logger, payload, progress, protocol,
+    script, settings, template, usb,
+};
+use zeroize::Zeroizing;
+
+type Button = hal::gpio::PinDriver<'static, hal::gpio::Gpio41, hal::gpio::Input>;
+// The pin of `Button`
+const BUTTON_GPIO: sys::gpio_num_t = 41;
+type Uart = hal::uart::UartDriver<'static>;
+
+// Keep the button pressed this long at boot to expose the drive read-only
+const READ_ONLY_GESTURE_MS: u32 = 3000;
+
+// PIN entry timings, see `crypto::PinEntry`
+const PIN_DIGIT_PAUSE_MS: u32 = 1000;
+const PIN_LONG_PRESS_MS: u32 = 1500;
+
+// How long a runtime error is signalled before the LED returns to the current state
+const ERROR_DISPLAY: Duration = Duration::from_secs(3);
+
+// "Done" flash after a payload has been typed completely
+const DONE_DISPLAY: Duration = Duration::from_millis(1500);
+
+// Autorun types anyway if the host has not set the keyboard LEDs by then
+const AUTORUN_LEDS_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, teed into a file once storage is mounted
+    logger::init();
 
     let peripherals = hal::peripherals::Peripherals::take()?;
 
     let config = hal::uart::UartConfig::default().baudrate(hal::units::Hertz(115_200));
-    let _uart1 = hal::uart::UartDriver::new(
+    let uart1 = hal::uart::UartDriver::new(
         peripherals.uart1,
         peripherals.pins.gpio42,
         peripherals.pins.gpio40,
@@ -25,47 +51,181 @@ fn main() -> anyhow::Result<()> {
         Option::<hal::gpio::AnyIOPin>::None,
         &config,
     )?;
-    log::info!("Log is now sent to UART1");
+    log::info!("Console is available on UART1");
 
     let mut button = hal::gpio::PinDriver::input(peripherals.pins.gpio41)?;
     button.set_pull(hal::gpio::Pull::Down)?;
+    // Read without the driver, which the main loop owns; down reads low like `button.is_low()`
+    usb::controller::set_abort_button(|| unsafe { sys::gpio_get_level(BUTTON_GPIO) } == 0);
     log::info!("Button initialized");
 
-    let mut led = Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio35).unwrap();
+    let led = Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio35)
+        .map_err(|e| anyhow::anyhow!("cannot initialize LED: {e:?}"))?;
+    let status = StatusLed::spawn(led)?;
     log::info!("LED initialized");
 
-    // Expose MSC to host machine only when the device is started with its button pressed down
-    let is_msc_mode = button.is_low();
+    // Keep signalling a fatal error on the LED instead of silently stopping
+    if let Err(e) = run(button, uart1, &status) {
+        log::error!("{e:?}");
+        status.set(led::ErrorCode::of(&e).into());
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+    Ok(())
+}
+
+fn run(button: Button, uart: Uart, status: &StatusLed) -> anyhow::Result<()> {
+    let mut store = settings::Store::open(esp_idf_svc::nvs::EspDefaultNvsPartition::take()?)?;
+
+    // Expose MSC to host machine only when the device is started with its button pressed down,
+    // or once after `reboot msc` on the console
+    let is_msc_mode = match store.take_next_mode()? {
+        Some(mode) => mode == settings::Mode::Msc,
+        None => button.is_low(),
+    };
 
     log::info!("MSC mode: {is_msc_mode:?}");
 
-    let keys: Option<Vec<u8>> = if is_msc_mode {
+    let config = {
+        let _mounted = usb::storage::mount_without_msc("/usb")?;
+        config::Config::load(format!("/usb/{}", config::FILE_NAME))?
+    };
+    status.set_palette(led::Palette::from_config(&config));
+
+    let is_read_only = is_msc_mode && {
+        status.set(led::State::Gesture);
+        let mut held_ms = 0;
+        while button.is_low() && held_ms < READ_ONLY_GESTURE_MS {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            held_ms += 10;
+        }
+        let gesture = held_ms >= READ_ONLY_GESTURE_MS;
+        if gesture {
+            status.set(led::State::Acknowledge);
+            while button.is_low() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+        status.set(led::State::Booting);
+        gesture || config.read_only()
+    };
+
+    log::info!("Read-only: {is_read_only:?}");
+
+    let mut settings = store.load()?;
+    settings.mode = if is_msc_mode {
+        settings::Mode::Msc
+    } else {
+        settings::Mode::Keyboard
+    };
+    store.save(&settings)?;
+    log::info!("Settings: {settings:?}");
+    status.set_brightness(settings.brightness);
+    usb::set_timing(settings.timing);
+
+    // The console can select another slot, which is loaded on the next boot
+    let slot = settings.slot;
+    let payload_path = format!("/usb/{}", config.payload_file(slot));
+
+    // Keyboard mode keeps the drive mounted locally for the whole session, so that the log can be
+    // read from MSC mode later; in MSC mode the host owns the drive and nothing is written to it
+    let _mounted = if is_msc_mode {
+        logger::detach();
         None
     } else {
-        let _mounted = usb::storage::mount_without_msc("/usb")?;
-        std::fs::File::create_new("/usb/input.txt").ok();
-        let keys: Vec<u8> = std::fs::File::options()
-            .read(true)
-            .open("/usb/input.txt")?
-            .bytes()
-            .flatten()
-            .collect();
+        let mounted = usb::storage::mount_without_msc("/usb")?;
+        match config.log_max_size() {
+            0 => logger::detach(),
+            max_size => {
+                if let Err(e) = logger::attach("/usb/logs", max_size as u64) {
+                    logger::detach();
+                    log::warn!("cannot write log to storage: {e}");
+                }
+            }
+        }
+        Some(mounted)
+    };
 
-        log::info!(
-            "content: {:?}",
-            String::from_utf8(keys.clone()).unwrap_or("(cannot print)".into())
-        );
+    let extension = std::path::Path::new(&payload_path).extension();
+    let escapes = config.escapes() || extension == Some(escape::FILE_EXTENSION.as_ref());
+    let script_name = (config.slot_mode(slot) == config::SlotMode::Script
+        || extension == Some(script::FILE_EXTENSION.as_ref()))
+    .then(|| config.payload_file(slot));
+    // Compiled payloads are run from the drive instead of being loaded
+    let mut compiled =
+        (extension == Some(bytecode::FILE_EXTENSION.as_ref())).then(|| payload_path.clone());
+    // So are plain payloads, which can then be paused, see `type_streamed`
+    let mut streamed = false;
 
-        // log::info!("keys: {keys:?}");
+    let mut keys: Option<Vec<u8>> = if is_msc_mode {
+        None
+    } else {
+        // Move a freshly provisioned HOTP secret out of the drive
+        let hotp_path = format!("/usb/{}", hotp::FILE_NAME);
+        if let Ok(text) = std::fs::read_to_string(&hotp_path) {
+            match hotp::Hotp::parse(&text) {
+                Ok(hotp) => {
+                    store.set_hotp(&hotp)?;
+                    std::fs::remove_file(&hotp_path)?;
+                    log::info!("HOTP provisioned: {hotp:?}");
+                }
+                Err(e) => log::error!("cannot provision HOTP from {hotp_path}: {e}"),
+            }
+        }
+
+        std::fs::File::create_new(&payload_path).ok();
+        if compiled.is_some() {
+            log::info!("content: compiled, run from {payload_path}");
+            None
+        } else {
+            let mut stream = payload::Stream::open(std::fs::File::open(&payload_path)?, 0)?;
+            // Decryption, templates, escapes and scripts need the whole payload at once
+            if !escapes
+                && script_name.is_none()
+                && !config.templates()
+                && !crypto::is_encrypted(stream.fill()?)
+            {
+                log::info!("content: {} bytes, typed from the drive", stream.length());
+                streamed = true;
+                None
+            } else {
+                let keys = payload::read(std::fs::File::open(&payload_path)?)?;
+
+                log::info!(
+                    "content: {:?}",
+                    String::from_utf8(keys.clone()).unwrap_or("(cannot print)".into())
+                );
+
+                // log::info!("keys: {keys:?}");
+
+                Some(keys)
+            }
+        }
+    };
 
-        Some(keys)
+    // Encrypted payloads stay encrypted in RAM; only the PIN is kept to decrypt them on demand
+    let device_key = store.device_key()?;
+    let pin = match keys {
+        Some(ref mut keys) if crypto::is_encrypted(keys) => {
+            let pin = unlock(keys, &device_key, &button, status, &mut store)?;
+            if !crypto::is_device_bound(keys) {
+                bind_to_device(keys, &pin, &device_key, &payload_path);
+            }
+            Some(pin)
+        }
+        _ => None,
     };
 
     let keyboard = usb::HidInstance {
         instance_id: 0,
         report_id: 0,
         descriptor: usbd_hid::descriptor::KeyboardReport::desc(),
+        packet_size: 16,
+        has_out_endpoint: false,
     };
+    // Raw HID channel of the host tool, see `protocol`
+    let vendor_instance = usb::vendor::instance(1);
 
     let serial: &'static std::ffi::CStr = {
         let mut id: u64 = 0;
@@ -82,42 +242,1085 @@ fn main() -> anyhow::Result<()> {
         msc: c"auto-keyboard",
         serial: &serial,
     };
-    let hid_instances = [keyboard.clone()];
+    let hid_instances = [keyboard.clone(), vendor_instance.clone()];
     usb::install(string_descriptor, &hid_instances, is_msc_mode)?;
     log::info!("USB initialized");
+    let vendor = usb::vendor::Channel::new(vendor_instance);
+    let host_writes = config.host_writes();
+    let host_typing = config.host_typing();
 
     if is_msc_mode {
-        usb::storage::init_msc()?;
+        usb::storage::init_msc(is_read_only)?;
         std::fs::File::create_new("/usb/input.txt").ok();
     }
 
+    let variables = config.variables();
+
+    // Encrypted scripts are not checked: the report would leak their content to the drive
+    if let (Some(name), None) = (&script_name, &pin) {
+        match keys.as_deref().map(|source| lint_on_boot(source, name)) {
+            Some(Ok(true)) => {
+                match compile_on_boot(&mut keys, name, &settings.layout, config.templates()) {
+                    Ok(path) => compiled = path,
+                    Err(e) => {
+                        log::warn!("cannot compile {name}, it is interpreted instead: {e}");
+                        if keys.is_none() {
+                            keys = Some(payload::read(std::fs::File::open(&payload_path)?)?);
+                        }
+                    }
+                }
+            }
+            Some(Ok(false)) => status.show_for(led::ErrorCode::Payload.into(), ERROR_DISPLAY),
+            Some(Err(e)) => log::warn!("cannot write {}: {e}", lint::FILE_NAME),
+            None => {}
+        }
+    }
+    // The source is not needed anymore
+    if compiled.is_some() {
+        keys = None;
+    }
+
+    // Cleared once typed: autorun happens once per power cycle
+    let mut autorun = if is_msc_mode {
+        None
+    } else {
+        config.autorun(slot)
+    };
+    log::info!("Autorun: {autorun:?}");
+
+    let mut terminal = Terminal {
+        uart,
+        line: console::LineBuffer::default(),
+    };
+    terminal.print("auto-keyboard console, type `help` for commands\n> ");
+
+    // Where a paused streamed payload goes on; the next press resumes it
+    let mut paused: Option<payload::Position> = None;
+
     log::info!("Now waiting for a button press...");
 
     loop {
-        // Show status by LED color
-        if !is_msc_mode {
-            #[rustfmt::skip]
-            led.write([RGB8 { r: 0, g: 20, b: 50 }].into_iter())?;
-        } else if usb::storage::is_exposed() {
-            #[rustfmt::skip]
-            led.write([RGB8 { r: 128, g: 0, b: 0 }].into_iter())?;
+        // Show status by LED pattern
+        status.set(if is_msc_mode {
+            if usb::storage::is_exposed() {
+                led::State::MscActive
+            } else {
+                led::State::MscIdle
+            }
         } else {
-            #[rustfmt::skip]
-            led.write([RGB8 { r: 0, g: 0, b: 0 }].into_iter())?;
-        }
+            match usb::bus::state() {
+                usb::bus::State::NotMounted => led::State::NotMounted,
+                usb::bus::State::Mounted if paused.is_some() => led::State::Paused,
+                usb::bus::State::Mounted => led::State::Ready,
+                usb::bus::State::Suspended => led::State::Suspended,
+            }
+        });
 
-        if (!is_msc_mode) && button.is_low() {
-            // pushed
+        let pushed = (!is_msc_mode) && button.is_low();
+        if pushed {
             while button.is_low() {
                 std::thread::sleep(std::time::Duration::from_millis(10))
             }
-            if let Some(ref keys) = keys {
-                println!("pushing keys: {keys:?}");
-                (&keyboard).type_keys(&mut keys.iter().map(|key| key.clone()));
-                println!("pushed");
+        }
+
+        // Requests from the host tool; typing the selected slot is handled like a button press
+        let mut requested = false;
+        if let Some(request) = vendor.poll() {
+            let result = match request.command {
+                // No payload is loaded in MSC mode
+                protocol::Command::Type if request.data.is_empty() && is_msc_mode => Err(
+                    anyhow::anyhow!("reboot into keyboard mode to type the selected slot"),
+                ),
+                protocol::Command::Type if request.data.is_empty() => {
+                    requested = true;
+                    Ok(Vec::new())
+                }
+                _ => serve(
+                    &request,
+                    &keyboard,
+                    is_msc_mode,
+                    host_writes,
+                    host_typing,
+                    &settings,
+                ),
             };
+            let response = match result {
+                Ok(data) => protocol::Message::new(request.command, data),
+                Err(e) => {
+                    log::warn!("vendor request {:?} failed: {e}", request.command);
+                    protocol::Message::error(request.command, e)
+                }
+            };
+            if let Err(e) = vendor.respond(&response) {
+                log::error!("cannot respond to the host: {e}");
+            }
+        }
+
+        let autorun_now = match autorun {
+            Some(ref options) if usb::bus::state() == usb::bus::State::Mounted => {
+                std::thread::sleep(Duration::from_millis(options.delay_ms as u64));
+                if options.wait_for_leds {
+                    wait_for_keyboard_leds(AUTORUN_LEDS_TIMEOUT);
+                }
+                log::info!("Autorun: typing slot {slot}");
+                autorun = None;
+                true
+            }
+            _ => false,
+        };
+
+        if pushed || autorun_now || requested {
+            if config.slot_mode(slot) == config::SlotMode::Hotp {
+                match store.hotp()? {
+                    Some(hotp) => {
+                        status.set(led::State::Typing { percent: 0 });
+                        let counter = store.next_hotp_counter()?;
+                        let mut code = hotp.code(counter);
+                        if hotp.enter {
+                            code.push('\n');
+                        }
+                        match (&keyboard).type_keys(&mut code.chars()) {
+                            Ok(()) => {
+                                log::info!("HOTP code typed (counter: {counter})");
+                                status.show_for(led::State::Done, DONE_DISPLAY);
+                            }
+                            Err(usb::SendError::Aborted) => {
+                                log::info!("typing aborted");
+                                status
+                                    .show_for(led::State::Acknowledge, Duration::from_millis(300));
+                                wait_for_release(&button);
+                            }
+                            Err(e) => {
+                                log::error!("typing HOTP code failed: {e}");
+                                status.show_for(led::ErrorCode::HidSend.into(), ERROR_DISPLAY);
+                            }
+                        }
+                    }
+                    None => {
+                        log::error!("HOTP slot selected but no secret is provisioned");
+                        status.show_for(led::ErrorCode::Hotp.into(), ERROR_DISPLAY);
+                    }
+                }
+            } else if let Some(ref path) = compiled {
+                status.set(led::State::Typing { percent: 0 });
+                let result = run_compiled(&keyboard, path, &settings.layout, &button, status);
+                finish_typing(result, status, &button, &mut store, slot);
+            } else if streamed {
+                status.set(led::State::Typing { percent: 0 });
+                match type_streamed(&keyboard, &payload_path, paused.take(), &button) {
+                    Ok(Some(position)) => {
+                        log::info!(
+                            "typing paused at byte {} of {}",
+                            position.offset,
+                            position.length
+                        );
+                        paused = Some(position);
+                    }
+                    result => finish_typing(result.map(|_| ()), status, &button, &mut store, slot),
+                }
+            } else if let Some(ref keys) = keys {
+                let decrypted = match pin {
+                    Some(ref pin) => match crypto::decrypt(keys, pin, &device_key) {
+                        Ok(plaintext) => Some(plaintext),
+                        Err(e) => {
+                            log::error!("cannot decrypt payload: {e}");
+                            status.show_for(led::ErrorCode::Payload.into(), ERROR_DISPLAY);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let keys = decrypted.as_deref().map(Vec::as_slice).unwrap_or(keys);
+
+                let expanded = match std::str::from_utf8(keys) {
+                    Ok(text) if config.templates() && template::contains_placeholders(text) => {
+                        let mut context = template::Context {
+                            serial: serial.to_str()?,
+                            slot,
+                            variables: &variables,
+                            counter: &mut || {
+                                store.next_counter().unwrap_or_else(|e| {
+                                    log::error!("cannot update counter: {e}");
+                                    0
+                                })
+                            },
+                            random: &mut || unsafe { sys::esp_random() },
+                        };
+                        match template::expand(text, &mut context) {
+                            Ok(text) => Some(Zeroizing::new(text.into_bytes())),
+                            Err(e) => {
+                                log::error!("cannot expand template: {e}");
+                                status.show_for(led::ErrorCode::Payload.into(), ERROR_DISPLAY);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                let keys = expanded.as_deref().map(Vec::as_slice).unwrap_or(keys);
+
+                if pin.is_none() {
+                    println!("pushing keys: {keys:?}");
+                }
+                let prepared = match prepare(keys, escapes, script_name.as_deref()) {
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        log::error!("cannot parse payload: {e}");
+                        status.show_for(led::ErrorCode::Payload.into(), ERROR_DISPLAY);
+                        continue;
+                    }
+                };
+
+                status.set(led::State::Typing { percent: 0 });
+                let typed = match prepared {
+                    Prepared::Plain => (&keyboard)
+                        .type_keys(&mut keys.iter().map(|key| key.clone()))
+                        .map_err(Into::into),
+                    Prepared::Escaped(ref tokens) => {
+                        type_escaped(&keyboard, tokens).map_err(Into::into)
+                    }
+                    Prepared::Script(ref script) => {
+                        run_script(&keyboard, &button, status, |host| {
+                            match script::run(script, host, script::Limits::DEFAULT) {
+                                // Keep the controller error itself for `led::ErrorCode::of`
+                                Err(script::RunError::Host(e)) => Err(e.into()),
+                                result => Ok(result?),
+                            }
+                        })
+                    }
+                };
+                finish_typing(typed, status, &button, &mut store, slot);
+            };
+        }
+
+        while let Some(line) = terminal.poll() {
+            let result = match console::parse(&line) {
+                Ok(Some(command)) => execute(
+                    command,
+                    &terminal,
+                    &keyboard,
+                    &hid_instances,
+                    is_msc_mode,
+                    status,
+                    &mut store,
+                    &mut settings,
+                ),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                terminal.print(&format!("error: {e}\n"));
+                // `type` and `key` can be aborted with a long press too
+                if is_aborted(&e) {
+                    wait_for_release(&button);
+                }
+            }
+            terminal.print("> ");
         }
 
         std::thread::sleep(std::time::Duration::from_millis(10));
     }
 }
+
+// Development console on UART1, see `console::HELP`
+struct Terminal {
+    uart: Uart,
+    line: console::LineBuffer,
+}
+
+impl Terminal {
+    // Never blocks; echoes what is received, as serial terminals do not echo locally
+    fn poll(&mut self) -> Option<String> {
+        let mut byte = [0u8];
+        while let Ok(1) = self.uart.read(&mut byte, hal::delay::NON_BLOCK) {
+            match byte[0] {
+                b'\r' | b'\n' => self.print("\n"),
+                0x08 | 0x7f => self.print("\x08 \x08"),
+                _ => {
+                    self.uart.write(&byte).ok();
+                }
+            }
+            if let Some(line) = self.line.push(byte[0]) {
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    fn print(&self, text: &str) {
+        self.uart.write(text.replace('\n', "\r\n").as_bytes()).ok();
+    }
+}
+
+// The host owns the drive in MSC mode; writing behind its back corrupts the FAT
+fn ensure_drive(is_msc_mode: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !is_msc_mode,
+        "the drive is exposed to the host, reboot into keyboard mode to access files"
+    );
+    Ok(())
+}
+
+// Any program on the host can use the host channel, so it may only change payloads on request
+fn ensure_host_writes(host_writes: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        host_writes,
+        "writing from the host is disabled, see `host_writes` in {}",
+        config::FILE_NAME
+    );
+    Ok(())
+}
+
+fn drive_path(path: &str) -> String {
+    format!("/usb/{path}")
+}
+
+// `key: value` lines, shown by the console and sent to the host tool
+fn status(settings: &settings::Settings) -> String {
+    let leds = match usb::keyboard_leds() {
+        None => "not set by the host".to_string(),
+        Some(leds) => ["num", "caps", "scroll", "compose", "kana"]
+            .iter()
+            .enumerate()
+            .map(|(bit, name)| {
+                let on = if leds & (1 << bit) != 0 { "on" } else { "off" };
+                format!("{name} {on}")
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let (done, total) = progress::PROGRESS.get();
+    format!(
+        "mode: {:?}\nslot: {}\nbus: {:?}\ndrive exposed: {}\nkeyboard LEDs: {leds}\nprogress: {done}/{total}\n",
+        settings.mode,
+        settings.slot,
+        usb::bus::state(),
+        usb::storage::is_exposed(),
+    )
+}
+
+// Handles a request of the host tool, returning the data of the response
+fn serve(
+    request: &protocol::Message,
+    keyboard: &usb::HidInstance<'static>,
+    is_msc_mode: bool,
+    host_writes: bool,
+    host_typing: bool,
+    settings: &settings::Settings,
+) -> anyhow::Result<Vec<u8>> {
+    use protocol::Command;
+
+    let config_path = drive_path(config::FILE_NAME);
+    match request.command {
+        Command::Status => Ok(status(settings).into_bytes()),
+        Command::ReadFile => {
+            ensure_drive(is_msc_mode)?;
+            let path = console::relative_path(std::str::from_utf8(&request.data)?)?;
+            let content = std::fs::read(drive_path(&path))?;
+            anyhow::ensure!(
+                content.len() <= protocol::MAX_MESSAGE_SIZE,
+                "{path} is too large to transfer ({} bytes)",
+                content.len()
+            );
+            Ok(content)
+        }
+        Command::WriteFile => {
+            ensure_drive(is_msc_mode)?;
+            ensure_host_writes(host_writes)?;
+            let (path, content) = protocol::split_path(&request.data)
+                .ok_or_else(|| anyhow::anyhow!("malformed write request"))?;
+            let path = console::relative_path(path)?;
+            std::fs::write(drive_path(&path), content)?;
+            log::info!("vendor: {path} written ({} bytes)", content.len());
+            Ok(Vec::new())
+        }
+        Command::ReadConfig => {
+            ensure_drive(is_msc_mode)?;
+            // A missing file is the default configuration
+            match std::fs::read(&config_path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                result => Ok(result?),
+            }
+        }
+        Command::WriteConfig => {
+            ensure_drive(is_msc_mode)?;
+            ensure_host_writes(host_writes)?;
+            // Parsed first, so that problems end up in the boot log right away
+            config::Config::parse(std::str::from_utf8(&request.data)?);
+            std::fs::write(&config_path, &request.data)?;
+            log::info!("vendor: {} written", config::FILE_NAME);
+            Ok(Vec::new())
+        }
+        Command::Type => {
+            anyhow::ensure!(
+                host_typing,
+                "typing from the host is disabled, see `host_typing` in {}",
+                config::FILE_NAME
+            );
+            keyboard.type_keys(&mut std::str::from_utf8(&request.data)?.chars())?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+// A payload ready to be typed; escapes and scripts are parsed completely beforehand, so that a
+// syntax error never leaves a payload half-typed
+enum Prepared {
+    Plain,
+    Escaped(Vec<escape::Token>),
+    Script(script::Script),
+}
+
+// `script_name` is the payload file name if it is a script
+fn prepare(payload: &[u8], escapes: bool, script_name: Option<&str>) -> anyhow::Result<Prepared> {
+    if !escapes && script_name.is_none() {
+        return Ok(Prepared::Plain);
+    }
+    let text = std::str::from_utf8(payload)?;
+    Ok(match script_name {
+        Some(name) => Prepared::Script(script::parse(text, name, &mut read_include)?),
+        None => Prepared::Escaped(escape::tokenize(text)?),
+    })
+}
+
+// INCLUDE reads other files of the drive
+fn read_include(path: &str) -> std::io::Result<String> {
+    let path = console::relative_path(path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    std::fs::read_to_string(drive_path(&path))
+}
+
+// Compiles a valid script next to it, so that typing runs it from the drive instead of RAM; the
+// file is only written when the script has changed. None for scripts with template
+// placeholders, which are expanded when typing and so stay interpreted.
+//
+// The source is dropped once parsed, so that it, the script and the compiled code are not in RAM
+// all at once; it is kept for scripts that stay interpreted, and is gone if compiling fails.
+fn compile_on_boot(
+    source: &mut Option<Vec<u8>>,
+    name: &str,
+    layout: &str,
+    templates: bool,
+) -> anyhow::Result<Option<String>> {
+    let Some(text) = source.as_deref() else {
+        return Ok(None);
+    };
+    let text = std::str::from_utf8(text)?;
+    if templates && template::contains_placeholders(text) {
+        return Ok(None);
+    }
+    let script = script::parse(text, name, &mut read_include)?;
+    *source = None;
+    let compiled = bytecode::compile(&script, layout)?;
+    drop(script);
+
+    // The previous file is checked piece by piece; a damaged one is replaced too
+    let header = bytecode::Header::read(&mut compiled.as_slice())?;
+    let path = drive_path(&format!("{name}.{}", bytecode::FILE_EXTENSION));
+    let unchanged = std::fs::File::open(&path)
+        .map_err(bytecode::Error::from)
+        .and_then(|file| bytecode::Program::load(file, None))
+        .is_ok_and(|previous| *previous.header() == header);
+    if !unchanged {
+        std::fs::write(&path, &compiled)?;
+        log::info!("{name} compiled to {path} ({} bytes)", compiled.len());
+    }
+    Ok(Some(path))
+}
+
+// Writes what is wrong with an invalid script to the drive, where it can be read in MSC mode,
+// and removes the report of a previous boot otherwise; false if the script is invalid
+fn lint_on_boot(payload: &[u8], name: &str) -> std::io::Result<bool> {
+    let path = drive_path(lint::FILE_NAME);
+    // Typing reports it
+    let Ok(text) = std::str::from_utf8(payload) else {
+        return Ok(false);
+    };
+    let timing = usb::timing();
+    let tap = Duration::from_millis((timing.hold_ms + timing.release_ms).into());
+    let report = lint::script(text, name, &mut read_include, tap);
+
+    if report.has_errors() {
+        log::error!("{name} is invalid, see {}", lint::FILE_NAME);
+        std::fs::write(path, report.to_string())?;
+        return Ok(false);
+    }
+    for finding in &report.findings {
+        log::warn!("{finding}");
+    }
+    log::info!("{name}: {}", report.summary());
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(true),
+    }
+}
+
+// Types text with the controller, skipping unmappable characters like `HidInstance::type_keys`
+fn type_text(
+    controller: &mut usb::controller::KeyboardController,
+    text: &str,
+) -> Result<(), usb::controller::ControllerError> {
+    let mut skipped = 0;
+    for char in text.chars() {
+        progress::PROGRESS.advance(1);
+        match controller.type_char(char) {
+            Err(usb::controller::ControllerError::Keycode(_)) => skipped += 1,
+            result => result?,
+        }
+    }
+    // Not the characters themselves, the log is kept on the drive
+    if skipped > 0 {
+        log::warn!("skipped {skipped} unmappable characters");
+    }
+    Ok(())
+}
+
+// Types a payload with inline escapes, publishing progress like `HidInstance::type_keys`
+fn type_escaped(
+    keyboard: &usb::HidInstance<'static>,
+    tokens: &[escape::Token],
+) -> Result<(), usb::controller::ControllerError> {
+    use escape::Token;
+
+    let total = tokens
+        .iter()
+        .map(|token| match token {
+            Token::Text(text) => text.chars().count(),
+            Token::Tap { count, .. } => *count as usize,
+            Token::Press(_) | Token::Release(_) | Token::Sleep(_) => 0,
+        })
+        .sum();
+    progress::PROGRESS.start(total);
+
+    let mut controller = usb::controller::KeyboardController::new(keyboard);
+    for token in tokens {
+        match token {
+            Token::Text(text) => type_text(&mut controller, text)?,
+            Token::Tap { chord, count } => {
+                for _ in 0..*count {
+                    progress::PROGRESS.advance(1);
+                    controller.tap(*chord)?;
+                }
+            }
+            Token::Press(chord) => controller.press(*chord)?,
+            Token::Release(chord) => controller.release(*chord)?,
+            Token::Sleep(duration) => controller.sleep(*duration)?,
+        }
+    }
+    // Keys left down by `{KEY down}` are released by the controller
+    drop(controller);
+    keyboard.flush(usb::REPORT_TIMEOUT)?;
+    progress::PROGRESS.finish();
+    Ok(())
+}
+
+// Runs scripts on the keyboard
+struct ScriptHost<'a> {
+    controller: usb::controller::KeyboardController<'a>,
+    button: &'a Button,
+    status: &'a StatusLed,
+}
+
+impl script::Host for ScriptHost<'_> {
+    type Error = usb::controller::ControllerError;
+
+    fn type_text(&mut self, text: &str) -> Result<(), Self::Error> {
+        type_text(&mut self.controller, text)
+    }
+
+    fn tap(&mut self, chord: usb::keycode::KeyChord) -> Result<(), Self::Error> {
+        self.controller.tap(chord)
+    }
+
+    fn press(&mut self, chord: usb::keycode::KeyChord) -> Result<(), Self::Error> {
+        self.controller.press(chord)
+    }
+
+    fn release(&mut self, chord: usb::keycode::KeyChord) -> Result<(), Self::Error> {
+        self.controller.release(chord)
+    }
+
+    fn sleep(&mut self, duration: Duration) -> Result<(), Self::Error> {
+        Ok(self.controller.sleep(duration)?)
+    }
+
+    fn keyboard_leds(&mut self) -> u8 {
+        usb::keyboard_leds().unwrap_or(0)
+    }
+
+    fn wait_for_leds(
+        &mut self,
+        done: &mut dyn FnMut(u8) -> bool,
+        timeout: Option<Duration>,
+    ) -> Result<bool, Self::Error> {
+        let leds = || usb::keyboard_leds().unwrap_or(0);
+        Ok(self.controller.wait_until(|| done(leds()), timeout)?)
+    }
+
+    // A press goes on when the button is released; a long press aborts the script
+    fn wait_for_button(&mut self, timeout: Option<Duration>) -> Result<bool, Self::Error> {
+        let previous = self.status.state();
+        self.status.set(led::State::Waiting);
+        let result = self.wait_for_press(timeout);
+        self.status.set(previous);
+        result
+    }
+}
+
+impl ScriptHost<'_> {
+    // A long press is noticed by the controller while waiting for the release
+    fn wait_for_press(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<bool, usb::controller::ControllerError> {
+        let button = self.button;
+        log::info!("Script waiting for the button...");
+        if !self.controller.wait_until(|| button.is_low(), timeout)? {
+            return Ok(false);
+        }
+        self.controller.wait_until(|| !button.is_low(), None)?;
+        Ok(true)
+    }
+}
+
+// Types a plain payload while reading it from the drive, from `resume` on if it was paused.
+// Pressing the button pauses it again; the position to resume at is returned then.
+fn type_streamed(
+    keyboard: &usb::HidInstance<'static>,
+    path: &str,
+    resume: Option<payload::Position>,
+    button: &Button,
+) -> anyhow::Result<Option<payload::Position>> {
+    let file = std::fs::File::open(path).map_err(payload::Error::from)?;
+    let mut stream = match resume {
+        Some(position) => payload::Stream::resume(file, position)?,
+        None => payload::Stream::open(file, 0)?,
+    };
+    let mut keys = StreamedKeys {
+        stream: &mut stream,
+        button,
+        paused: false,
+        error: None,
+        started: false,
+    };
+    keyboard.type_keys(&mut keys)?;
+    let (paused, error) = (keys.paused, keys.error);
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    if !paused {
+        return Ok(None);
+    }
+    wait_for_release(button);
+    Ok(Some(stream.position()))
+}
+
+// The bytes of a streamed payload, until the button is pressed or reading fails
+struct StreamedKeys<'a> {
+    stream: &'a mut payload::Stream<std::fs::File>,
+    button: &'a Button,
+    paused: bool,
+    error: Option<payload::Error>,
+    // Whether progress has been advanced past what was typed before a pause
+    started: bool,
+}
+
+impl Iterator for StreamedKeys<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        // `type_keys` starts progress from 0 right before the first byte
+        if !self.started {
+            self.started = true;
+            progress::PROGRESS.advance(self.stream.offset() as usize);
+        }
+        if self.button.is_low() {
+            self.paused = true;
+            return None;
+        }
+        match self.stream.fill() {
+            Ok(bytes) => {
+                let byte = *bytes.first()?;
+                self.stream.consume(1);
+                Some(byte)
+            }
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    // Progress counts the whole payload, so that a resumed one goes on from where it was
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.stream.length() as usize))
+    }
+}
+
+// Runs a script or compiled payload with `run`. Its length is unknown, so progress only shows
+// when it is done.
+fn run_script(
+    keyboard: &usb::HidInstance<'static>,
+    button: &Button,
+    status: &StatusLed,
+    run: impl FnOnce(&mut ScriptHost) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    progress::PROGRESS.start(0);
+    let mut host = ScriptHost {
+        controller: usb::controller::KeyboardController::new(keyboard),
+        button,
+        status,
+    };
+    run(&mut host)?;
+    // Keys left down by HOLD are released by the controller
+    drop(host);
+    keyboard.flush(usb::REPORT_TIMEOUT)?;
+    progress::PROGRESS.finish();
+    Ok(())
+}
+
+// Runs a compiled payload while reading it from the drive, see `bytecode`
+fn run_compiled(
+    keyboard: &usb::HidInstance<'static>,
+    path: &str,
+    layout: &str,
+    button: &Button,
+    status: &StatusLed,
+) -> anyhow::Result<()> {
+    let file = std::fs::File::open(path).map_err(bytecode::Error::from)?;
+    let mut program = bytecode::Program::load(file, Some(layout))?;
+    run_script(keyboard, button, status, |host| {
+        match bytecode::run(&mut program, host, script::Limits::DEFAULT) {
+            // Keep the inner errors themselves, so that `led::ErrorCode::of` sees them
+            Err(bytecode::RunError::Host(e)) => Err(e.into()),
+            Err(bytecode::RunError::Program(e)) => Err(e.into()),
+            result => Ok(result?),
+        }
+    })
+}
+
+// The main loop would take a button still down for a new press
+fn wait_for_release(button: &Button) {
+    while button.is_low() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Whether typing failed because of a long press, from the controller or `type_keys`
+fn is_aborted(e: &anyhow::Error) -> bool {
+    use usb::controller::ControllerError;
+    use usb::SendError;
+
+    matches!(
+        e.downcast_ref(),
+        Some(ControllerError::Send(SendError::Aborted))
+    ) || matches!(e.downcast_ref(), Some(SendError::Aborted))
+}
+
+// Shows how typing went and counts the use of the slot
+fn finish_typing(
+    typed: anyhow::Result<()>,
+    status: &StatusLed,
+    button: &Button,
+    store: &mut settings::Store,
+    slot: u8,
+) {
+    if let Err(e) = typed {
+        // Aborting on purpose, with a long press, is not an error
+        if is_aborted(&e) {
+            log::info!("typing aborted");
+            status.show_for(led::State::Acknowledge, Duration::from_millis(300));
+            wait_for_release(button);
+        } else {
+            log::error!("typing failed: {e}");
+            status.show_for(led::ErrorCode::of(&e).into(), ERROR_DISPLAY);
+        }
+        return;
+    }
+    println!("pushed");
+    status.show_for(led::State::Done, DONE_DISPLAY);
+    // The payload has been typed anyway, so a failing counter is not worth stopping for
+    match store.increment_usage(slot) {
+        Ok(count) => log::info!("slot {slot} used {count} times"),
+        Err(e) => log::warn!("cannot count usage of slot {slot}: {e}"),
+    }
+}
+
+fn execute(
+    command: console::Command,
+    terminal: &Terminal,
+    keyboard: &usb::HidInstance<'static>,
+    hid_instances: &[usb::HidInstance<'static>],
+    is_msc_mode: bool,
+    status: &StatusLed,
+    store: &mut settings::Store,
+    settings: &mut settings::Settings,
+) -> anyhow::Result<()> {
+    use console::Command;
+
+    match command {
+        Command::Help => terminal.print(console::HELP),
+        Command::List { dir } => {
+            ensure_drive(is_msc_mode)?;
+            let mut entries: Vec<_> = std::fs::read_dir(drive_path(dir.as_deref().unwrap_or("")))?
+                .flatten()
+                .collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                match entry.metadata() {
+                    Ok(metadata) if metadata.is_dir() => terminal.print(&format!("{name}/\n")),
+                    Ok(metadata) => terminal.print(&format!("{name:<24} {}\n", metadata.len())),
+                    Err(_) => terminal.print(&format!("{name}\n")),
+                }
+            }
+        }
+        Command::Show { file } => {
+            ensure_drive(is_msc_mode)?;
+            let content = std::fs::read(drive_path(&file))?;
+            if crypto::is_encrypted(&content) {
+                terminal.print(&format!("(encrypted, {} bytes)\n", content.len()));
+            } else {
+                terminal.print(&String::from_utf8_lossy(&content));
+                if !content.ends_with(b"\n") {
+                    terminal.print("\n");
+                }
+            }
+        }
+        Command::Write { file, text } => {
+            ensure_drive(is_msc_mode)?;
+            std::fs::write(drive_path(&file), &text)?;
+            terminal.print(&format!(
+                "{} bytes written, payloads other than plain text are reloaded on reboot\n",
+                text.len()
+            ));
+        }
+        Command::Append { file, text } => {
+            ensure_drive(is_msc_mode)?;
+            std::fs::File::options()
+                .create(true)
+                .append(true)
+                .open(drive_path(&file))?
+                .write_all(text.as_bytes())?;
+            terminal.print(&format!(
+                "{} bytes appended, payloads other than plain text are reloaded on reboot\n",
+                text.len()
+            ));
+        }
+        Command::Remove { file } => {
+            ensure_drive(is_msc_mode)?;
+            std::fs::remove_file(drive_path(&file))?;
+        }
+        Command::Type { text } => keyboard.type_keys(&mut text.chars())?,
+        Command::Key { chords } => {
+            let chords = chords
+                .iter()
+                .map(|chord| chord.parse::<usb::keycode::KeyChord>())
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut controller = usb::controller::KeyboardController::new(keyboard);
+            for chord in chords {
+                controller.tap(chord)?;
+            }
+        }
+        Command::Layout { name: None } => terminal.print(&format!(
+            "{} (available: {})\n",
+            settings.layout,
+            usb::keycode::LAYOUTS.join(", ")
+        )),
+        Command::Layout { name: Some(name) } => {
+            anyhow::ensure!(
+                usb::keycode::LAYOUTS.contains(&name.as_str()),
+                "unsupported layout {name:?} (available: {})",
+                usb::keycode::LAYOUTS.join(", ")
+            );
+            settings.layout = name;
+            store.save(settings)?;
+        }
+        Command::Slot { set: None } => terminal.print(&format!("{}\n", settings.slot)),
+        Command::Slot { set: Some(slot) } => {
+            anyhow::ensure!(
+                slot < settings::SLOT_COUNT,
+                "no slot {slot} (0-{})",
+                settings::SLOT_COUNT - 1
+            );
+            settings.slot = slot;
+            store.save(settings)?;
+            terminal.print("takes effect after reboot\n");
+        }
+        Command::Brightness { set: None } => terminal.print(&format!("{}\n", settings.brightness)),
+        Command::Brightness {
+            set: Some(brightness),
+        } => {
+            settings.brightness = brightness;
+            store.save(settings)?;
+            status.set_brightness(brightness);
+        }
+        Command::Timing { set: None } => {
+            let timing = usb::timing();
+            terminal.print(&format!(
+                "hold {} ms, release {} ms\n",
+                timing.hold_ms, timing.release_ms
+            ));
+        }
+        Command::Timing {
+            set: Some((hold_ms, release_ms)),
+        } => {
+            settings.timing = usb::Timing {
+                hold_ms,
+                release_ms,
+            };
+            usb::set_timing(settings.timing);
+            store.save(settings)?;
+        }
+        Command::Descriptors => {
+            let device = usb::descriptor::device_descriptor();
+            let device = unsafe {
+                std::slice::from_raw_parts(
+                    &device as *const _ as *const u8,
+                    std::mem::size_of_val(&device),
+                )
+            };
+            terminal.print(&format!("device:\n{}", console::hex_dump(device)));
+            let config = usb::descriptor::config_descriptor(is_msc_mode, hid_instances)?;
+            terminal.print(&format!("configuration:\n{}", console::hex_dump(&config)));
+            for instance in hid_instances {
+                terminal.print(&format!(
+                    "report (instance {}):\n{}",
+                    instance.instance_id,
+                    console::hex_dump(instance.desc())
+                ));
+            }
+        }
+        Command::Status => terminal.print(&status(settings)),
+        Command::Reboot { mode } => {
+            if let Some(mode) = mode {
+                store.set_next_mode(match mode {
+                    console::RebootMode::Keyboard => settings::Mode::Keyboard,
+                    console::RebootMode::Msc => settings::Mode::Msc,
+                })?;
+            }
+            terminal.print("rebooting...\n");
+            logger::flush();
+            // Let the UART drain
+            std::thread::sleep(Duration::from_millis(100));
+            unsafe { sys::esp_restart() };
+        }
+    }
+    Ok(())
+}
+
+fn wait_for_keyboard_leds(timeout: Duration) {
+    let start = std::time::Instant::now();
+    while usb::keyboard_leds().is_none() {
+        if start.elapsed() >= timeout {
+            log::warn!("Host did not set the keyboard LEDs within {timeout:?}");
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Blocks until a PIN entered with the button decrypts `payload`
+fn unlock(
+    payload: &[u8],
+    device_key: &crypto::DeviceKey,
+    button: &Button,
+    status: &StatusLed,
+    store: &mut settings::Store,
+) -> anyhow::Result<Zeroizing<String>> {
+    loop {
+        let failures = store.pin_failures()?;
+        let delay = crypto::lockout_delay(failures);
+        if !delay.is_zero() {
+            log::warn!("{failures} failed PIN attempts, locked for {delay:?}");
+            status.set(led::State::Locked);
+            std::thread::sleep(delay);
+        }
+
+        log::info!("Waiting for PIN...");
+        status.set(led::State::PinEntry);
+        let pin = read_pin(button, status);
+
+        // Counted as failed before trying, so unplugging during the attempt does not skip the
+        // lockout
+        store.set_pin_failures(failures + 1)?;
+        // The plaintext is dropped (and zeroized) right away; it is decrypted again before typing
+        match crypto::validate_pin(&pin).and_then(|_| crypto::decrypt(payload, &pin, device_key)) {
+            Ok(_plaintext) => {
+                store.set_pin_failures(0)?;
+                log::info!("Payload unlocked");
+                return Ok(pin);
+            }
+            Err(e) => {
+                log::warn!("Unlock failed: {e}");
+                status.show_for(led::ErrorCode::Pin.into(), ERROR_DISPLAY);
+                std::thread::sleep(ERROR_DISPLAY);
+            }
+        }
+    }
+}
+
+// Seals an unlocked payload with the device key, on the drive too, so that a copy of the file
+// cannot be brute-forced elsewhere; see `crypto`
+fn bind_to_device(payload: &mut Vec<u8>, pin: &str, device_key: &crypto::DeviceKey, path: &str) {
+    let mut salt = [0u8; crypto::SALT_LENGTH];
+    let mut nonce = [0u8; crypto::NONCE_LENGTH];
+    unsafe {
+        sys::esp_fill_random(salt.as_mut_ptr().cast(), salt.len());
+        sys::esp_fill_random(nonce.as_mut_ptr().cast(), nonce.len());
+    }
+    let sealed = match crypto::bind_to_device(payload, pin, device_key, &salt, &nonce) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            log::warn!("cannot seal {path} with the device key: {e}");
+            return;
+        }
+    };
+    // Written aside first, so that a failed write leaves the payload as it was
+    let temporary = format!("{path}.tmp");
+    let written = std::fs::write(&temporary, &sealed)
+        .and_then(|_| std::fs::remove_file(path))
+        .and_then(|_| std::fs::rename(&temporary, path));
+    match written {
+        Ok(()) => {
+            log::info!("{path} sealed with the device key");
+            *payload = sealed;
+        }
+        Err(e) => log::warn!("cannot seal {path} with the device key: {e}"),
+    }
+}
+
+// Each digit is a run of clicks, digits are separated by a pause and a long press finishes
+fn read_pin(button: &Button, status: &StatusLed) -> Zeroizing<String> {
+    let mut entry = crypto::PinEntry::default();
+    let mut idle_ms = 0;
+
+    loop {
+        if !button.is_low() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            idle_ms += 10;
+            if idle_ms == PIN_DIGIT_PAUSE_MS {
+                entry.pause();
+            }
+            continue;
+        }
+
+        let mut held_ms = 0;
+        while button.is_low() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            held_ms += 10;
+            if held_ms == PIN_LONG_PRESS_MS {
+                // Confirm the long press before the button is released
+                status.show_for(led::State::Acknowledge, Duration::from_secs(60));
+            }
+        }
+        idle_ms = 0;
+
+        if held_ms >= PIN_LONG_PRESS_MS {
+            status.show_for(led::State::Acknowledge, Duration::from_millis(300));
+            return entry.finish();
+        }
+        entry.click();
+        status.show_for(led::State::Acknowledge, Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,251 @@
+// Reading payload files in chunks. Plain payloads are typed straight from the drive, so that one
+// as large as the `storage` partition never has to fit in RAM, and a paused one goes on from the
+// byte it stopped at unless what was typed has changed. Payloads that are needed at once (encrypted, templates, escapes, scripts)
+// are still loaded, with the same size limit and without swallowing read errors.
+
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+
+// Size of the `storage` partition in `partition.csv`; no file on the drive can be larger
+pub const MAX_SIZE: u64 = 1024 * 1024;
+
+// Read from the file at once
+pub const CHUNK_SIZE: usize = 512;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("cannot read payload: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("payload is larger than {MAX_SIZE} bytes")]
+    TooLarge,
+}
+
+// The whole payload
+pub fn read(reader: impl Read) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::new();
+    reader.take(MAX_SIZE + 1).read_to_end(&mut payload)?;
+    if payload.len() as u64 > MAX_SIZE {
+        return Err(Error::TooLarge);
+    }
+    Ok(payload)
+}
+
+// CRC-32 as in zip and PNG, bit by bit since it runs once per load; `crc` is that of the bytes
+// before, 0 at the start
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Where a paused payload goes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: u64,
+    // Of the payload when it was paused, to notice that it has been replaced since
+    pub length: u64,
+    // CRC-32 of the bytes before `offset`, to notice an edit of the same length
+    pub checksum: u32,
+}
+
+// A payload read chunk by chunk from `offset` on
+pub struct Stream<R> {
+    reader: BufReader<R>,
+    length: u64,
+    // Of the first byte not consumed yet
+    offset: u64,
+    // CRC-32 of the bytes before `offset`
+    checksum: u32,
+}
+
+impl<R: Read + Seek> Stream<R> {
+    // Reads what comes before `offset` too, for the checksum of `position`
+    pub fn open(mut reader: R, offset: u64) -> Result<Self, Error> {
+        let length = reader.seek(SeekFrom::End(0))?;
+        if length > MAX_SIZE {
+            return Err(Error::TooLarge);
+        }
+        reader.seek(SeekFrom::Start(0))?;
+        let mut stream = Self {
+            reader: BufReader::with_capacity(CHUNK_SIZE, reader),
+            length,
+            offset: 0,
+            checksum: 0,
+        };
+        let offset = offset.min(length);
+        while stream.offset < offset {
+            let chunk = stream.fill()?;
+            if chunk.is_empty() {
+                // Shorter than it was a moment ago
+                break;
+            }
+            let count = chunk.len().min((offset - stream.offset) as usize);
+            stream.consume(count);
+        }
+        Ok(stream)
+    }
+
+    // Goes on at `position` unless the payload has changed since, then from the start
+    pub fn resume(reader: R, position: Position) -> Result<Self, Error> {
+        let stream = Self::open(reader, position.offset)?;
+        if stream.position() == position {
+            return Ok(stream);
+        }
+        log::info!("payload changed while paused, starting over");
+        Self::open(stream.reader.into_inner(), 0)
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            offset: self.offset,
+            length: self.length,
+            checksum: self.checksum,
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.length - self.offset
+    }
+
+    // Bytes from the offset on, at most a chunk; empty at the end of the payload
+    pub fn fill(&mut self) -> Result<&[u8], Error> {
+        Ok(self.reader.fill_buf()?)
+    }
+
+    // Marks `count` bytes returned by `fill` as typed
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.reader.buffer().len());
+        self.checksum = crc32(self.checksum, &self.reader.buffer()[..count]);
+        self.reader.consume(count);
+        self.offset = (self.offset + count as u64).min(self.length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn payload(length: usize) -> Vec<u8> {
+        (0..length).map(|i| b'a' + (i % 26) as u8).collect()
+    }
+
+    // Everything from the offset on, in the chunks `fill` returns, consuming `step` at a time
+    fn drain(stream: &mut Stream<Cursor<Vec<u8>>>, step: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let chunk = stream.fill().unwrap();
+            if chunk.is_empty() {
+                return out;
+            }
+            assert!(chunk.len() <= CHUNK_SIZE);
+            let count = chunk.len().min(step);
+            out.extend_from_slice(&chunk[..count]);
+            stream.consume(count);
+        }
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn size_limit() {
+        let largest = payload(MAX_SIZE as usize);
+        assert_eq!(read(largest.as_slice()).unwrap(), largest);
+        let stream = Stream::open(Cursor::new(largest), 0).unwrap();
+        assert_eq!(stream.length(), MAX_SIZE);
+
+        let too_large = payload(MAX_SIZE as usize + 1);
+        assert!(matches!(read(too_large.as_slice()), Err(Error::TooLarge)));
+        assert!(matches!(
+            Stream::open(Cursor::new(too_large), 0),
+            Err(Error::TooLarge)
+        ));
+        assert_eq!(read(&b""[..]).unwrap(), b"");
+    }
+
+    #[test]
+    fn chunks() {
+        let text = payload(3 * CHUNK_SIZE + 100);
+        for step in [1, 7, CHUNK_SIZE, usize::MAX] {
+            let mut stream = Stream::open(Cursor::new(text.clone()), 0).unwrap();
+            assert_eq!(drain(&mut stream, step), text, "{step}");
+            assert_eq!(stream.remaining(), 0);
+            assert_eq!(stream.position().offset, text.len() as u64);
+            assert_eq!(stream.position().checksum, crc32(0, &text));
+        }
+
+        let mut stream = Stream::open(Cursor::new(text.clone()), 1000).unwrap();
+        assert_eq!(stream.offset(), 1000);
+        assert_eq!(stream.remaining(), text.len() as u64 - 1000);
+        assert_eq!(stream.position().checksum, crc32(0, &text[..1000]));
+        assert_eq!(drain(&mut stream, 100), &text[1000..]);
+
+        // Past the end is the end
+        let mut stream = Stream::open(Cursor::new(text.clone()), u64::MAX).unwrap();
+        assert_eq!(stream.offset(), text.len() as u64);
+        assert!(stream.fill().unwrap().is_empty());
+    }
+
+    #[test]
+    fn resume() {
+        let text = payload(2 * CHUNK_SIZE + 10);
+        let mut stream = Stream::open(Cursor::new(text.clone()), 0).unwrap();
+        let mut typed = Vec::new();
+        while typed.len() < 700 {
+            let chunk = stream.fill().unwrap();
+            let count = chunk.len().min(700 - typed.len());
+            typed.extend_from_slice(&chunk[..count]);
+            stream.consume(count);
+        }
+        let position = stream.position();
+        assert_eq!(position.offset, 700);
+
+        // Unchanged, it goes on where it stopped
+        let mut resumed = Stream::resume(Cursor::new(text.clone()), position).unwrap();
+        assert_eq!(resumed.position(), position);
+        typed.extend(drain(&mut resumed, usize::MAX));
+        assert_eq!(typed, text);
+
+        // Changed after the position, too
+        let mut edited = text.clone();
+        *edited.last_mut().unwrap() = b'!';
+        let mut resumed = Stream::resume(Cursor::new(edited.clone()), position).unwrap();
+        assert_eq!(resumed.offset(), 700);
+        assert_eq!(drain(&mut resumed, usize::MAX), &edited[700..]);
+
+        // Changed before it, or in length, it starts over
+        let mut edited = text.clone();
+        edited[3] = b'!';
+        let mut resumed = Stream::resume(Cursor::new(edited.clone()), position).unwrap();
+        assert_eq!(resumed.offset(), 0);
+        assert_eq!(drain(&mut resumed, usize::MAX), edited);
+
+        let longer = [text.as_slice(), b"more"].concat();
+        let resumed = Stream::resume(Cursor::new(longer), position).unwrap();
+        assert_eq!(resumed.offset(), 0);
+        let resumed = Stream::resume(Cursor::new(payload(500)), position).unwrap();
+        assert_eq!(resumed.offset(), 0);
+        assert_eq!(resumed.length(), 500);
+    }
+}
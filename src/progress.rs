@@ -0,0 +1,54 @@
+// Progress of the payload being typed. The typing engine publishes it and the status LED
+// renders it, so an operator knows when it is safe to unplug the device.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub static PROGRESS: Progress = Progress::new();
+
+pub struct Progress {
+    sent: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    pub const fn new() -> Self {
+        Self {
+            sent: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn start(&self, total: usize) {
+        self.sent.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, count: usize) {
+        self.sent.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.sent.store(self.total.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    // (sent, total)
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn percent(&self) -> u8 {
+        match self.get() {
+            (_, 0) => 0,
+            (sent, total) => (sent.min(total) * 100 / total) as u8,
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,203 @@
+// Framing of the vendor-defined raw HID channel, shared by the firmware and the host tool.
+//
+// Every report is REPORT_SIZE bytes: command, flags, data length, then up to DATA_SIZE bytes
+// of data. A message spans one or more reports of the same command, the first flagged FIRST
+// and the last flagged LAST. Responses echo the command of the request; ERROR marks a
+// response whose data is a UTF-8 error message.
+
+pub const REPORT_SIZE: usize = 64;
+const HEADER_SIZE: usize = 3;
+pub const DATA_SIZE: usize = REPORT_SIZE - HEADER_SIZE;
+
+// Messages are assembled in RAM
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+const FIRST: u8 = 0b001;
+const LAST: u8 = 0b010;
+const ERROR: u8 = 0b100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    // -> status lines, `key: value`
+    Status = 0x01,
+    // path -> content
+    ReadFile = 0x02,
+    // path, NUL, content -> nothing
+    WriteFile = 0x03,
+    // -> content of config.txt
+    ReadConfig = 0x04,
+    // content of config.txt -> nothing
+    WriteConfig = 0x05,
+    // nothing to type the selected slot, or UTF-8 text to type -> nothing
+    Type = 0x06,
+}
+
+impl Command {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Status),
+            0x02 => Some(Self::ReadFile),
+            0x03 => Some(Self::WriteFile),
+            0x04 => Some(Self::ReadConfig),
+            0x05 => Some(Self::WriteConfig),
+            0x06 => Some(Self::Type),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub command: Command,
+    pub error: bool,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(command: Command, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            command,
+            error: false,
+            data: data.into(),
+        }
+    }
+
+    pub fn error(command: Command, message: impl std::fmt::Display) -> Self {
+        Self {
+            command,
+            error: true,
+            data: message.to_string().into_bytes(),
+        }
+    }
+
+    // The error message of an ERROR response
+    pub fn error_message(&self) -> Option<String> {
+        self.error
+            .then(|| String::from_utf8_lossy(&self.data).into_owned())
+    }
+
+    // Always at least one report, even without data
+    pub fn encode(&self) -> Vec<[u8; REPORT_SIZE]> {
+        let chunks: Vec<&[u8]> = if self.data.is_empty() {
+            vec![&self.data]
+        } else {
+            self.data.chunks(DATA_SIZE).collect()
+        };
+        let last = chunks.len() - 1;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut flags = 0;
+                if index == 0 {
+                    flags |= FIRST;
+                }
+                if index == last {
+                    flags |= LAST;
+                }
+                if self.error {
+                    flags |= ERROR;
+                }
+                let mut report = [0; REPORT_SIZE];
+                report[0] = self.command as u8;
+                report[1] = flags;
+                report[2] = chunk.len() as u8;
+                report[HEADER_SIZE..HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+                report
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnknownCommand(u8),
+    InvalidLength(usize),
+    // A report that does not continue the message being assembled
+    OutOfSequence,
+    TooLong,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownCommand(command) => write!(f, "unknown command 0x{command:02x}"),
+            Self::InvalidLength(length) => write!(f, "invalid report length {length}"),
+            Self::OutOfSequence => write!(f, "report out of sequence"),
+            Self::TooLong => write!(f, "message longer than {MAX_MESSAGE_SIZE} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Assembles messages from reports. After an error, the partial message is dropped and the
+// next FIRST report starts over.
+#[derive(Debug, Default)]
+pub struct Assembler {
+    partial: Option<Message>,
+}
+
+impl Assembler {
+    pub const fn new() -> Self {
+        Self { partial: None }
+    }
+
+    // Returns the message once its LAST report has been pushed
+    pub fn push(&mut self, report: &[u8]) -> Result<Option<Message>, Error> {
+        let result = self.assemble(report);
+        if result.is_err() {
+            self.partial = None;
+        }
+        result
+    }
+
+    fn assemble(&mut self, report: &[u8]) -> Result<Option<Message>, Error> {
+        let [command, flags, length, ..] = *report else {
+            return Err(Error::InvalidLength(report.len()));
+        };
+        let command = Command::from_u8(command).ok_or(Error::UnknownCommand(command))?;
+        let data = report
+            .get(HEADER_SIZE..HEADER_SIZE + length as usize)
+            .filter(|_| length as usize <= DATA_SIZE)
+            .ok_or(Error::InvalidLength(report.len()))?;
+
+        if flags & FIRST != 0 {
+            self.partial = Some(Message {
+                command,
+                error: flags & ERROR != 0,
+                data: Vec::new(),
+            });
+        }
+        let message = match self.partial {
+            Some(ref mut message) if message.command == command => message,
+            _ => return Err(Error::OutOfSequence),
+        };
+        if message.data.len() + data.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::TooLong);
+        }
+        message.data.extend_from_slice(data);
+
+        Ok(match flags & LAST {
+            0 => None,
+            _ => self.partial.take(),
+        })
+    }
+}
+
+// Splits the data of a WriteFile request
+pub fn split_path(data: &[u8]) -> Option<(&str, &[u8])> {
+    let end = data.iter().position(|byte| *byte == 0)?;
+    let path = std::str::from_utf8(&data[..end]).ok()?;
+    Some((path, &data[end + 1..]))
+}
+
+// Builds the data of a WriteFile request
+pub fn join_path(path: &str, content: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(path.len() + 1 + content.len());
+    data.extend_from_slice(path.as_bytes());
+    data.push(0);
+    data.extend_from_slice(content);
+    data
+}
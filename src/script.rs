@@ -0,0 +1,191 @@
+// DuckyScript-style payload scripts, selected with `slot.N.mode = script` or the `.ducky`
+// payload extension:
+//
+//     REM comment
+//     DEFAULT_DELAY 20
+//     GUI r
+//     DELAY 500
+//     STRINGLN notepad
+//     VAR $count = 3
+//     WHILE $count > 0
+//         STRINGLN hello
+//         $count = $count - 1
+//     END_WHILE
+//     FUNCTION SAVE()
+//         CTRL s
+//     END_FUNCTION
+//     SAVE()
+//     INCLUDE common.ducky
+//     WAIT_FOR_SCROLL_CHANGE timeout=5000
+//     WAIT_FOR_BUTTON
+//     IF CAPS_LOCK THEN
+//         CAPSLOCK
+//     END_IF
+//
+// Scripts are parsed completely before anything is typed, so syntax errors never leave a
+// payload half-typed. The interpreter only talks to the keyboard through `Host`, which keeps
+// this module free of hardware and lets the host tool run scripts too.
+
+mod interpreter;
+mod parser;
+#[cfg(test)]
+pub mod testing;
+
+pub use interpreter::{run, wait_for_lock, Limits, RunError, RuntimeError};
+pub use parser::{parse, ParseError, ParseErrorKind, MAX_INCLUDE_DEPTH};
+
+use crate::usb::keycode::KeyChord;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Payload files with this extension are scripts whatever the slot mode
+pub const FILE_EXTENSION: &str = "ducky";
+
+// What a script can do to the outside world
+pub trait Host {
+    type Error: std::fmt::Display;
+
+    fn type_text(&mut self, text: &str) -> Result<(), Self::Error>;
+    fn tap(&mut self, chord: KeyChord) -> Result<(), Self::Error>;
+    fn press(&mut self, chord: KeyChord) -> Result<(), Self::Error>;
+    fn release(&mut self, chord: KeyChord) -> Result<(), Self::Error>;
+    fn sleep(&mut self, duration: Duration) -> Result<(), Self::Error>;
+    // Num/Caps/Scroll Lock bits last set by the host, see `Lock::mask`; 0 until it sets them
+    fn keyboard_leds(&mut self) -> u8;
+    // Waits until `done` is true for the keyboard LEDs; false after `timeout`
+    fn wait_for_leds(
+        &mut self,
+        done: &mut dyn FnMut(u8) -> bool,
+        timeout: Option<Duration>,
+    ) -> Result<bool, Self::Error>;
+    // Waits for the operator to press the button; false after `timeout`
+    fn wait_for_button(&mut self, timeout: Option<Duration>) -> Result<bool, Self::Error>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    pub statements: Vec<Statement>,
+    pub functions: HashMap<String, Vec<Statement>>,
+}
+
+// Where a statement comes from; included files keep their own name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Arc<str>,
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub location: Location,
+    pub kind: StatementKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementKind {
+    // STRING and STRINGLN
+    Type {
+        text: String,
+        newline: bool,
+    },
+    // A line of key names, e.g. `CTRL ALT DELETE`
+    Tap(KeyChord),
+    Hold(KeyChord),
+    Release(KeyChord),
+    Delay(Expr),
+    DefaultDelay(Expr),
+    // `VAR $NAME = ...` declares, `$NAME = ...` assigns
+    Assign {
+        name: String,
+        value: Expr,
+        declare: bool,
+    },
+    // IF, any number of ELSE IF, and ELSE
+    If {
+        branches: Vec<(Expr, Vec<Statement>)>,
+        otherwise: Vec<Statement>,
+    },
+    While {
+        condition: Expr,
+        body: Vec<Statement>,
+    },
+    Call(String),
+    Return,
+    // `WAIT_FOR_CAPS_ON`, `WAIT_FOR_SCROLL_CHANGE timeout=5000`...
+    WaitForLock {
+        lock: Lock,
+        until: LockState,
+        timeout: Option<Expr>,
+    },
+    WaitForButton {
+        timeout: Option<Expr>,
+    },
+}
+
+// Keyboard LEDs the host sets, which a cooperating program on the host can use to signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lock {
+    Num,
+    Caps,
+    Scroll,
+}
+
+impl Lock {
+    // Bit in the keyboard LED output report
+    pub fn mask(self) -> u8 {
+        match self {
+            Self::Num => 1 << 0,
+            Self::Caps => 1 << 1,
+            Self::Scroll => 1 << 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    On,
+    Off,
+    // Either way, relative to when the wait starts
+    Change,
+}
+
+// Integer expressions; conditions are true when non-zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i32),
+    Variable(String),
+    // 1 while the LED is on
+    Lock(Lock),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
@@ -0,0 +1,645 @@
+// Tree-walking interpreter. Every executed statement and loop test counts against a step
+// budget, and function calls against a depth limit, so a buggy script ends with an error
+// instead of running forever.
+
+use super::{
+    BinaryOp, Expr, Host, Location, Lock, LockState, Script, Statement, StatementKind, UnaryOp,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_steps: u32,
+    pub max_depth: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Self = Self {
+        max_steps: 100_000,
+        max_depth: 32,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    UndefinedVariable(String),
+    DivisionByZero,
+    Overflow,
+    NegativeDelay(i32),
+    StepLimit(u32),
+    RecursionLimit(usize),
+    Timeout(Duration),
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UndefinedVariable(name) => write!(f, "${name} is used before VAR"),
+            Self::DivisionByZero => write!(f, "division by zero"),
+            Self::Overflow => write!(f, "integer overflow"),
+            Self::NegativeDelay(ms) => write!(f, "negative delay {ms}"),
+            Self::StepLimit(steps) => write!(f, "still running after {steps} steps"),
+            Self::RecursionLimit(depth) => write!(f, "functions nested deeper than {depth}"),
+            Self::Timeout(timeout) => write!(f, "timed out after {} ms", timeout.as_millis()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError<E> {
+    Runtime {
+        location: Location,
+        error: RuntimeError,
+    },
+    Host(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for RunError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Runtime { location, error } => write!(f, "{location}: {error}"),
+            Self::Host(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for RunError<E> {}
+
+pub fn run<H: Host>(
+    script: &Script,
+    host: &mut H,
+    limits: Limits,
+) -> Result<(), RunError<H::Error>> {
+    let mut interpreter = Interpreter {
+        script,
+        host,
+        limits,
+        variables: HashMap::new(),
+        default_delay: Duration::ZERO,
+        steps: 0,
+        depth: 0,
+    };
+    interpreter.block(&script.statements)?;
+    Ok(())
+}
+
+enum Flow {
+    Next,
+    Return,
+}
+
+struct Interpreter<'s, 'h, H: Host> {
+    script: &'s Script,
+    host: &'h mut H,
+    limits: Limits,
+    variables: HashMap<String, i32>,
+    // Waited after every command that sends keys
+    default_delay: Duration,
+    steps: u32,
+    depth: usize,
+}
+
+impl<H: Host> Interpreter<'_, '_, H> {
+    fn block(&mut self, statements: &[Statement]) -> Result<Flow, RunError<H::Error>> {
+        for statement in statements {
+            if let Flow::Return = self.statement(statement)? {
+                return Ok(Flow::Return);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<Flow, RunError<H::Error>> {
+        let location = &statement.location;
+        self.step(location)?;
+
+        match &statement.kind {
+            StatementKind::Type { text, newline } => {
+                self.host.type_text(text).map_err(RunError::Host)?;
+                if *newline {
+                    self.host.type_text("\n").map_err(RunError::Host)?;
+                }
+                self.pause()?;
+            }
+            StatementKind::Tap(chord) => {
+                self.host.tap(*chord).map_err(RunError::Host)?;
+                self.pause()?;
+            }
+            StatementKind::Hold(chord) => {
+                self.host.press(*chord).map_err(RunError::Host)?;
+                self.pause()?;
+            }
+            StatementKind::Release(chord) => {
+                self.host.release(*chord).map_err(RunError::Host)?;
+                self.pause()?;
+            }
+            StatementKind::Delay(ms) => {
+                let duration = self.duration(ms, location)?;
+                self.host.sleep(duration).map_err(RunError::Host)?;
+            }
+            StatementKind::DefaultDelay(ms) => self.default_delay = self.duration(ms, location)?,
+            StatementKind::Assign {
+                name,
+                value,
+                declare,
+            } => {
+                let value = self.evaluate(value, location)?;
+                if !declare && !self.variables.contains_key(name) {
+                    return Err(runtime(
+                        location,
+                        RuntimeError::UndefinedVariable(name.clone()),
+                    ));
+                }
+                self.variables.insert(name.clone(), value);
+            }
+            StatementKind::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    if self.evaluate(condition, location)? != 0 {
+                        return self.block(body);
+                    }
+                }
+                return self.block(otherwise);
+            }
+            StatementKind::While { condition, body } => {
+                while self.evaluate(condition, location)? != 0 {
+                    if let Flow::Return = self.block(body)? {
+                        return Ok(Flow::Return);
+                    }
+                    self.step(location)?;
+                }
+            }
+            StatementKind::Call(name) => {
+                // Checked by the parser
+                let body = &self.script.functions[name];
+                if self.depth >= self.limits.max_depth {
+                    return Err(runtime(
+                        location,
+                        RuntimeError::RecursionLimit(self.limits.max_depth),
+                    ));
+                }
+                self.depth += 1;
+                let result = self.block(body);
+                self.depth -= 1;
+                result?;
+            }
+            StatementKind::Return => return Ok(Flow::Return),
+            StatementKind::WaitForLock {
+                lock,
+                until,
+                timeout,
+            } => {
+                let timeout = self.timeout(timeout, location)?;
+                let done = wait_for_lock(self.host, *lock, *until, timeout);
+                waited(done, timeout, location)?;
+            }
+            StatementKind::WaitForButton { timeout } => {
+                let timeout = self.timeout(timeout, location)?;
+                let pressed = self.host.wait_for_button(timeout);
+                waited(pressed, timeout, location)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn step(&mut self, location: &Location) -> Result<(), RunError<H::Error>> {
+        self.steps += 1;
+        if self.steps > self.limits.max_steps {
+            return Err(runtime(
+                location,
+                RuntimeError::StepLimit(self.limits.max_steps),
+            ));
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), RunError<H::Error>> {
+        if self.default_delay.is_zero() {
+            return Ok(());
+        }
+        self.host.sleep(self.default_delay).map_err(RunError::Host)
+    }
+
+    fn duration(&mut self, ms: &Expr, location: &Location) -> Result<Duration, RunError<H::Error>> {
+        match self.evaluate(ms, location)? {
+            ms if ms < 0 => Err(runtime(location, RuntimeError::NegativeDelay(ms))),
+            ms => Ok(Duration::from_millis(ms as u64)),
+        }
+    }
+
+    fn timeout(
+        &mut self,
+        ms: &Option<Expr>,
+        location: &Location,
+    ) -> Result<Option<Duration>, RunError<H::Error>> {
+        match ms {
+            Some(ms) => Ok(Some(self.duration(ms, location)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn evaluate(&mut self, expr: &Expr, location: &Location) -> Result<i32, RunError<H::Error>> {
+        let error = |error| runtime(location, error);
+        Ok(match expr {
+            Expr::Number(number) => *number,
+            Expr::Variable(name) => *self
+                .variables
+                .get(name)
+                .ok_or_else(|| error(RuntimeError::UndefinedVariable(name.clone())))?,
+            Expr::Lock(lock) => (self.host.keyboard_leds() & lock.mask() != 0) as i32,
+            Expr::Unary(op, operand) => {
+                let value = self.evaluate(operand, location)?;
+                op.apply(value).map_err(error)?
+            }
+            // Short-circuit, so `$n != 0 && 10 / $n > 1` is safe
+            Expr::Binary(BinaryOp::And, left, right) => {
+                (self.evaluate(left, location)? != 0 && self.evaluate(right, location)? != 0) as i32
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                (self.evaluate(left, location)? != 0 || self.evaluate(right, location)? != 0) as i32
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.evaluate(left, location)?;
+                let right = self.evaluate(right, location)?;
+                op.apply(left, right).map_err(error)?
+            }
+        })
+    }
+}
+
+impl UnaryOp {
+    pub fn apply(self, value: i32) -> Result<i32, RuntimeError> {
+        match self {
+            Self::Negate => value.checked_neg().ok_or(RuntimeError::Overflow),
+            Self::Not => Ok((value == 0) as i32),
+        }
+    }
+}
+
+impl BinaryOp {
+    // Both operands are evaluated already, so `And` and `Or` do not short-circuit here
+    pub fn apply(self, left: i32, right: i32) -> Result<i32, RuntimeError> {
+        let checked = |value: Option<i32>| value.ok_or(RuntimeError::Overflow);
+        match self {
+            Self::Add => checked(left.checked_add(right)),
+            Self::Subtract => checked(left.checked_sub(right)),
+            Self::Multiply => checked(left.checked_mul(right)),
+            Self::Divide | Self::Remainder if right == 0 => Err(RuntimeError::DivisionByZero),
+            Self::Divide => checked(left.checked_div(right)),
+            Self::Remainder => checked(left.checked_rem(right)),
+            Self::Equal => Ok((left == right) as i32),
+            Self::NotEqual => Ok((left != right) as i32),
+            Self::Less => Ok((left < right) as i32),
+            Self::LessOrEqual => Ok((left <= right) as i32),
+            Self::Greater => Ok((left > right) as i32),
+            Self::GreaterOrEqual => Ok((left >= right) as i32),
+            Self::And => Ok((left != 0 && right != 0) as i32),
+            Self::Or => Ok((left != 0 || right != 0) as i32),
+        }
+    }
+}
+
+// WAIT_FOR_<lock>_<until>, where `Change` is relative to the LEDs when it starts
+pub fn wait_for_lock<H: Host>(
+    host: &mut H,
+    lock: Lock,
+    until: LockState,
+    timeout: Option<Duration>,
+) -> Result<bool, H::Error> {
+    let mask = lock.mask();
+    let initial = host.keyboard_leds() & mask;
+    let mut done = |leds: u8| match until {
+        LockState::On => leds & mask != 0,
+        LockState::Off => leds & mask == 0,
+        LockState::Change => leds & mask != initial,
+    };
+    host.wait_for_leds(&mut done, timeout)
+}
+
+// The result of a WAIT_FOR_* command, which ends the script once it times out
+fn waited<E>(
+    done: Result<bool, E>,
+    timeout: Option<Duration>,
+    location: &Location,
+) -> Result<(), RunError<E>> {
+    match done {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(runtime(
+            location,
+            RuntimeError::Timeout(timeout.unwrap_or_default()),
+        )),
+        Err(e) => Err(RunError::Host(e)),
+    }
+}
+
+fn runtime<E>(location: &Location, error: RuntimeError) -> RunError<E> {
+    RunError::Runtime {
+        location: location.clone(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::parse;
+    use crate::script::testing::{Event, Recorder};
+    use crate::usb::keycode::KeyChord;
+    use std::convert::Infallible;
+
+    fn run_on(host: &mut Recorder, text: &str, limits: Limits) -> Result<(), RunError<Infallible>> {
+        let script = parse(text, "test.ducky", &mut |path| panic!("included {path}")).unwrap();
+        run(&script, host, limits)
+    }
+
+    fn events(text: &str) -> Vec<Event> {
+        let mut host = Recorder::default();
+        run_on(&mut host, text, Limits::DEFAULT).unwrap();
+        host.events
+    }
+
+    fn typed(text: &str) -> String {
+        events(text)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Type(text) => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The value of `expression` on line 2, which DELAY passes on as milliseconds
+    fn value(setup: &str, expression: &str) -> Result<u64, (usize, RuntimeError)> {
+        let mut host = Recorder::default();
+        run_on(
+            &mut host,
+            &format!("{setup}\nDELAY {expression}"),
+            Limits::DEFAULT,
+        )
+        .map_err(runtime_error)?;
+        match host.events.last() {
+            Some(Event::Sleep(ms)) => Ok(*ms),
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+
+    // The line and error a script ends with
+    fn error_with(host: &mut Recorder, text: &str, limits: Limits) -> (usize, RuntimeError) {
+        runtime_error(run_on(host, text, limits).unwrap_err())
+    }
+
+    fn error(text: &str) -> (usize, RuntimeError) {
+        error_with(&mut Recorder::default(), text, Limits::DEFAULT)
+    }
+
+    fn runtime_error(error: RunError<Infallible>) -> (usize, RuntimeError) {
+        match error {
+            RunError::Runtime { location, error } => (location.line, error),
+            RunError::Host(e) => match e {},
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let ctrl_s: KeyChord = "Ctrl+s".parse().unwrap();
+        let shift: KeyChord = "Shift".parse().unwrap();
+        assert_eq!(
+            events("STRINGLN  hi\nDEFAULT_DELAY 20\nCTRL s\nHOLD SHIFT\nDELAY 5\nRELEASE SHIFT"),
+            [
+                Event::Type(" hi\n".into()),
+                Event::Tap(ctrl_s),
+                Event::Sleep(20),
+                Event::Press(shift),
+                Event::Sleep(20),
+                Event::Sleep(5),
+                Event::Release(shift),
+                Event::Sleep(20),
+            ]
+        );
+        assert_eq!(error("DELAY 0 - 1"), (1, RuntimeError::NegativeDelay(-1)));
+    }
+
+    #[test]
+    fn variables() {
+        let setup = "VAR $a = 2\nVAR $b = $a * 3 + 1\n$a = $b - $a";
+        assert_eq!(value(setup, "$a"), Ok(5));
+        assert_eq!(value(setup, "$b"), Ok(7));
+        // VAR again only assigns
+        assert_eq!(value("VAR $a = 1\nVAR $a = $a + 1", "$a"), Ok(2));
+
+        assert_eq!(
+            error("STRING a\n$x = 1"),
+            (2, RuntimeError::UndefinedVariable("x".into()))
+        );
+        assert_eq!(
+            error("VAR $a = $b"),
+            (1, RuntimeError::UndefinedVariable("b".into()))
+        );
+        // Declared inside a block, visible after it
+        assert_eq!(value("IF TRUE THEN\nVAR $c = 3\nEND_IF", "$c"), Ok(3));
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(value("", "1 + 2 * 3"), Ok(7));
+        assert_eq!(value("", "(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("", "10 - 4 - 3"), Ok(3));
+        assert_eq!(value("", "17 / 5 + 17 % 5"), Ok(5));
+        assert_eq!(value("", "-(-5)"), Ok(5));
+        assert_eq!(value("", "!0 + !7 + (3 > 2) + (2 >= 3) + (1 != 1)"), Ok(2));
+        assert_eq!(value("", "TRUE + FALSE"), Ok(1));
+
+        let min = "VAR $min = -2147483647 - 1";
+        let overflow = Err((2, RuntimeError::Overflow));
+        assert_eq!(value("", "2147483647 + 1"), overflow);
+        assert_eq!(value(min, "0 - $min"), overflow);
+        assert_eq!(value(min, "-$min"), overflow);
+        assert_eq!(value(min, "$min * -1"), overflow);
+        assert_eq!(value(min, "$min / -1"), overflow);
+        assert_eq!(value(min, "$min % -1"), overflow);
+        assert_eq!(value(min, "$min - 1"), overflow);
+
+        let zero = Err((2, RuntimeError::DivisionByZero));
+        assert_eq!(value("", "1 / 0"), zero);
+        assert_eq!(value("", "1 % (2 - 2)"), zero);
+    }
+
+    #[test]
+    fn short_circuit() {
+        let setup = "VAR $n = 0";
+        assert_eq!(value(setup, "$n != 0 && 10 / $n > 1"), Ok(0));
+        assert_eq!(value(setup, "$n == 0 || 10 / $n > 1"), Ok(1));
+        assert_eq!(value(setup, "FALSE && $undefined"), Ok(0));
+        assert_eq!(value(setup, "TRUE || $undefined"), Ok(1));
+        // Both sides otherwise
+        assert_eq!(
+            value(setup, "10 / $n > 1 && $n != 0"),
+            Err((2, RuntimeError::DivisionByZero))
+        );
+        assert_eq!(
+            value(setup, "TRUE && $undefined"),
+            Err((2, RuntimeError::UndefinedVariable("undefined".into())))
+        );
+        assert_eq!(value("", "2 && 3"), Ok(1));
+        assert_eq!(value("", "0 || -1"), Ok(1));
+    }
+
+    #[test]
+    fn conditions() {
+        let script = |n| {
+            format!(
+                "VAR $n = {n}\n\
+                 IF $n == 0 THEN\nSTRING zero\n\
+                 ELSE IF $n == 1 THEN\nSTRING one\n\
+                 ELSE IF $n == 1 || $n == 2\nSTRING two\n\
+                 ELSE\nSTRING many\n\
+                 END_IF\nSTRING ."
+            )
+        };
+        assert_eq!(typed(&script(0)), "zero.");
+        assert_eq!(typed(&script(1)), "one.");
+        assert_eq!(typed(&script(2)), "two.");
+        assert_eq!(typed(&script(3)), "many.");
+        assert_eq!(typed("IF FALSE THEN\nSTRING no\nEND_IF\nSTRING ."), ".");
+
+        let mut host = Recorder {
+            leds: Lock::Caps.mask(),
+            ..Default::default()
+        };
+        let text = "IF CAPS_LOCK && !NUM_LOCK THEN\nSTRING caps\nEND_IF";
+        run_on(&mut host, text, Limits::DEFAULT).unwrap();
+        assert_eq!(host.events, [Event::Type("caps".into())]);
+    }
+
+    #[test]
+    fn loops() {
+        let text = "VAR $count = 3\nWHILE $count > 0\nSTRING x\n$count = $count - 1\nEND_WHILE";
+        assert_eq!(typed(text), "xxx");
+        assert_eq!(typed("WHILE FALSE\nSTRING x\nEND_WHILE\nSTRING ."), ".");
+
+        let nested = "VAR $i = 0\n\
+                      WHILE $i < 2\n\
+                      VAR $j = 0\n\
+                      WHILE $j < 3\nSTRING x\n$j = $j + 1\nEND_WHILE\n\
+                      STRING |\n$i = $i + 1\n\
+                      END_WHILE";
+        assert_eq!(typed(nested), "xxx|xxx|");
+    }
+
+    #[test]
+    fn functions() {
+        let text = "FUNCTION GREET()\n\
+                    STRING a\n\
+                    IF TRUE THEN\nRETURN\nEND_IF\n\
+                    STRING b\n\
+                    END_FUNCTION\n\
+                    GREET()\nGREET()\nSTRING c";
+        assert_eq!(typed(text), "aac");
+
+        // RETURN leaves loops of the function, not the caller's
+        let text = "FUNCTION FIRST()\n\
+                    VAR $i = 0\n\
+                    WHILE TRUE\n$i = $i + 1\nIF $i == 3 THEN\nRETURN\nEND_IF\nEND_WHILE\n\
+                    END_FUNCTION\n\
+                    VAR $k = 0\n\
+                    WHILE $k < 2\nFIRST()\nSTRING x\n$k = $k + 1\nEND_WHILE";
+        assert_eq!(typed(text), "xx");
+        // Functions share the script's variables
+        assert_eq!(
+            value(
+                "VAR $i = 0\nFUNCTION INC()\n$i = $i + 1\nEND_FUNCTION\nINC()\nINC()",
+                "$i"
+            ),
+            Ok(2)
+        );
+        // Calls may precede the definition
+        assert_eq!(typed("F()\nFUNCTION F()\nSTRING f\nEND_FUNCTION"), "f");
+
+        // At top level, RETURN ends the script
+        assert_eq!(typed("STRING a\nRETURN\nSTRING b"), "a");
+        assert_eq!(
+            typed("WHILE TRUE\nSTRING a\nRETURN\nEND_WHILE\nSTRING b"),
+            "a"
+        );
+    }
+
+    #[test]
+    fn step_limit() {
+        let limits = Limits {
+            max_steps: 3,
+            ..Limits::DEFAULT
+        };
+        let mut host = Recorder::default();
+        run_on(&mut host, "STRING a\nSTRING b\nSTRING c", limits).unwrap();
+        assert_eq!(
+            error_with(&mut host, "STRING a\nSTRING b\nSTRING c\nSTRING d", limits),
+            (4, RuntimeError::StepLimit(3))
+        );
+
+        // Loop tests count too, so an empty loop ends
+        assert_eq!(
+            error("WHILE TRUE\nEND_WHILE"),
+            (1, RuntimeError::StepLimit(Limits::DEFAULT.max_steps))
+        );
+    }
+
+    #[test]
+    fn recursion_limit() {
+        assert_eq!(
+            error("FUNCTION F()\nF()\nEND_FUNCTION\nF()"),
+            (2, RuntimeError::RecursionLimit(Limits::DEFAULT.max_depth))
+        );
+
+        let text = "FUNCTION A()\nB()\nEND_FUNCTION\n\
+                    FUNCTION B()\nSTRING b\nEND_FUNCTION\n\
+                    A()";
+        let limits = |max_depth| Limits {
+            max_depth,
+            ..Limits::DEFAULT
+        };
+        run_on(&mut Recorder::default(), text, limits(2)).unwrap();
+        assert_eq!(
+            error_with(&mut Recorder::default(), text, limits(1)),
+            (2, RuntimeError::RecursionLimit(1))
+        );
+    }
+
+    #[test]
+    fn waits() {
+        let mut host = Recorder {
+            leds: Lock::Caps.mask(),
+            pressed: true,
+            ..Default::default()
+        };
+        let text = "WAIT_FOR_CAPS_ON\nWAIT_FOR_NUM_OFF\nWAIT_FOR_BUTTON\nSTRING ok";
+        run_on(&mut host, text, Limits::DEFAULT).unwrap();
+        assert_eq!(host.events, [Event::Type("ok".into())]);
+
+        assert_eq!(
+            error_with(
+                &mut host,
+                "STRING a\nWAIT_FOR_CAPS_CHANGE timeout=50",
+                Limits::DEFAULT
+            ),
+            (2, RuntimeError::Timeout(Duration::from_millis(50)))
+        );
+        host.pressed = false;
+        assert_eq!(
+            error_with(&mut host, "WAIT_FOR_BUTTON timeout=10 * 2", Limits::DEFAULT),
+            (1, RuntimeError::Timeout(Duration::from_millis(20)))
+        );
+        assert_eq!(
+            error_with(&mut host, "WAIT_FOR_BUTTON timeout=-1", Limits::DEFAULT),
+            (1, RuntimeError::NegativeDelay(-1))
+        );
+    }
+}
@@ -0,0 +1,898 @@
+// Line-oriented parser: one command per line, blocks closed by END_IF, END_WHILE and
+// END_FUNCTION. INCLUDE is resolved here, so the interpreter sees a single script.
+
+use super::{BinaryOp, Expr, Location, Lock, LockState, Script, Statement, StatementKind, UnaryOp};
+use crate::usb::keycode::{Key, KeyChord, KeycodeError, Modifiers};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// How deep INCLUDE can nest
+pub const MAX_INCLUDE_DEPTH: usize = 8;
+
+// How deep IF, WHILE and FUNCTION can nest, counting across INCLUDE. Parsing, and running on
+// the device's small stack, recurse once per level.
+pub const MAX_BLOCK_DEPTH: usize = 16;
+
+// How deep precedence levels and unary operators can nest within one expression, for the same
+// reason; a pair of parentheses takes two levels
+pub const MAX_EXPRESSION_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub location: Location,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidArgument {
+        argument: String,
+        expected: &'static str,
+    },
+    InvalidExpression(String),
+    InvalidVariable(String),
+    InvalidKey(KeycodeError),
+    // A block keyword without its opening one, e.g. END_IF outside IF
+    Unexpected(String),
+    Unterminated(&'static str),
+    NestedFunction,
+    DuplicateFunction(String),
+    UndefinedFunction(String),
+    Include {
+        path: String,
+        error: String,
+    },
+    IncludeCycle(String),
+    IncludeTooDeep,
+    BlocksTooDeep,
+    ExpressionTooDeep,
+}
+
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownCommand(command) => write!(f, "unknown command {command:?}"),
+            Self::MissingArgument(name) => write!(f, "missing {name}"),
+            Self::InvalidArgument { argument, expected } => {
+                write!(f, "invalid argument {argument:?}, expected {expected}")
+            }
+            Self::InvalidExpression(text) => write!(f, "invalid expression {text:?}"),
+            Self::InvalidVariable(name) => {
+                write!(f, "invalid variable {name:?} (expected `$` and a name)")
+            }
+            Self::InvalidKey(e) => write!(f, "{e}"),
+            Self::Unexpected(keyword) => write!(f, "{keyword} without its block"),
+            Self::Unterminated(block) => write!(f, "{block} is never closed"),
+            Self::NestedFunction => write!(f, "FUNCTION inside a block"),
+            Self::DuplicateFunction(name) => write!(f, "{name}() is defined twice"),
+            Self::UndefinedFunction(name) => write!(f, "{name}() is not defined"),
+            Self::Include { path, error } => write!(f, "cannot include {path}: {error}"),
+            Self::IncludeCycle(path) => write!(f, "{path} includes itself"),
+            Self::IncludeTooDeep => {
+                write!(f, "INCLUDE nested deeper than {MAX_INCLUDE_DEPTH}")
+            }
+            Self::BlocksTooDeep => write!(f, "blocks nested deeper than {MAX_BLOCK_DEPTH}"),
+            Self::ExpressionTooDeep => {
+                write!(f, "expression nested deeper than {MAX_EXPRESSION_DEPTH}")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Keywords closing or continuing a block
+const BLOCK_KEYWORDS: &[&str] = &["ELSE", "END_IF", "END_WHILE", "END_FUNCTION"];
+
+// Parses `text`, named `file` in locations. `load` reads included files by their path as
+// written in the script.
+pub fn parse(
+    text: &str,
+    file: &str,
+    load: &mut dyn FnMut(&str) -> std::io::Result<String>,
+) -> Result<Script, ParseError> {
+    let mut parser = Parser {
+        load,
+        functions: HashMap::new(),
+        includes: vec![file.to_string()],
+        calls: Vec::new(),
+        in_block: false,
+        depth: 0,
+    };
+    let statements = parser.file(text, file)?;
+
+    for (location, name) in parser.calls {
+        if !parser.functions.contains_key(&name) {
+            return Err(ParseError {
+                location,
+                kind: ParseErrorKind::UndefinedFunction(name),
+            });
+        }
+    }
+    Ok(Script {
+        statements,
+        functions: parser.functions,
+    })
+}
+
+type Lines<'t, 'i> = dyn Iterator<Item = (Location, &'t str)> + 'i;
+
+// The line a block ended with
+struct End<'t> {
+    location: Location,
+    keyword: String,
+    rest: &'t str,
+}
+
+struct Parser<'l> {
+    load: &'l mut dyn FnMut(&str) -> std::io::Result<String>,
+    functions: HashMap<String, Vec<Statement>>,
+    // Files being parsed, outermost first
+    includes: Vec<String>,
+    // Checked once every function is known, so calls may precede definitions
+    calls: Vec<(Location, String)>,
+    // Functions are only defined at the top level of a file
+    in_block: bool,
+    // Blocks being parsed, see `MAX_BLOCK_DEPTH`
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn file(&mut self, text: &str, file: &str) -> Result<Vec<Statement>, ParseError> {
+        let file: Arc<str> = file.into();
+        let mut lines = text.lines().enumerate().map(|(number, line)| {
+            let location = Location {
+                file: file.clone(),
+                line: number + 1,
+            };
+            (location, line)
+        });
+        let (statements, _) = self.block(&mut lines, None)?;
+        Ok(statements)
+    }
+
+    // Parses until one of the keywords closing `opener`, or the end of the file at top level
+    fn block<'t>(
+        &mut self,
+        lines: &mut Lines<'t, '_>,
+        opener: Option<(&'static str, &Location, &[&str])>,
+    ) -> Result<(Vec<Statement>, Option<End<'t>>), ParseError> {
+        let mut statements = Vec::new();
+        while let Some((location, line)) = lines.next() {
+            let line = line.trim_start();
+            let (keyword, rest) = split_keyword(line);
+            let error = |kind| ParseError {
+                location: location.clone(),
+                kind,
+            };
+
+            if BLOCK_KEYWORDS.contains(&keyword.as_str()) {
+                return match opener {
+                    Some((_, _, ends)) if ends.contains(&keyword.as_str()) => Ok((
+                        statements,
+                        Some(End {
+                            location,
+                            keyword,
+                            rest,
+                        }),
+                    )),
+                    _ => Err(error(ParseErrorKind::Unexpected(keyword))),
+                };
+            }
+
+            let kind = match keyword.as_str() {
+                "" | "REM" => continue,
+                "STRING" | "STRINGLN" => StatementKind::Type {
+                    // Only the separating space is dropped, the text is typed as written
+                    text: line[keyword.len()..]
+                        .strip_prefix(' ')
+                        .unwrap_or_default()
+                        .to_string(),
+                    newline: keyword == "STRINGLN",
+                },
+                "DELAY" => StatementKind::Delay(expression(rest).map_err(error)?),
+                "DEFAULT_DELAY" | "DEFAULTDELAY" => {
+                    StatementKind::DefaultDelay(expression(rest).map_err(error)?)
+                }
+                "HOLD" => StatementKind::Hold(chord(rest).map_err(error)?),
+                "RELEASE" => StatementKind::Release(chord(rest).map_err(error)?),
+                "VAR" => assignment(rest, true).map_err(error)?,
+                "RETURN" => StatementKind::Return,
+                "IF" => self.if_block(lines, &location, rest)?,
+                "WHILE" => {
+                    let condition = expression(rest).map_err(error)?;
+                    let (body, _) = self.nested(lines, "WHILE", &location, &["END_WHILE"])?;
+                    StatementKind::While { condition, body }
+                }
+                "FUNCTION" => {
+                    if self.in_block {
+                        return Err(error(ParseErrorKind::NestedFunction));
+                    }
+                    let name = function_name(rest)
+                        .ok_or_else(|| error(ParseErrorKind::MissingArgument("function name")))?;
+                    let (body, _) = self.nested(lines, "FUNCTION", &location, &["END_FUNCTION"])?;
+                    if self.functions.insert(name.clone(), body).is_some() {
+                        return Err(error(ParseErrorKind::DuplicateFunction(name)));
+                    }
+                    continue;
+                }
+                "INCLUDE" => {
+                    statements.extend(self.include(&location, rest.trim())?);
+                    continue;
+                }
+                "WAIT_FOR_BUTTON" => StatementKind::WaitForButton {
+                    timeout: timeout(rest).map_err(error)?,
+                },
+                _ if keyword.starts_with("WAIT_FOR_") => {
+                    let (lock, until) = lock_wait(&keyword)
+                        .ok_or_else(|| error(ParseErrorKind::UnknownCommand(keyword.clone())))?;
+                    StatementKind::WaitForLock {
+                        lock,
+                        until,
+                        timeout: timeout(rest).map_err(error)?,
+                    }
+                }
+                _ if line.starts_with('$') => assignment(line, false).map_err(error)?,
+                _ => match function_name(line) {
+                    Some(name) => {
+                        self.calls.push((location.clone(), name.clone()));
+                        StatementKind::Call(name)
+                    }
+                    None => StatementKind::Tap(key_line(line).map_err(error)?),
+                },
+            };
+            statements.push(Statement { location, kind });
+        }
+
+        match opener {
+            Some((name, location, _)) => Err(ParseError {
+                location: location.clone(),
+                kind: ParseErrorKind::Unterminated(name),
+            }),
+            None => Ok((statements, None)),
+        }
+    }
+
+    fn nested<'t>(
+        &mut self,
+        lines: &mut Lines<'t, '_>,
+        name: &'static str,
+        location: &Location,
+        ends: &[&str],
+    ) -> Result<(Vec<Statement>, End<'t>), ParseError> {
+        if self.depth >= MAX_BLOCK_DEPTH {
+            return Err(ParseError {
+                location: location.clone(),
+                kind: ParseErrorKind::BlocksTooDeep,
+            });
+        }
+        let in_block = std::mem::replace(&mut self.in_block, true);
+        self.depth += 1;
+        let result = self.block(lines, Some((name, location, ends)));
+        self.depth -= 1;
+        self.in_block = in_block;
+        let (statements, end) = result?;
+        Ok((statements, end.expect("nested blocks end with a keyword")))
+    }
+
+    // `IF cond [THEN]`, then any number of `ELSE IF cond [THEN]`, an optional `ELSE`, and END_IF
+    fn if_block<'t>(
+        &mut self,
+        lines: &mut Lines<'t, '_>,
+        location: &Location,
+        condition: &'t str,
+    ) -> Result<StatementKind, ParseError> {
+        let mut branches = Vec::new();
+        let mut condition = Some((location.clone(), condition));
+        let mut otherwise = Vec::new();
+
+        while let Some((location, text)) = condition.take() {
+            let expr = expression(strip_then(text)).map_err(|kind| ParseError {
+                location: location.clone(),
+                kind,
+            })?;
+            let (body, end) = self.nested(lines, "IF", &location, &["ELSE", "END_IF"])?;
+            branches.push((expr, body));
+            if end.keyword == "END_IF" {
+                break;
+            }
+            match split_keyword(end.rest) {
+                (keyword, rest) if keyword == "IF" => condition = Some((end.location, rest)),
+                _ => otherwise = self.nested(lines, "ELSE", &end.location, &["END_IF"])?.0,
+            }
+        }
+        Ok(StatementKind::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    fn include(&mut self, location: &Location, path: &str) -> Result<Vec<Statement>, ParseError> {
+        let error = |kind| ParseError {
+            location: location.clone(),
+            kind,
+        };
+        if path.is_empty() {
+            return Err(error(ParseErrorKind::MissingArgument("file")));
+        }
+        if self.includes.iter().any(|included| included == path) {
+            return Err(error(ParseErrorKind::IncludeCycle(path.into())));
+        }
+        if self.includes.len() > MAX_INCLUDE_DEPTH {
+            return Err(error(ParseErrorKind::IncludeTooDeep));
+        }
+        let text = (self.load)(path).map_err(|e| {
+            error(ParseErrorKind::Include {
+                path: path.into(),
+                error: e.to_string(),
+            })
+        })?;
+
+        // Included files are parsed as if at top level
+        let in_block = std::mem::replace(&mut self.in_block, false);
+        self.includes.push(path.into());
+        let result = self.file(&text, path);
+        self.includes.pop();
+        self.in_block = in_block;
+        result
+    }
+}
+
+// The uppercase first word and the rest of the line
+fn split_keyword(line: &str) -> (String, &str) {
+    let line = line.trim_start();
+    let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    (keyword.to_uppercase(), rest.trim())
+}
+
+fn strip_then(condition: &str) -> &str {
+    let condition = condition.trim_end();
+    match condition.len().checked_sub(4) {
+        Some(start)
+            if condition.is_char_boundary(start)
+                && condition[start..].eq_ignore_ascii_case("THEN") =>
+        {
+            condition[..start].trim_end()
+        }
+        _ => condition,
+    }
+}
+
+// `NAME()`
+fn function_name(text: &str) -> Option<String> {
+    let name = text.trim().strip_suffix("()")?;
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    valid.then(|| name.to_string())
+}
+
+fn variable_name(text: &str) -> Result<String, ParseErrorKind> {
+    let invalid = || ParseErrorKind::InvalidVariable(text.into());
+    let name = text.strip_prefix('$').ok_or_else(invalid)?;
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    valid.then(|| name.to_string()).ok_or_else(invalid)
+}
+
+// `$NAME = expression`
+fn assignment(text: &str, declare: bool) -> Result<StatementKind, ParseErrorKind> {
+    let (name, value) = text
+        .split_once('=')
+        .ok_or(ParseErrorKind::MissingArgument("`=` and a value"))?;
+    Ok(StatementKind::Assign {
+        name: variable_name(name.trim())?,
+        value: expression(value)?,
+        declare,
+    })
+}
+
+// `WAIT_FOR_{NUM,CAPS,SCROLL}_{ON,OFF,CHANGE}`
+fn lock_wait(keyword: &str) -> Option<(Lock, LockState)> {
+    let (lock, state) = keyword.strip_prefix("WAIT_FOR_")?.split_once('_')?;
+    let lock = match lock {
+        "NUM" => Lock::Num,
+        "CAPS" => Lock::Caps,
+        "SCROLL" => Lock::Scroll,
+        _ => return None,
+    };
+    let state = match state {
+        "ON" => LockState::On,
+        "OFF" => LockState::Off,
+        "CHANGE" => LockState::Change,
+        _ => return None,
+    };
+    Some((lock, state))
+}
+
+// Nothing, or `timeout=MS`
+fn timeout(text: &str) -> Result<Option<Expr>, ParseErrorKind> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let (_, ms) = text
+        .split_once('=')
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("timeout"))
+        .ok_or_else(|| ParseErrorKind::InvalidArgument {
+            argument: text.into(),
+            expected: "`timeout=MS`",
+        })?;
+    expression(ms).map(Some)
+}
+
+// LED names in expressions, including the DuckyScript 3 variables
+fn lock_name(word: &str) -> Option<Lock> {
+    match word.to_uppercase().as_str() {
+        "NUM_LOCK" | "$_NUMLOCK_ON" => Some(Lock::Num),
+        "CAPS_LOCK" | "$_CAPSLOCK_ON" => Some(Lock::Caps),
+        "SCROLL_LOCK" | "$_SCROLLLOCK_ON" => Some(Lock::Scroll),
+        _ => None,
+    }
+}
+
+fn chord(text: &str) -> Result<KeyChord, ParseErrorKind> {
+    if text.is_empty() {
+        return Err(ParseErrorKind::MissingArgument("key"));
+    }
+    key_line(text)
+}
+
+// Key names separated by spaces or `+`, e.g. `GUI r`, `CTRL ALT DELETE` or `Ctrl+Shift+Esc`
+fn key_line(line: &str) -> Result<KeyChord, ParseErrorKind> {
+    let line = line.trim();
+    // A leading `+` is the `+` key itself
+    let first = match line.split([' ', '\t', '+']).next() {
+        Some("") if line.starts_with('+') => "+",
+        first => first.unwrap_or_default(),
+    };
+    let known = Key::from_name(first).is_some()
+        || Modifiers::from_alias(first).is_some()
+        || first.chars().count() == 1;
+    if !known {
+        return Err(ParseErrorKind::UnknownCommand(first.into()));
+    }
+    line.split_whitespace()
+        .collect::<Vec<_>>()
+        .join("+")
+        .parse()
+        .map_err(ParseErrorKind::InvalidKey)
+}
+
+pub(super) fn expression(text: &str) -> Result<Expr, ParseErrorKind> {
+    let invalid = || ParseErrorKind::InvalidExpression(text.trim().into());
+    let tokens = tokenize(text).ok_or_else(invalid)?;
+    if tokens.is_empty() {
+        return Err(ParseErrorKind::MissingArgument("expression"));
+    }
+    let mut parser = ExprParser {
+        tokens,
+        position: 0,
+        depth: 0,
+        too_deep: false,
+    };
+    let expr = parser.binary(0);
+    if parser.too_deep {
+        return Err(ParseErrorKind::ExpressionTooDeep);
+    }
+    let expr = expr.ok_or_else(invalid)?;
+    if parser.position != parser.tokens.len() {
+        return Err(invalid());
+    }
+    Ok(expr)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i32),
+    Variable(String),
+    Lock(Lock),
+    Operator(&'static str),
+}
+
+// Longest first, so that `<=` is not read as `<`
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")",
+];
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(rest.len());
+        let (token, length) = if word_end > 0 {
+            let word = &rest[..word_end];
+            let token = if let Some(lock) = lock_name(word) {
+                Token::Lock(lock)
+            } else if word.starts_with('$') {
+                Token::Variable(variable_name(word).ok()?)
+            } else if word.eq_ignore_ascii_case("TRUE") {
+                Token::Number(1)
+            } else if word.eq_ignore_ascii_case("FALSE") {
+                Token::Number(0)
+            } else {
+                Token::Number(word.parse().ok()?)
+            };
+            (token, word_end)
+        } else {
+            let operator = OPERATORS.iter().find(|op| rest.starts_with(**op))?;
+            (Token::Operator(operator), operator.len())
+        };
+        tokens.push(token);
+        rest = rest[length..].trim_start();
+    }
+    Some(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    position: usize,
+    // Calls of `binary` and `unary` in progress, see `MAX_EXPRESSION_DEPTH`
+    depth: usize,
+    // Set when parsing stopped at the limit
+    too_deep: bool,
+}
+
+impl ExprParser {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) => Some(operator),
+            _ => None,
+        }
+    }
+
+    // Runs `parse` one level deeper, or fails at the limit
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Option<Expr>) -> Option<Expr> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            self.too_deep = true;
+            return None;
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    // Precedence climbing; higher binds tighter
+    fn binary(&mut self, min_precedence: u8) -> Option<Expr> {
+        self.nested(|parser| parser.binary_from(min_precedence))
+    }
+
+    fn binary_from(&mut self, min_precedence: u8) -> Option<Expr> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.peek_operator().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Some(left)
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        self.nested(Self::operand)
+    }
+
+    fn operand(&mut self) -> Option<Expr> {
+        let token = self.tokens.get(self.position)?.clone();
+        self.position += 1;
+        match token {
+            Token::Number(number) => Some(Expr::Number(number)),
+            Token::Variable(name) => Some(Expr::Variable(name)),
+            Token::Lock(lock) => Some(Expr::Lock(lock)),
+            Token::Operator("-") => Some(Expr::Unary(UnaryOp::Negate, Box::new(self.unary()?))),
+            Token::Operator("!") => Some(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Token::Operator("(") => {
+                let expr = self.binary(0)?;
+                (self.peek_operator() == Some(")")).then(|| self.position += 1)?;
+                Some(expr)
+            }
+            Token::Operator(_) => None,
+        }
+    }
+}
+
+fn binary_operator(operator: &str) -> Option<(BinaryOp, u8)> {
+    Some(match operator {
+        "||" => (BinaryOp::Or, 0),
+        "&&" => (BinaryOp::And, 1),
+        "==" => (BinaryOp::Equal, 2),
+        "!=" => (BinaryOp::NotEqual, 2),
+        "<" => (BinaryOp::Less, 3),
+        "<=" => (BinaryOp::LessOrEqual, 3),
+        ">" => (BinaryOp::Greater, 3),
+        ">=" => (BinaryOp::GreaterOrEqual, 3),
+        "+" => (BinaryOp::Add, 4),
+        "-" => (BinaryOp::Subtract, 4),
+        "*" => (BinaryOp::Multiply, 5),
+        "/" => (BinaryOp::Divide, 5),
+        "%" => (BinaryOp::Remainder, 5),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::keycode::Modifiers;
+
+    #[test]
+    fn key_lines() {
+        let plus = key_line("+").unwrap();
+        assert_eq!(plus, "+".parse().unwrap());
+        assert_eq!(key_line("CTRL +").unwrap(), "Ctrl++".parse().unwrap());
+        assert_eq!(key_line("GUI r").unwrap(), "Gui+r".parse().unwrap());
+        assert_eq!(
+            key_line("CTRL ALT DELETE").unwrap(),
+            "Ctrl+Alt+Delete".parse().unwrap()
+        );
+        assert_eq!(
+            key_line("Ctrl+Shift+Esc").unwrap().modifiers,
+            Modifiers::LEFT_CTRL | Modifiers::LEFT_SHIFT
+        );
+        assert_eq!(
+            key_line("TYPO x"),
+            Err(ParseErrorKind::UnknownCommand("TYPO".into()))
+        );
+    }
+
+    fn parse_files(text: &str, files: &[(&str, &str)]) -> Result<Script, ParseError> {
+        parse(text, "main.ducky", &mut |path| {
+            files
+                .iter()
+                .find(|(name, _)| *name == path)
+                .map(|(_, text)| text.to_string())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        })
+    }
+
+    // The file, line and kind of the error `text` fails with
+    fn error(text: &str, files: &[(&str, &str)]) -> (String, usize, ParseErrorKind) {
+        let error = parse_files(text, files).unwrap_err();
+        (
+            error.location.file.to_string(),
+            error.location.line,
+            error.kind,
+        )
+    }
+
+    fn main_error(line: usize, kind: ParseErrorKind) -> (String, usize, ParseErrorKind) {
+        ("main.ducky".into(), line, kind)
+    }
+
+    #[test]
+    fn blocks() {
+        let script = parse_files(
+            "IF $a == 1 THEN\nSTRING one\nELSE IF $a == 2\nSTRING two\nELSE\nSTRING other\nEND_IF",
+            &[],
+        )
+        .unwrap();
+        let [Statement {
+            kind:
+                StatementKind::If {
+                    branches,
+                    otherwise,
+                },
+            ..
+        }] = script.statements.as_slice()
+        else {
+            panic!("{:?}", script.statements);
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(otherwise.len(), 1);
+        assert_eq!(otherwise[0].location.line, 6);
+
+        assert_eq!(
+            error("STRING a\nEND_IF", &[]),
+            main_error(2, ParseErrorKind::Unexpected("END_IF".into()))
+        );
+        assert_eq!(
+            error("WHILE 1\nEND_IF", &[]),
+            main_error(2, ParseErrorKind::Unexpected("END_IF".into()))
+        );
+        assert_eq!(
+            error("REM\nWHILE 1\nSTRING a", &[]),
+            main_error(2, ParseErrorKind::Unterminated("WHILE"))
+        );
+        assert_eq!(
+            error("IF 1\nFUNCTION F()\nEND_FUNCTION\nEND_IF", &[]),
+            main_error(2, ParseErrorKind::NestedFunction)
+        );
+        assert_eq!(
+            error(
+                "FUNCTION F()\nEND_FUNCTION\nFUNCTION F()\nEND_FUNCTION",
+                &[]
+            ),
+            main_error(3, ParseErrorKind::DuplicateFunction("F".into()))
+        );
+        assert_eq!(
+            error("F()\nG()\nFUNCTION F()\nEND_FUNCTION", &[]),
+            main_error(2, ParseErrorKind::UndefinedFunction("G".into()))
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let files = [
+            (
+                "lib.ducky",
+                "FUNCTION GREET()\nSTRING hi\nEND_FUNCTION\nSTRING lib",
+            ),
+            ("say.ducky", "STRING say"),
+            ("a.ducky", "INCLUDE b.ducky"),
+            ("b.ducky", "STRING b\nINCLUDE a.ducky"),
+        ];
+        let script = parse_files("INCLUDE lib.ducky\nGREET()", &files).unwrap();
+        assert!(script.functions.contains_key("GREET"));
+        let locations: Vec<_> = script
+            .statements
+            .iter()
+            .map(|statement| statement.location.to_string())
+            .collect();
+        assert_eq!(locations, ["lib.ducky:4", "main.ducky:2"]);
+        // Functions can be defined by files included within blocks
+        parse_files("IF 1\nINCLUDE lib.ducky\nEND_IF", &files).unwrap();
+        // The same file twice in a row is no cycle, though its functions are defined twice
+        let script = parse_files("INCLUDE say.ducky\nINCLUDE say.ducky", &files).unwrap();
+        assert_eq!(script.statements.len(), 2);
+        assert_eq!(
+            error("INCLUDE lib.ducky\nINCLUDE lib.ducky", &files),
+            (
+                "lib.ducky".into(),
+                1,
+                ParseErrorKind::DuplicateFunction("GREET".into())
+            )
+        );
+
+        assert_eq!(
+            error("INCLUDE a.ducky", &files),
+            (
+                "b.ducky".into(),
+                2,
+                ParseErrorKind::IncludeCycle("a.ducky".into())
+            )
+        );
+        assert_eq!(
+            error("INCLUDE main.ducky", &files),
+            main_error(1, ParseErrorKind::IncludeCycle("main.ducky".into()))
+        );
+        assert_eq!(
+            error("INCLUDE", &files),
+            main_error(1, ParseErrorKind::MissingArgument("file"))
+        );
+        assert!(matches!(
+            error("INCLUDE missing.ducky", &files),
+            (_, 1, ParseErrorKind::Include { path, .. }) if path == "missing.ducky"
+        ));
+    }
+
+    #[test]
+    fn include_depth() {
+        // `N.ducky` includes the next file up to `last`
+        let chain = |last: usize| {
+            let texts: Vec<String> = (1..=last)
+                .map(|n| match n {
+                    n if n < last => format!("INCLUDE {}.ducky", n + 1),
+                    _ => "STRING end".into(),
+                })
+                .collect();
+            let names: Vec<String> = (1..=last).map(|n| format!("{n}.ducky")).collect();
+            (names, texts)
+        };
+        let parse_chain = |last| {
+            let (names, texts) = chain(last);
+            let files: Vec<(&str, &str)> = names
+                .iter()
+                .map(String::as_str)
+                .zip(texts.iter().map(String::as_str))
+                .collect();
+            parse_files("INCLUDE 1.ducky", &files)
+        };
+
+        parse_chain(MAX_INCLUDE_DEPTH).unwrap();
+        let error = parse_chain(MAX_INCLUDE_DEPTH + 1).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::IncludeTooDeep);
+        assert_eq!(
+            error.location.to_string(),
+            format!("{MAX_INCLUDE_DEPTH}.ducky:1")
+        );
+    }
+
+    #[test]
+    fn block_depth() {
+        let nested =
+            |depth: usize| "IF 1\n".repeat(depth) + "STRING deep\n" + &"END_IF\n".repeat(depth);
+        parse_files(&nested(MAX_BLOCK_DEPTH), &[]).unwrap();
+        assert_eq!(
+            error(&nested(MAX_BLOCK_DEPTH + 1), &[]),
+            main_error(MAX_BLOCK_DEPTH + 1, ParseErrorKind::BlocksTooDeep)
+        );
+        assert_eq!(
+            error(&"WHILE 1\n".repeat(100_000), &[]),
+            main_error(MAX_BLOCK_DEPTH + 1, ParseErrorKind::BlocksTooDeep)
+        );
+
+        // Blocks of included files count too
+        let included = nested(2);
+        let files = [("inner.ducky", included.as_str())];
+        let outer = "IF 1\n".repeat(MAX_BLOCK_DEPTH - 1)
+            + "INCLUDE inner.ducky\n"
+            + &"END_IF\n".repeat(MAX_BLOCK_DEPTH - 1);
+        assert_eq!(
+            error(&outer, &files),
+            ("inner.ducky".into(), 2, ParseErrorKind::BlocksTooDeep)
+        );
+    }
+
+    #[test]
+    fn expressions() {
+        let one = || Box::new(Expr::Number(1));
+        assert_eq!(
+            expression("1 + 1 * 1"),
+            Ok(Expr::Binary(
+                BinaryOp::Add,
+                one(),
+                Box::new(Expr::Binary(BinaryOp::Multiply, one(), one()))
+            ))
+        );
+        assert_eq!(
+            expression("-!1"),
+            Ok(Expr::Unary(
+                UnaryOp::Negate,
+                Box::new(Expr::Unary(UnaryOp::Not, one()))
+            ))
+        );
+        assert_eq!(
+            expression("$a >= caps_lock"),
+            Ok(Expr::Binary(
+                BinaryOp::GreaterOrEqual,
+                Box::new(Expr::Variable("a".into())),
+                Box::new(Expr::Lock(Lock::Caps))
+            ))
+        );
+        assert_eq!(
+            expression(" "),
+            Err(ParseErrorKind::MissingArgument("expression"))
+        );
+        for text in ["1 +", "(1", "1)", "1 2", "$", "2147483648", "1 = 1"] {
+            assert_eq!(
+                expression(text),
+                Err(ParseErrorKind::InvalidExpression(text.into())),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn expression_depth() {
+        let parenthesized = |depth: usize| "(".repeat(depth) + "1" + &")".repeat(depth);
+        assert_eq!(expression(&parenthesized(15)), Ok(Expr::Number(1)));
+        assert_eq!(
+            expression(&parenthesized(16)),
+            Err(ParseErrorKind::ExpressionTooDeep)
+        );
+        assert_eq!(
+            expression(&("-".repeat(400_000) + "1")),
+            Err(ParseErrorKind::ExpressionTooDeep)
+        );
+        assert_eq!(
+            error(&format!("STRING a\nDELAY {}1", "!".repeat(400_000)), &[]),
+            main_error(2, ParseErrorKind::ExpressionTooDeep)
+        );
+        // Long flat expressions do not nest
+        let sum = vec!["1"; 10_000].join(" + ");
+        assert!(expression(&sum).is_ok());
+    }
+}
@@ -0,0 +1,72 @@
+// A `Host` for tests, recording what a script does
+
+use super::Host;
+use crate::usb::keycode::KeyChord;
+use std::convert::Infallible;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // Text typed in a row, however it was split
+    Type(String),
+    Tap(KeyChord),
+    Press(KeyChord),
+    Release(KeyChord),
+    Sleep(u64),
+}
+
+// Keyboard LEDs and the button stay as set
+#[derive(Debug, Default)]
+pub struct Recorder {
+    pub events: Vec<Event>,
+    pub leds: u8,
+    pub pressed: bool,
+}
+
+impl Host for Recorder {
+    type Error = Infallible;
+
+    fn type_text(&mut self, text: &str) -> Result<(), Infallible> {
+        match self.events.last_mut() {
+            Some(Event::Type(typed)) => typed.push_str(text),
+            _ => self.events.push(Event::Type(text.into())),
+        }
+        Ok(())
+    }
+
+    fn tap(&mut self, chord: KeyChord) -> Result<(), Infallible> {
+        self.events.push(Event::Tap(chord));
+        Ok(())
+    }
+
+    fn press(&mut self, chord: KeyChord) -> Result<(), Infallible> {
+        self.events.push(Event::Press(chord));
+        Ok(())
+    }
+
+    fn release(&mut self, chord: KeyChord) -> Result<(), Infallible> {
+        self.events.push(Event::Release(chord));
+        Ok(())
+    }
+
+    fn sleep(&mut self, duration: Duration) -> Result<(), Infallible> {
+        self.events.push(Event::Sleep(duration.as_millis() as u64));
+        Ok(())
+    }
+
+    fn keyboard_leds(&mut self) -> u8 {
+        self.leds
+    }
+
+    fn wait_for_leds(
+        &mut self,
+        done: &mut dyn FnMut(u8) -> bool,
+        _timeout: Option<Duration>,
+    ) -> Result<bool, Infallible> {
+        Ok(done(self.leds))
+    }
+
+    fn wait_for_button(&mut self, _timeout: Option<Duration>) -> Result<bool, Infallible> {
+        Ok(self.pressed)
+    }
+}
@@ -0,0 +1,258 @@
+// Preferences and counters persisted in the `nvs` partition.
+// They live apart from the FAT partition, so they survive `format_if_mount_failed`.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys;
+
+use zeroize::Zeroizing;
+
+use crate::crypto;
+use crate::hotp::{self, Hotp};
+use crate::usb;
+
+const NAMESPACE: &str = "settings";
+
+// Bump this and add a step to `Store::migrate` whenever the stored layout changes
+const SCHEMA_VERSION: u8 = 1;
+
+pub const SLOT_COUNT: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Keyboard = 0,
+    Msc = 1,
+}
+
+impl Mode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Keyboard),
+            1 => Some(Self::Msc),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub slot: u8,
+    pub mode: Mode,
+    pub layout: String,
+    pub brightness: u8,
+    pub timing: usb::Timing,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            slot: 0,
+            mode: Mode::Keyboard,
+            layout: "us".into(),
+            brightness: 255,
+            timing: usb::Timing::default(),
+        }
+    }
+}
+
+pub struct Store {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Store {
+    pub fn open(partition: EspDefaultNvsPartition) -> Result<Self, sys::EspError> {
+        let mut store = Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&mut self) -> Result<(), sys::EspError> {
+        let mut version = self.nvs.get_u8("version")?.unwrap_or(0);
+
+        if version > SCHEMA_VERSION {
+            // Written by a newer firmware; we cannot know what the keys mean anymore
+            log::warn!("settings schema v{version} is newer than v{SCHEMA_VERSION}, resetting");
+            self.reset()?;
+            version = 0;
+        }
+
+        while version < SCHEMA_VERSION {
+            log::info!("migrating settings schema v{version} -> v{}", version + 1);
+            match version {
+                // Fresh namespace: defaults are applied lazily by `load`
+                0 => {}
+                _ => unreachable!(),
+            }
+            version += 1;
+            self.nvs.set_u8("version", version)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), sys::EspError> {
+        for key in [
+            "version",
+            "slot",
+            "mode",
+            "layout",
+            "brightness",
+            "hold_ms",
+            "release_ms",
+            "next_mode",
+            "counter",
+            "hotp_secret",
+            "hotp_digits",
+            "hotp_enter",
+            "hotp_counter",
+            "pin_failures",
+        ] {
+            self.nvs.remove(key)?;
+        }
+        for slot in 0..SLOT_COUNT {
+            self.nvs.remove(&usage_key(slot))?;
+        }
+        // `device_key` is kept, or payloads sealed with it could never be decrypted again
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<Settings, sys::EspError> {
+        let default = Settings::default();
+
+        Ok(Settings {
+            slot: self
+                .nvs
+                .get_u8("slot")?
+                .filter(|slot| *slot < SLOT_COUNT)
+                .unwrap_or(default.slot),
+            mode: self
+                .nvs
+                .get_u8("mode")?
+                .and_then(Mode::from_u8)
+                .unwrap_or(default.mode),
+            layout: self.get_string("layout")?.unwrap_or(default.layout),
+            brightness: self.nvs.get_u8("brightness")?.unwrap_or(default.brightness),
+            timing: usb::Timing {
+                hold_ms: self
+                    .nvs
+                    .get_u32("hold_ms")?
+                    .unwrap_or(default.timing.hold_ms),
+                release_ms: self
+                    .nvs
+                    .get_u32("release_ms")?
+                    .unwrap_or(default.timing.release_ms),
+            },
+        })
+    }
+
+    // Sized by the stored length, so that no layout name is too long to load
+    fn get_string(&self, key: &str) -> Result<Option<String>, sys::EspError> {
+        let Some(length) = self.nvs.str_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; length];
+        Ok(self.nvs.get_str(key, &mut buf)?.map(String::from))
+    }
+
+    pub fn save(&mut self, settings: &Settings) -> Result<(), sys::EspError> {
+        self.nvs.set_u8("slot", settings.slot)?;
+        self.nvs.set_u8("mode", settings.mode as u8)?;
+        self.nvs.set_str("layout", &settings.layout)?;
+        self.nvs.set_u8("brightness", settings.brightness)?;
+        self.nvs.set_u32("hold_ms", settings.timing.hold_ms)?;
+        self.nvs.set_u32("release_ms", settings.timing.release_ms)?;
+        Ok(())
+    }
+
+    // Boots once into `mode` regardless of the button, e.g. after `reboot msc` on the console
+    pub fn set_next_mode(&mut self, mode: Mode) -> Result<(), sys::EspError> {
+        self.nvs.set_u8("next_mode", mode as u8)
+    }
+
+    pub fn take_next_mode(&mut self) -> Result<Option<Mode>, sys::EspError> {
+        let mode = self.nvs.get_u8("next_mode")?.and_then(Mode::from_u8);
+        if mode.is_some() {
+            self.nvs.remove("next_mode")?;
+        }
+        Ok(mode)
+    }
+
+    pub fn usage_count(&self, slot: u8) -> Result<u32, sys::EspError> {
+        Ok(self.nvs.get_u32(&usage_key(slot))?.unwrap_or(0))
+    }
+
+    // Backs the {{counter}} template placeholder; returns the new value
+    pub fn next_counter(&mut self) -> Result<u32, sys::EspError> {
+        let counter = self.nvs.get_u32("counter")?.unwrap_or(0).wrapping_add(1);
+        self.nvs.set_u32("counter", counter)?;
+        Ok(counter)
+    }
+
+    pub fn hotp(&self) -> Result<Option<Hotp>, sys::EspError> {
+        let mut buf = [0u8; hotp::MAX_SECRET_LENGTH];
+        let Some(secret) = self.nvs.get_blob("hotp_secret", &mut buf)? else {
+            return Ok(None);
+        };
+        Ok(Some(Hotp {
+            secret: secret.to_vec(),
+            digits: self
+                .nvs
+                .get_u8("hotp_digits")?
+                .unwrap_or(*hotp::DIGITS.start()),
+            enter: self.nvs.get_u8("hotp_enter")?.unwrap_or(0) != 0,
+        }))
+    }
+
+    // Provisioning a new secret restarts the moving counter
+    pub fn set_hotp(&mut self, hotp: &Hotp) -> Result<(), sys::EspError> {
+        self.nvs.set_blob("hotp_secret", &hotp.secret)?;
+        self.nvs.set_u8("hotp_digits", hotp.digits)?;
+        self.nvs.set_u8("hotp_enter", hotp.enter as u8)?;
+        self.nvs.set_u64("hotp_counter", 0)?;
+        Ok(())
+    }
+
+    // Returns the counter for the code to type now. The next value is persisted first,
+    // so a reset while typing can never reuse a code.
+    pub fn next_hotp_counter(&mut self) -> Result<u64, sys::EspError> {
+        let counter = self.nvs.get_u64("hotp_counter")?.unwrap_or(0);
+        self.nvs.set_u64("hotp_counter", counter + 1)?;
+        Ok(counter)
+    }
+
+    // Consecutive failed PIN attempts, kept across reboots so that power cycling
+    // does not bypass `crypto::lockout_delay`
+    pub fn pin_failures(&self) -> Result<u32, sys::EspError> {
+        Ok(self.nvs.get_u32("pin_failures")?.unwrap_or(0))
+    }
+
+    pub fn set_pin_failures(&mut self, failures: u32) -> Result<(), sys::EspError> {
+        self.nvs.set_u32("pin_failures", failures)
+    }
+
+    // Mixed into the key of encrypted payloads, see `crypto`; created on first use
+    pub fn device_key(&mut self) -> Result<Zeroizing<crypto::DeviceKey>, sys::EspError> {
+        let mut key = Zeroizing::new([0u8; crypto::DEVICE_KEY_LENGTH]);
+        match self.nvs.get_blob("device_key", key.as_mut())? {
+            Some(stored) if stored.len() == crypto::DEVICE_KEY_LENGTH => {}
+            _ => {
+                unsafe { sys::esp_fill_random(key.as_mut_ptr().cast(), key.len()) };
+                self.nvs.set_blob("device_key", key.as_ref())?;
+            }
+        }
+        Ok(key)
+    }
+
+    // Returns the new count
+    pub fn increment_usage(&mut self, slot: u8) -> Result<u32, sys::EspError> {
+        let count = self.usage_count(slot)?.wrapping_add(1);
+        self.nvs.set_u32(&usage_key(slot), count)?;
+        Ok(count)
+    }
+}
+
+// NVS keys are limited to 15 characters
+fn usage_key(slot: u8) -> String {
+    format!("used{slot}")
+}
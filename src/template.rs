@@ -0,0 +1,184 @@
+// Placeholders expanded in payload text right before it is typed
+//
+//   {{serial}}      chip unique ID, also used as the USB serial number
+//   {{counter}}     persistent number incremented once per typed payload
+//   {{slot}}        currently selected slot
+//   {{hex:N}}       N random lowercase hex digits
+//   {{alnum:N}}     N random alphanumeric characters
+//   {{NAME}}        user-defined variable, `var.NAME = value` in the config file
+
+use std::collections::HashMap;
+
+const ALNUM: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const HEX: &[u8] = b"0123456789abcdef";
+
+// Upper bound for {{hex:N}} and {{alnum:N}}, so a typo cannot type for minutes
+const MAX_RANDOM_LENGTH: usize = 256;
+
+pub struct Context<'a> {
+    pub serial: &'a str,
+    pub slot: u8,
+    pub variables: &'a HashMap<String, String>,
+    // Called at most once per expansion, so every {{counter}} in a payload has the same value
+    pub counter: &'a mut dyn FnMut() -> u32,
+    pub random: &'a mut dyn FnMut() -> u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Unterminated { offset: usize },
+    UnknownPlaceholder(String),
+    InvalidLength(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unterminated { offset } => write!(f, "unterminated placeholder at byte {offset}"),
+            Self::UnknownPlaceholder(name) => write!(f, "unknown placeholder {{{{{name}}}}}"),
+            Self::InvalidLength(name) => write!(
+                f,
+                "invalid length in {{{{{name}}}}} (expected 1..={MAX_RANDOM_LENGTH})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn contains_placeholders(text: &str) -> bool {
+    text.contains("{{")
+}
+
+pub fn expand(text: &str, context: &mut Context) -> Result<String, Error> {
+    let mut output = String::with_capacity(text.len());
+    let mut counter: Option<u32> = None;
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let offset = text.len() - rest.len() + start;
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or(Error::Unterminated { offset })?;
+        let name = after[..end].trim();
+
+        match name.split_once(':') {
+            Some(("hex", length)) => push_random(&mut output, HEX, name, length, context)?,
+            Some(("alnum", length)) => push_random(&mut output, ALNUM, name, length, context)?,
+            Some(_) => return Err(Error::UnknownPlaceholder(name.into())),
+            None => match name {
+                "serial" => output.push_str(context.serial),
+                "slot" => output.push_str(&context.slot.to_string()),
+                "counter" => {
+                    let value = *counter.get_or_insert_with(|| (context.counter)());
+                    output.push_str(&value.to_string());
+                }
+                _ => match context.variables.get(&name.to_lowercase()) {
+                    Some(value) => output.push_str(value),
+                    None => return Err(Error::UnknownPlaceholder(name.into())),
+                },
+            },
+        }
+
+        rest = &after[end + 2..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn push_random(
+    output: &mut String,
+    alphabet: &[u8],
+    name: &str,
+    length: &str,
+    context: &mut Context,
+) -> Result<(), Error> {
+    let length: usize = match length.trim().parse() {
+        Ok(length) if (1..=MAX_RANDOM_LENGTH).contains(&length) => length,
+        _ => return Err(Error::InvalidLength(name.into())),
+    };
+    for _ in 0..length {
+        let index = (context.random)() as usize % alphabet.len();
+        output.push(alphabet[index] as char);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expands `text` with fixed values, returning how often the counter was read too
+    fn expand_counting(text: &str) -> (Result<String, Error>, u32) {
+        let variables = HashMap::from([("host".to_string(), "build01".to_string())]);
+        let mut reads = 0;
+        let mut counter = || {
+            reads += 1;
+            41 + reads
+        };
+        let mut random = || 1;
+        let result = expand(
+            text,
+            &mut Context {
+                serial: "A1B2",
+                slot: 3,
+                variables: &variables,
+                counter: &mut counter,
+                random: &mut random,
+            },
+        );
+        (result, reads)
+    }
+
+    fn expanded(text: &str) -> Result<String, Error> {
+        expand_counting(text).0
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            expanded("{{serial}}/{{ slot }}/{{HOST}}/{{hex:3}}/{{alnum:2}}"),
+            Ok("A1B2/3/build01/111/11".into())
+        );
+        assert_eq!(expanded("no placeholders"), Ok("no placeholders".into()));
+        assert_eq!(expanded("} {single} }}"), Ok("} {single} }}".into()));
+    }
+
+    #[test]
+    fn counter_read_once() {
+        assert_eq!(
+            expand_counting("{{counter}} {{counter}}"),
+            (Ok("42 42".into()), 1)
+        );
+        assert_eq!(expand_counting("{{serial}}"), (Ok("A1B2".into()), 0));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            expanded("{{user}}"),
+            Err(Error::UnknownPlaceholder("user".into()))
+        );
+        assert_eq!(
+            expanded("{{date:iso}}"),
+            Err(Error::UnknownPlaceholder("date:iso".into()))
+        );
+        assert_eq!(
+            expanded("{{hex:0}}"),
+            Err(Error::InvalidLength("hex:0".into()))
+        );
+        assert_eq!(
+            expanded("{{alnum:x}}"),
+            Err(Error::InvalidLength("alnum:x".into()))
+        );
+        assert_eq!(
+            expanded("{{hex:257}}"),
+            Err(Error::InvalidLength("hex:257".into()))
+        );
+        assert_eq!(
+            expanded("ok {{serial}} {{slot"),
+            Err(Error::Unterminated { offset: 14 })
+        );
+    }
+}
@@ -2,30 +2,77 @@
 // https://github.com/esp-rs/esp-idf-sys/issues/301
 // https://github.com/esp-rs/esp-idf-hal/issues/231
 
+pub mod bus;
+pub mod controller;
 pub mod descriptor;
 pub mod keycode;
 pub mod storage;
+pub mod vendor;
 
 use esp_idf_svc::sys::{self, tinyusb};
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// How long `HidInstance::push` waits for the endpoint before giving up
+pub const REPORT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// Bit N is set while a report of instance N is queued and not yet completed
+static IN_FLIGHT: Mutex<u32> = Mutex::new(0);
+static REPORT_COMPLETED: Condvar = Condvar::new();
+
+// Keyboard LED output report last set by the host, or NO_KEYBOARD_LEDS
+static KEYBOARD_LEDS: AtomicU16 = AtomicU16::new(NO_KEYBOARD_LEDS);
+const NO_KEYBOARD_LEDS: u16 = u16::MAX;
+
+// See `Timing`
+static HOLD_MS: AtomicU32 = AtomicU32::new(Timing::DEFAULT.hold_ms);
+static RELEASE_MS: AtomicU32 = AtomicU32::new(Timing::DEFAULT.release_ms);
 
 static HID_INSTANCES: once_cell::sync::Lazy<std::sync::Mutex<Vec<HidInstance>>> =
     once_cell::sync::Lazy::new(|| std::sync::Mutex::new(vec![]));
 
+#[derive(Debug, thiserror::Error)]
+pub enum InstallError {
+    #[error("USB already installed")]
+    AlreadyInstalled,
+    #[error(transparent)]
+    Descriptor(#[from] descriptor::DescriptorError),
+    #[error(transparent)]
+    Driver(#[from] sys::EspError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("cannot serialize report: {0:?}")]
+    Serialize(ssmarshal::Error),
+    #[error("HID instance {0} stayed busy for {1:?}")]
+    Timeout(u8, Duration),
+    // See `controller::abort`
+    #[error("aborted")]
+    Aborted,
+}
+
+// Callbacks run on the TinyUSB task and must never panic, so a poisoned lock is recovered
+fn lock_hid_instances() -> std::sync::MutexGuard<'static, Vec<HidInstance<'static>>> {
+    HID_INSTANCES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 pub fn install(
     string_descriptor: descriptor::StringDescriptor,
     hid_instances: &[HidInstance<'static>],
     msc_enabled: bool,
-) -> anyhow::Result<()> {
-    if HID_INSTANCES.lock().unwrap().len() != 0 {
-        return Err(anyhow::anyhow!("USB already installed"));
-    } else {
-        HID_INSTANCES
-            .lock()
-            .unwrap()
-            .extend_from_slice(&hid_instances);
+) -> Result<(), InstallError> {
+    let config_descriptor = descriptor::config_descriptor(msc_enabled, &hid_instances)?;
+
+    {
+        let mut installed = lock_hid_instances();
+        if !installed.is_empty() {
+            return Err(InstallError::AlreadyInstalled);
+        }
+        installed.extend_from_slice(&hid_instances);
     }
 
-    let config_descriptor = descriptor::config_descriptor(msc_enabled, &hid_instances);
     let string_descriptor = Box::new(descriptor::string_descriptor(string_descriptor));
     let device_descriptor = Box::new(descriptor::device_descriptor());
 
@@ -52,11 +99,60 @@ pub fn is_ready() -> bool {
     unsafe { tinyusb::tud_mounted() }
 }
 
+// Num/Caps/Scroll Lock... bits last set by the host; None until the host has set them once,
+// which is a sign that it is actively listening to the keyboard
+pub fn keyboard_leds() -> Option<u8> {
+    match KEYBOARD_LEDS.load(Ordering::Relaxed) {
+        NO_KEYBOARD_LEDS => None,
+        leds => Some(leds as u8),
+    }
+}
+
+// How long `HidInstance::type_keys` holds each key, and waits after releasing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub hold_ms: u32,
+    pub release_ms: u32,
+}
+
+impl Timing {
+    pub const DEFAULT: Self = Self {
+        hold_ms: 50,
+        release_ms: 30,
+    };
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+pub fn timing() -> Timing {
+    Timing {
+        hold_ms: HOLD_MS.load(Ordering::Relaxed),
+        release_ms: RELEASE_MS.load(Ordering::Relaxed),
+    }
+}
+
+pub fn set_timing(timing: Timing) {
+    HOLD_MS.store(timing.hold_ms, Ordering::Relaxed);
+    RELEASE_MS.store(timing.release_ms, Ordering::Relaxed);
+}
+
+fn lock_in_flight() -> std::sync::MutexGuard<'static, u32> {
+    IN_FLIGHT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 #[derive(Debug, Clone)]
 pub struct HidInstance<'a> {
+    // Also the interface number: instances are listed in order in the configuration descriptor
     pub instance_id: u8,
     pub report_id: u8,
     pub descriptor: &'a [u8],
+    pub packet_size: u16,
+    // For output reports; otherwise they arrive on the control endpoint
+    pub has_out_endpoint: bool,
 }
 
 impl<'a> HidInstance<'a> {
@@ -65,47 +161,138 @@ impl<'a> HidInstance<'a> {
     }
 
     // type_keys can only be used for KeyboardReport
-    pub fn type_keys<T: keycode::AsKeyboardReport>(&self, keys: &mut dyn Iterator<Item = T>) {
-        for report in keys.map(|char| char.as_keyboard_report()).flatten() {
-            println!("report: {report:?}");
+    // Progress is published to `progress::PROGRESS`, counting every key including unmappable ones
+    // Unmappable keys are skipped; only their number is logged, as the log is kept on the drive
+    // and payloads may be secret
+    // Holding the abort button fails with `SendError::Aborted`, see `controller::set_abort_button`
+    pub fn type_keys<T: keycode::AsKeyboardReport>(
+        &self,
+        keys: &mut dyn Iterator<Item = T>,
+    ) -> Result<(), SendError> {
+        // Exact for slices; for `chars()` the upper bound is the byte length
+        let (lower, upper) = keys.size_hint();
+        crate::progress::PROGRESS.start(upper.unwrap_or(lower));
+        let timing = timing();
+        let mut watch = controller::AbortWatch::start();
+        let mut skipped = 0;
+
+        for report in keys.map(|char| {
+            crate::progress::PROGRESS.advance(1);
+            char.as_keyboard_report()
+        }) {
+            let report = match report {
+                Ok(report) => report,
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            // Wait for enumeration and pause while the host is suspended; a long press aborts
+            watch.wait_until_mounted(self)?;
 
             if report.modifier != 0 {
                 let mut modifier_only = report.clone();
                 modifier_only.keycodes = [0; 6];
-                self.push(&modifier_only);
+                self.push(&modifier_only)?;
                 esp_idf_svc::hal::delay::FreeRtos::delay_ms(20);
             }
 
             // Press keys
-            self.push(&report);
+            self.push(&report)?;
 
             // Hold keys for a short period of time
-            esp_idf_svc::hal::delay::FreeRtos::delay_ms(50);
+            esp_idf_svc::hal::delay::FreeRtos::delay_ms(timing.hold_ms);
 
             // Release keys
             if report.modifier != 0 {
                 let mut modifier_only = report.clone();
                 modifier_only.keycodes = [0; 6];
-                self.push(&modifier_only);
+                self.push(&modifier_only)?;
                 esp_idf_svc::hal::delay::FreeRtos::delay_ms(20);
             }
-            self.push(&usbd_hid::descriptor::KeyboardReport::default());
-            esp_idf_svc::hal::delay::FreeRtos::delay_ms(30);
+            self.push(&usbd_hid::descriptor::KeyboardReport::default())?;
+            esp_idf_svc::hal::delay::FreeRtos::delay_ms(timing.release_ms);
+        }
+
+        self.flush(REPORT_TIMEOUT)?;
+        crate::progress::PROGRESS.finish();
+        if skipped > 0 {
+            log::warn!("skipped {skipped} unmappable characters");
         }
+        Ok(())
     }
 
-    pub fn push<T: usbd_hid::descriptor::generator_prelude::Serialize>(&self, report: &T) {
+    // Queues a report once the previous one has been delivered, so no report is dropped
+    // whatever the host polling interval is. Fails with `SendError::Timeout` if the endpoint
+    // stays busy (or the bus unusable) for REPORT_TIMEOUT.
+    pub fn push<T: usbd_hid::descriptor::generator_prelude::Serialize>(
+        &self,
+        report: &T,
+    ) -> Result<(), SendError> {
         let mut buff: [u8; 64] = [0; 64];
-        let size = ssmarshal::serialize(&mut buff, report).unwrap();
-        unsafe {
-            tinyusb::tud_hid_n_report(
-                self.instance_id,
-                self.report_id,
-                buff.as_ptr() as *const std::ffi::c_void,
-                size as u16,
-            );
+        let size = ssmarshal::serialize(&mut buff, report).map_err(SendError::Serialize)?;
+        self.push_bytes(&buff[..size])
+    }
+
+    // Same as `push`, for reports that are already serialized
+    pub fn push_bytes(&self, report: &[u8]) -> Result<(), SendError> {
+        let deadline = Instant::now() + REPORT_TIMEOUT;
+        self.flush(REPORT_TIMEOUT)?;
+
+        loop {
+            let ready = unsafe { tinyusb::tud_hid_n_ready(self.instance_id) };
+            if ready {
+                // Mark as in flight before queueing: completion may fire before we return
+                *lock_in_flight() |= self.in_flight_bit();
+                let queued = unsafe {
+                    tinyusb::tud_hid_n_report(
+                        self.instance_id,
+                        self.report_id,
+                        report.as_ptr() as *const std::ffi::c_void,
+                        report.len() as u16,
+                    )
+                };
+                if queued {
+                    return Ok(());
+                }
+                *lock_in_flight() &= !self.in_flight_bit();
+            }
+
+            // Busy: retry until the deadline
+            if Instant::now() >= deadline {
+                return Err(SendError::Timeout(self.instance_id, REPORT_TIMEOUT));
+            }
+            esp_idf_svc::hal::delay::FreeRtos::delay_ms(1);
+        }
+    }
+
+    // Waits until the last queued report has been delivered to the host
+    pub fn flush(&self, timeout: Duration) -> Result<(), SendError> {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = lock_in_flight();
+        while *in_flight & self.in_flight_bit() != 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                // Never completed (e.g. bus reset); do not block the next report forever
+                *in_flight &= !self.in_flight_bit();
+                return Err(SendError::Timeout(self.instance_id, timeout));
+            }
+            in_flight = REPORT_COMPLETED
+                .wait_timeout(in_flight, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
         }
+        Ok(())
     }
+
+    fn in_flight_bit(&self) -> u32 {
+        in_flight_bit(self.instance_id)
+    }
+}
+
+// No bit for instances beyond the mask; they are never installed anyway
+fn in_flight_bit(instance: u8) -> u32 {
+    1u32.checked_shl(instance.into()).unwrap_or(0)
 }
 
 /**  CALLBACKS  **/
@@ -114,9 +301,7 @@ impl<'a> HidInstance<'a> {
 // https://github.com/espressif/esp-idf/blob/4523f2d67465373f0e732a3264273a8e84a1a6d1/examples/peripherals/usb/device/tusb_hid/main/tusb_hid_example_main.c#L62
 #[no_mangle]
 extern "C" fn tud_hid_descriptor_report_cb(instance: u8) -> *const u8 {
-    match HID_INSTANCES
-        .lock()
-        .unwrap()
+    match lock_hid_instances()
         .iter()
         .find(|i| i.instance_id == instance)
     {
@@ -125,6 +310,13 @@ extern "C" fn tud_hid_descriptor_report_cb(instance: u8) -> *const u8 {
     }
 }
 
+// Invoked when a report has been delivered to the host
+#[no_mangle]
+extern "C" fn tud_hid_report_complete_cb(instance: u8, _report: *const u8, _len: u16) {
+    *lock_in_flight() &= !in_flight_bit(instance);
+    REPORT_COMPLETED.notify_all();
+}
+
 #[no_mangle]
 extern "C" fn tud_hid_get_report_cb(
     _instance: u8,
@@ -138,10 +330,22 @@ extern "C" fn tud_hid_get_report_cb(
 
 #[no_mangle]
 extern "C" fn tud_hid_set_report_cb(
-    _instance: u8,
+    instance: u8,
     _report_id: u8,
-    _report_type: esp_idf_svc::sys::tinyusb::hid_report_type_t,
-    _buffer: *const u8,
-    _buffsize: u16,
+    report_type: esp_idf_svc::sys::tinyusb::hid_report_type_t,
+    buffer: *const u8,
+    buffsize: u16,
 ) {
+    if buffer.is_null() {
+        return;
+    }
+    let report = unsafe { std::slice::from_raw_parts(buffer, buffsize as usize) };
+    if vendor::on_report(instance, report) {
+        return;
+    }
+
+    // Keyboard output report: a single byte of LED bits
+    if let (tinyusb::hid_report_type_t_HID_REPORT_TYPE_OUTPUT, &[leds]) = (report_type, report) {
+        KEYBOARD_LEDS.store(leds as u16, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,86 @@
+// USB bus state fed by TinyUSB's device callbacks
+//
+// esp_tinyusb already defines `tud_mount_cb` and `tud_umount_cb` for its MSC storage, so those
+// two are wrapped at link time (`--wrap` in .cargo/config.toml) and forwarded to the original.
+
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+pub mod state;
+
+pub use state::{transition, Event, State};
+
+static BUS: Mutex<State> = Mutex::new(State::NotMounted);
+static CHANGED: Condvar = Condvar::new();
+
+// Never panics: this runs on the TinyUSB task
+fn lock() -> MutexGuard<'static, State> {
+    BUS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn dispatch(event: Event) {
+    let mut state = lock();
+    *state = transition(*state, event);
+    CHANGED.notify_all();
+}
+
+pub fn state() -> State {
+    *lock()
+}
+
+// Blocks until the host has enumerated the device and the bus is not suspended.
+// Returns false if `timeout` elapsed first.
+pub fn wait_until_mounted(timeout: Option<Duration>) -> bool {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut state = lock();
+    while *state != State::Mounted {
+        state = match deadline {
+            None => CHANGED.wait(state).unwrap_or_else(PoisonError::into_inner),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                CHANGED
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+        };
+    }
+    true
+}
+
+extern "C" {
+    fn __real_tud_mount_cb();
+    fn __real_tud_umount_cb();
+}
+
+/**  CALLBACKS  **/
+
+// Invoked when the device is mounted (configured) by the host
+#[no_mangle]
+extern "C" fn __wrap_tud_mount_cb() {
+    unsafe { __real_tud_mount_cb() };
+    dispatch(Event::Mounted);
+}
+
+// Invoked when the device is unmounted or the bus is reset
+#[no_mangle]
+extern "C" fn __wrap_tud_umount_cb() {
+    unsafe { __real_tud_umount_cb() };
+    dispatch(Event::Unmounted);
+}
+
+// Invoked when the bus is suspended; within 7 ms the device must draw less than 2.5 mA
+#[no_mangle]
+extern "C" fn tud_suspend_cb(remote_wakeup_en: bool) {
+    dispatch(Event::Suspended {
+        remote_wakeup_enabled: remote_wakeup_en,
+    });
+}
+
+#[no_mangle]
+extern "C" fn tud_resume_cb() {
+    dispatch(Event::Resumed);
+}
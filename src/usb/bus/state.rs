@@ -0,0 +1,86 @@
+// How the bus state follows TinyUSB's device events, kept apart from the callbacks so that it
+// can be tested on the host
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    // Not enumerated by a host (yet), or reset
+    NotMounted,
+    Mounted,
+    Suspended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Mounted,
+    Unmounted,
+    Suspended { remote_wakeup_enabled: bool },
+    Resumed,
+}
+
+pub fn transition(state: State, event: Event) -> State {
+    match (state, event) {
+        (_, Event::Mounted) => State::Mounted,
+        (_, Event::Unmounted) => State::NotMounted,
+        // The bus also idles before enumeration; that is still "not mounted"
+        (State::NotMounted, Event::Suspended { .. }) => State::NotMounted,
+        (_, Event::Suspended { .. }) => State::Suspended,
+        (State::Suspended, Event::Resumed) => State::Mounted,
+        (state, Event::Resumed) => state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUSPENDED: Event = Event::Suspended {
+        remote_wakeup_enabled: false,
+    };
+
+    fn after(events: &[Event]) -> State {
+        events
+            .iter()
+            .fold(State::NotMounted, |state, &event| transition(state, event))
+    }
+
+    #[test]
+    fn mount_and_unmount() {
+        assert_eq!(after(&[]), State::NotMounted);
+        assert_eq!(after(&[Event::Mounted]), State::Mounted);
+        assert_eq!(
+            after(&[Event::Mounted, Event::Unmounted]),
+            State::NotMounted
+        );
+        // A bus reset while suspended
+        assert_eq!(
+            after(&[Event::Mounted, SUSPENDED, Event::Unmounted]),
+            State::NotMounted
+        );
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        assert_eq!(after(&[Event::Mounted, SUSPENDED]), State::Suspended);
+        assert_eq!(
+            after(&[
+                Event::Mounted,
+                Event::Suspended {
+                    remote_wakeup_enabled: true
+                }
+            ]),
+            State::Suspended
+        );
+        assert_eq!(
+            after(&[Event::Mounted, SUSPENDED, Event::Resumed]),
+            State::Mounted
+        );
+        assert_eq!(after(&[Event::Mounted, Event::Resumed]), State::Mounted);
+    }
+
+    #[test]
+    fn idle_before_enumeration() {
+        assert_eq!(after(&[SUSPENDED]), State::NotMounted);
+        assert_eq!(after(&[SUSPENDED, Event::Resumed]), State::NotMounted);
+        assert_eq!(after(&[SUSPENDED, Event::Mounted]), State::Mounted);
+    }
+}
@@ -0,0 +1,277 @@
+// Keyboard state for input that is more than one character at a time: keys can be held across
+// other key presses, for a while (auto-repeat), or in overlapping order.
+//
+// Every change is sent as a complete report of what is down, and whatever is still down is
+// released when the controller is dropped or the run is aborted, so the host never sees a
+// stuck key. Holding the abort button aborts a run from within any wait.
+
+use super::keycode::{KeyChord, KeycodeError};
+use super::{bus, HidInstance, SendError, REPORT_TIMEOUT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+// Set by `abort`; checked between reports and while waiting
+static ABORT: AtomicBool = AtomicBool::new(false);
+
+// Whether the button is down, see `set_abort_button`
+static ABORT_BUTTON: OnceLock<fn() -> bool> = OnceLock::new();
+
+// Holding the abort button this long aborts the running controller
+pub const ABORT_PRESS: Duration = Duration::from_millis(1500);
+
+// Delay between a modifier change and the keys it applies to, as in `HidInstance::type_keys`
+const MODIFIER_DELAY: Duration = Duration::from_millis(20);
+
+// Granularity of waits, i.e. how late an abort can be noticed
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long typing waits for a host to enumerate the device; a suspended host is waited for
+// until the run is aborted, as it may resume any time
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Makes the running controller release everything and fail with `SendError::Aborted`;
+// can be called from any task
+pub fn abort() {
+    ABORT.store(true, Ordering::Relaxed);
+}
+
+pub fn is_aborted() -> bool {
+    ABORT.load(Ordering::Relaxed)
+}
+
+// Makes every controller poll `is_down` while it sends and waits, and `abort` once it has been
+// true for `ABORT_PRESS`; set once at startup
+pub fn set_abort_button(is_down: fn() -> bool) {
+    ABORT_BUTTON.set(is_down).ok();
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ControllerError {
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Keycode(#[from] KeycodeError),
+}
+
+// Abort checks for one run, shared by `KeyboardController` and `HidInstance::type_keys`
+pub struct AbortWatch {
+    // Since when the abort button has been down
+    held_since: Option<Instant>,
+}
+
+impl AbortWatch {
+    // Clears a previous abort: each watch is one run
+    pub fn start() -> Self {
+        ABORT.store(false, Ordering::Relaxed);
+        Self { held_since: None }
+    }
+
+    // Fails with `SendError::Aborted` once `abort` was called or the button held long enough
+    pub fn check(&mut self) -> Result<(), SendError> {
+        self.poll_abort_button();
+        if is_aborted() {
+            return Err(SendError::Aborted);
+        }
+        Ok(())
+    }
+
+    // Waits for enumeration and pauses while the host is suspended, checking for an abort every
+    // poll interval. Fails with `SendError::Timeout` if `keyboard` is not mounted within
+    // MOUNT_TIMEOUT.
+    pub fn wait_until_mounted(&mut self, keyboard: &HidInstance) -> Result<(), SendError> {
+        let start = Instant::now();
+        while !bus::wait_until_mounted(Some(POLL_INTERVAL)) {
+            self.check()?;
+            if bus::state() == bus::State::NotMounted && start.elapsed() >= MOUNT_TIMEOUT {
+                return Err(SendError::Timeout(keyboard.instance_id, MOUNT_TIMEOUT));
+            }
+        }
+        self.check()
+    }
+
+    fn poll_abort_button(&mut self) {
+        let Some(is_down) = ABORT_BUTTON.get() else {
+            return;
+        };
+        if !is_down() {
+            self.held_since = None;
+            return;
+        }
+        let held_since = *self.held_since.get_or_insert_with(Instant::now);
+        if held_since.elapsed() >= ABORT_PRESS && !is_aborted() {
+            log::info!("aborted with the button");
+            abort();
+        }
+    }
+}
+
+pub struct KeyboardController<'a> {
+    keyboard: &'a HidInstance<'a>,
+    // What the host was last told is down
+    down: KeyChord,
+    watch: AbortWatch,
+}
+
+impl<'a> KeyboardController<'a> {
+    // Clears a previous abort: each controller is one run
+    pub fn new(keyboard: &'a HidInstance<'a>) -> Self {
+        Self {
+            keyboard,
+            down: KeyChord::default(),
+            watch: AbortWatch::start(),
+        }
+    }
+
+    pub fn down(&self) -> KeyChord {
+        self.down
+    }
+
+    // Presses the modifiers and keys of `chord` in addition to those already down.
+    // New modifiers go out first, so the host applies them to the keys.
+    pub fn press(&mut self, chord: impl Into<KeyChord>) -> Result<(), ControllerError> {
+        let chord = chord.into();
+        let mut next = self.down;
+        next.merge(&chord)?;
+
+        let new_modifiers = chord.modifiers - self.down.modifiers;
+        if !new_modifiers.is_empty() && chord.keys().next().is_some() {
+            let mut modifiers_only = self.down;
+            modifiers_only.modifiers |= new_modifiers;
+            self.send(modifiers_only)?;
+            self.sleep(MODIFIER_DELAY)?;
+        }
+        self.send(next)?;
+        Ok(())
+    }
+
+    // Releases the modifiers and keys of `chord`; others stay down.
+    // Keys go up before modifiers, mirroring `press`.
+    pub fn release(&mut self, chord: impl Into<KeyChord>) -> Result<(), ControllerError> {
+        let chord = chord.into();
+        let mut next = self.down;
+        next.remove(&chord);
+
+        let released_modifiers = self.down.modifiers - next.modifiers;
+        if !released_modifiers.is_empty() && self.down.keys().ne(next.keys()) {
+            let mut keys_released = next;
+            keys_released.modifiers |= released_modifiers;
+            self.send(keys_released)?;
+            self.sleep(MODIFIER_DELAY)?;
+        }
+        self.send(next)?;
+        Ok(())
+    }
+
+    // Presses and releases `chord` with the configured `Timing`. Modifiers that were already
+    // held stay down, so a held Shift applies to every tap.
+    pub fn tap(&mut self, chord: impl Into<KeyChord>) -> Result<(), ControllerError> {
+        let timing = super::timing();
+        self.hold_for(chord, Duration::from_millis(timing.hold_ms.into()))?;
+        self.sleep(Duration::from_millis(timing.release_ms.into()))?;
+        Ok(())
+    }
+
+    // Like `tap`, holding `chord` for `duration` and without the pause after it
+    pub fn hold_for(
+        &mut self,
+        chord: impl Into<KeyChord>,
+        duration: Duration,
+    ) -> Result<(), ControllerError> {
+        let chord = chord.into();
+        let added = chord.difference(&self.down);
+        self.press(chord)?;
+        self.sleep(duration)?;
+        self.release(added)?;
+        Ok(())
+    }
+
+    // Types `char` with `tap`
+    pub fn type_char(&mut self, char: char) -> Result<(), ControllerError> {
+        let chord =
+            super::keycode::character_to_chord(char).ok_or(KeycodeError::Unmappable(char))?;
+        self.tap(chord)
+    }
+
+    // Releases everything; sends a report even if nothing is known to be down
+    pub fn release_all(&mut self) -> Result<(), SendError> {
+        self.down = KeyChord::default();
+        self.keyboard
+            .push(&usbd_hid::descriptor::KeyboardReport::default())?;
+        self.keyboard.flush(REPORT_TIMEOUT)
+    }
+
+    // Waits for `duration` unless aborted; everything is released on abort
+    pub fn sleep(&mut self, duration: Duration) -> Result<(), SendError> {
+        // An unreachable deadline waits until aborted
+        let deadline = Instant::now().checked_add(duration);
+        loop {
+            self.check_abort()?;
+            let now = Instant::now();
+            let wait = match deadline {
+                Some(deadline) if now >= deadline => return Ok(()),
+                Some(deadline) => (deadline - now).min(POLL_INTERVAL),
+                None => POLL_INTERVAL,
+            };
+            esp_idf_svc::hal::delay::FreeRtos::delay_ms(wait.as_millis() as u32);
+        }
+    }
+
+    // Waits until `condition` holds, checking it every poll interval; false after `timeout`.
+    // Everything is released on abort, as in `sleep`.
+    pub fn wait_until(
+        &mut self,
+        mut condition: impl FnMut() -> bool,
+        timeout: Option<Duration>,
+    ) -> Result<bool, SendError> {
+        let start = Instant::now();
+        loop {
+            if condition() {
+                return Ok(true);
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Ok(false);
+            }
+            self.sleep(POLL_INTERVAL)?;
+        }
+    }
+
+    fn check_abort(&mut self) -> Result<(), SendError> {
+        let checked = self.watch.check();
+        if checked.is_err() {
+            self.release_after_error();
+        }
+        checked
+    }
+
+    fn release_after_error(&mut self) {
+        if self.down.is_empty() {
+            return;
+        }
+        if let Err(e) = self.release_all() {
+            log::warn!("cannot release keys: {e}");
+        }
+    }
+
+    fn send(&mut self, chord: KeyChord) -> Result<(), SendError> {
+        if let Err(e) = self.watch.wait_until_mounted(self.keyboard) {
+            self.release_after_error();
+            return Err(e);
+        }
+        self.keyboard
+            .push(&usbd_hid::descriptor::KeyboardReport::from(chord))?;
+        self.down = chord;
+        Ok(())
+    }
+}
+
+impl Drop for KeyboardController<'_> {
+    fn drop(&mut self) {
+        if self.down.is_empty() {
+            return;
+        }
+        if let Err(e) = self.release_all() {
+            log::warn!("cannot release keys: {e}");
+        }
+    }
+}
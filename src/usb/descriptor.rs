@@ -2,6 +2,16 @@ use crate::usb::HidInstance;
 use bytes::BufMut;
 use esp_idf_svc::sys::tinyusb;
 
+#[derive(Debug, thiserror::Error)]
+pub enum DescriptorError {
+    #[error("endpoint number {0} is out of range (0-15)")]
+    EndpointNumber(u8),
+    #[error("configuration descriptor is too long ({0} bytes)")]
+    TooLong(usize),
+    #[error("HID report descriptors are too long ({0} bytes)")]
+    ReportDescriptorTooLong(usize),
+}
+
 pub struct StringDescriptor {
     pub lang_id: &'static std::ffi::CStr,
     pub manufacturer: &'static std::ffi::CStr,
@@ -58,49 +68,71 @@ pub fn device_descriptor() -> tinyusb::tusb_desc_device_t {
 
 // https://github.com/espressif/esp-idf/blob/4523f2d67465373f0e732a3264273a8e84a1a6d1/examples/peripherals/usb/device/tusb_hid/main/tusb_hid_example_main.c#L50-L56
 #[allow(non_snake_case)]
-pub fn config_descriptor(msc_enabled: bool, instances: &[HidInstance]) -> Box<[u8]> {
-    const BUFFER_SIZE: usize = 128;
-    let mut array = [0u8; BUFFER_SIZE];
-    let mut buf = &mut array[..];
+pub fn config_descriptor(
+    msc_enabled: bool,
+    instances: &[HidInstance],
+) -> Result<Box<[u8]>, DescriptorError> {
+    let mut buf: Vec<u8> = Vec::with_capacity(128);
+
+    // Interfaces are numbered in order: HID instances first, MSC last.
+    // Each interface gets its own endpoint number, since TinyUSB maps an interface to an instance.
+    let msc_interface = instances.len() as u8;
 
     // CONFIGURATION DESCRIPTOR
     buf.put_u8(9); // bLength == 9 (const)
     buf.put_u8(2); // bDescriptorType == CONFIGURATION(2) (const)
     buf.put_u16_le(0); // wTotalLength: temporal value
-    buf.put_u8(2); // bNumInterface
+    buf.put_u8(msc_interface + msc_enabled as u8); // bNumInterface
     buf.put_u8(1); // bConfigurationValue
     buf.put_u8(0); // iConfiguration
     buf.put_u8(0b10100000); // bmAttributes
     buf.put_u8(100); // bMaxPower
 
-    // HID INTERFACE DESCRIPTOR
-    buf.put_u8(9); // bLength == 9 (const)
-    buf.put_u8(4); // bDescriptorType == INTERFACE(4) (const)
-    buf.put_u8(0); // bInterfaceNumber
-    buf.put_u8(0); // bAlternateSetting
-    buf.put_u8(1); // bNumEndpoints
-    buf.put_u8(3); // bInterfaceClass
-    buf.put_u8(0); // bInterfaceSubClass
-    buf.put_u8(0); // bInterfaceProtocol
-    buf.put_u8(4); // iInterface
-
-    // HID DESCRIPTOR
-    buf.put_u8(9); // bLength == 9 (const)
-    buf.put_u8(0x21); // bDescriptorType == HID(0x21) (const)
-    buf.put_u16_le(0x0111); // bcdHID == v1.11
-    buf.put_u8(0); // bCountryCode (0 if not specify)
-    buf.put_u8(instances.len() as u8); // bNumDescriptors
-    buf.put_u8(0x22); // bDescriptorType (type of HID report descriptor)
-    let descriptor_size: u16 = instances.iter().map(|d| d.desc().len() as u16).sum();
-    buf.put_u16_le(descriptor_size); // wDescriptorLength
-
-    // ENDPOINT DESCRIPTOR
-    buf.put_u8(7); // bLength == 7 (const)
-    buf.put_u8(5); // bDescriptorType == ENDPOINT(5) (const)
-    buf.put_u8(endpoint_address(1, Direction::In));
-    buf.put_u8(0b11); // bmAttributes
-    buf.put_u16_le(16); // wMaxPacketSize
-    buf.put_u8(10); // bInterval
+    for (interface, instance) in instances.iter().enumerate() {
+        let interface = interface as u8;
+
+        // HID INTERFACE DESCRIPTOR
+        buf.put_u8(9); // bLength == 9 (const)
+        buf.put_u8(4); // bDescriptorType == INTERFACE(4) (const)
+        buf.put_u8(interface); // bInterfaceNumber
+        buf.put_u8(0); // bAlternateSetting
+        buf.put_u8(1 + instance.has_out_endpoint as u8); // bNumEndpoints
+        buf.put_u8(3); // bInterfaceClass
+        buf.put_u8(0); // bInterfaceSubClass
+        buf.put_u8(0); // bInterfaceProtocol
+        buf.put_u8(4); // iInterface
+
+        // HID DESCRIPTOR
+        buf.put_u8(9); // bLength == 9 (const)
+        buf.put_u8(0x21); // bDescriptorType == HID(0x21) (const)
+        buf.put_u16_le(0x0111); // bcdHID == v1.11
+        buf.put_u8(0); // bCountryCode (0 if not specify)
+        buf.put_u8(1); // bNumDescriptors
+        buf.put_u8(0x22); // bDescriptorType (type of HID report descriptor)
+        let descriptor_size = instance.desc().len();
+        let descriptor_size: u16 = descriptor_size
+            .try_into()
+            .map_err(|_| DescriptorError::ReportDescriptorTooLong(descriptor_size))?;
+        buf.put_u16_le(descriptor_size); // wDescriptorLength
+
+        // ENDPOINT DESCRIPTOR (IN)
+        buf.put_u8(7); // bLength == 7 (const)
+        buf.put_u8(5); // bDescriptorType == ENDPOINT(5) (const)
+        buf.put_u8(endpoint_address(interface + 1, Direction::In)?);
+        buf.put_u8(0b11); // bmAttributes
+        buf.put_u16_le(instance.packet_size); // wMaxPacketSize
+        buf.put_u8(10); // bInterval
+
+        if instance.has_out_endpoint {
+            // ENDPOINT DESCRIPTOR (OUT)
+            buf.put_u8(7); // bLength == 7 (const)
+            buf.put_u8(5); // bDescriptorType == ENDPOINT(5) (const)
+            buf.put_u8(endpoint_address(interface + 1, Direction::Out)?);
+            buf.put_u8(0b11); // bmAttributes
+            buf.put_u16_le(instance.packet_size); // wMaxPacketSize
+            buf.put_u8(10); // bInterval
+        }
+    }
 
     if msc_enabled {
         // MSC INTERFACE DESCRIPTOR
@@ -108,61 +140,48 @@ pub fn config_descriptor(msc_enabled: bool, instances: &[HidInstance]) -> Box<[u
         // https://github.com/hathach/tinyusb/blob/d10b65ada4be7d5754b3128e80a9b4db72bdb23f/src/device/usbd.h#L250-L257
         buf.put_u8(9); // bLength == 9 (const)
         buf.put_u8(4); // bDescriptorType == INTERFACE(4) (const)
-        buf.put_u8(1); // bInterfaceNumber
+        buf.put_u8(msc_interface); // bInterfaceNumber
         buf.put_u8(0); // bAlternateSetting
         buf.put_u8(2); // bNumEndpoints
-        buf.put_u8(
-            // bInterfaceClass
-            tinyusb::tusb_class_code_t_TUSB_CLASS_MSC
-                .try_into()
-                .unwrap(),
-        );
-        buf.put_u8(
-            // bInterfaceSubClass
-            tinyusb::msc_subclass_type_t_MSC_SUBCLASS_SCSI
-                .try_into()
-                .unwrap(),
-        );
-        buf.put_u8(
-            // bInterfaceProtocol
-            tinyusb::msc_protocol_type_t_MSC_PROTOCOL_BOT
-                .try_into()
-                .unwrap(),
-        );
+        buf.put_u8(tinyusb::tusb_class_code_t_TUSB_CLASS_MSC as u8); // bInterfaceClass
+        buf.put_u8(tinyusb::msc_subclass_type_t_MSC_SUBCLASS_SCSI as u8); // bInterfaceSubClass
+        buf.put_u8(tinyusb::msc_protocol_type_t_MSC_PROTOCOL_BOT as u8); // bInterfaceProtocol
         buf.put_u8(5); // iInterface
 
         // MSC ENDPOINT DESCRIPTOR (OUT)
         buf.put_u8(7); // bLength == 7 (const)
         buf.put_u8(5); // bDescriptorType == ENDPOINT(5) (const)
-        buf.put_u8(endpoint_address(2, Direction::Out));
-        buf.put_u8(tinyusb::tusb_xfer_type_t_TUSB_XFER_BULK.try_into().unwrap()); // bmAttributes
+        buf.put_u8(endpoint_address(msc_interface + 1, Direction::Out)?);
+        buf.put_u8(tinyusb::tusb_xfer_type_t_TUSB_XFER_BULK as u8); // bmAttributes
         buf.put_u16_le(64); // wMaxPacketSize
         buf.put_u8(0); // bInterval
 
         // MSC ENDPOINT DESCRIPTOR (IN)
         buf.put_u8(7); // bLength == 7 (const)
         buf.put_u8(5); // bDescriptorType == ENDPOINT(5) (const)
-        buf.put_u8(endpoint_address(2, Direction::In));
-        buf.put_u8(tinyusb::tusb_xfer_type_t_TUSB_XFER_BULK.try_into().unwrap()); // bmAttributes
+        buf.put_u8(endpoint_address(msc_interface + 1, Direction::In)?);
+        buf.put_u8(tinyusb::tusb_xfer_type_t_TUSB_XFER_BULK as u8); // bmAttributes
         buf.put_u16_le(64); // wMaxPacketSize
         buf.put_u8(0); // bInterval
     }
 
     // Update wTotalLength
-    let wTotalLength = BUFFER_SIZE - &buf.remaining_mut();
-    let (written, _empty) = array.split_at_mut(wTotalLength);
-    written[2..4].copy_from_slice((wTotalLength as u16).to_le_bytes().as_slice());
+    let wTotalLength: u16 = buf
+        .len()
+        .try_into()
+        .map_err(|_| DescriptorError::TooLong(buf.len()))?;
+    buf[2..4].copy_from_slice(wTotalLength.to_le_bytes().as_slice());
 
-    Box::from(written.as_ref())
+    Ok(buf.into_boxed_slice())
 }
 
-const fn endpoint_address(number: u8, direction: Direction) -> u8 {
+const fn endpoint_address(number: u8, direction: Direction) -> Result<u8, DescriptorError> {
     // bEndpointAddress (bit7: IN=1, OUT=0; bit3-0: Endpoint number)
     // ex. 0x10000001: No.1 (IN)
     if (number & 0b11110000) != 0 {
-        panic!("endpoint number is too big");
+        return Err(DescriptorError::EndpointNumber(number));
     }
-    direction as u8 | number
+    Ok(direction as u8 | number)
 }
 
 enum Direction {
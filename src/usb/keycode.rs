@@ -1,90 +1,642 @@
 use usbd_hid::descriptor::KeyboardReport;
-use esp_idf_svc::sys::tinyusb;
+
+// Layouts `character_to_chord` can map characters for
+pub const LAYOUTS: &[&str] = &["us"];
+
+// Keys a boot keyboard report can hold at once, besides modifiers
+pub const MAX_CHORD_KEYS: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum KeycodeError {
+    #[error("no key for {0:?} in the current layout")]
+    Unmappable(char),
+    #[error("unknown key name {0:?}")]
+    UnknownKey(String),
+    #[error("malformed key chord {0:?}")]
+    InvalidChord(String),
+    #[error("a chord holds at most {MAX_CHORD_KEYS} keys besides modifiers")]
+    TooManyKeys,
+}
 
 pub trait AsKeyboardReport {
-    fn as_keyboard_report(self) -> Option<KeyboardReport>;
+    // Implemented for small `Copy` types only
+    #[allow(clippy::wrong_self_convention)]
+    fn as_keyboard_report(self) -> Result<KeyboardReport, KeycodeError>;
 }
 
 impl AsKeyboardReport for u8 {
-    fn as_keyboard_report(self) -> Option<KeyboardReport> {
-        character_to_report(self as char)
+    fn as_keyboard_report(self) -> Result<KeyboardReport, KeycodeError> {
+        (self as char).as_keyboard_report()
     }
 }
 
 impl AsKeyboardReport for char {
-    fn as_keyboard_report(self) -> Option<KeyboardReport> {
-        character_to_report(self)
+    fn as_keyboard_report(self) -> Result<KeyboardReport, KeycodeError> {
+        character_to_chord(self)
+            .map(KeyboardReport::from)
+            .ok_or(KeycodeError::Unmappable(self))
+    }
+}
+
+impl AsKeyboardReport for Key {
+    fn as_keyboard_report(self) -> Result<KeyboardReport, KeycodeError> {
+        Ok(KeyChord::from(self).into())
+    }
+}
+
+impl AsKeyboardReport for KeyChord {
+    fn as_keyboard_report(self) -> Result<KeyboardReport, KeycodeError> {
+        Ok(self.into())
+    }
+}
+
+// The keys typing `char` on a US layout
+pub fn character_to_chord(char: char) -> Option<KeyChord> {
+    // https://github.com/hathach/tinyusb/blob/fd11bf17fde6cbfdb4bb1ed7070ed4111e503ae8/src/class/hid/hid.h#L952-L1099
+    use usbd_hid::descriptor::KeyboardUsage::*;
+
+    let plain = |usage| Some(KeyChord::single(Modifiers::empty(), usage));
+    let shifted = |usage| Some(KeyChord::single(Modifiers::LEFT_SHIFT, usage));
+
+    match char {
+        'A'..='Z' => shifted(char as u8 - b'A' + KeyboardAa as u8),
+        'a'..='z' => plain(char as u8 - b'a' + KeyboardAa as u8),
+        '1'..='9' => plain(char as u8 - b'1' + Keyboard1Exclamation as u8),
+        '0' => plain(Keyboard0CloseParens as u8),
+        '\x08' => plain(Key::Backspace.usage()),
+        '\t' => plain(Key::Tab.usage()),
+        '\n' => plain(Key::Enter.usage()),
+        '\x1b' => plain(Key::Escape.usage()),
+        '`' => plain(KeyboardBacktickTilde as u8),
+        '~' => shifted(KeyboardBacktickTilde as u8),
+        '!' => shifted(Keyboard1Exclamation as u8),
+        '@' => shifted(Keyboard2At as u8),
+        '#' => shifted(Keyboard3Hash as u8),
+        '$' => shifted(Keyboard4Dollar as u8),
+        '%' => shifted(Keyboard5Percent as u8),
+        '^' => shifted(Keyboard6Caret as u8),
+        '&' => shifted(Keyboard7Ampersand as u8),
+        '*' => shifted(Keyboard8Asterisk as u8),
+        '(' => shifted(Keyboard9OpenParens as u8),
+        ')' => shifted(Keyboard0CloseParens as u8),
+        '-' => plain(KeyboardDashUnderscore as u8),
+        '_' => shifted(KeyboardDashUnderscore as u8),
+        '=' => plain(KeyboardEqualPlus as u8),
+        '+' => shifted(KeyboardEqualPlus as u8),
+        '[' => plain(KeyboardOpenBracketBrace as u8),
+        '{' => shifted(KeyboardOpenBracketBrace as u8),
+        ']' => plain(KeyboardCloseBracketBrace as u8),
+        '}' => shifted(KeyboardCloseBracketBrace as u8),
+        '\\' => plain(KeyboardBackslashBar as u8),
+        '|' => shifted(KeyboardBackslashBar as u8),
+        ';' => plain(KeyboardSemiColon as u8),
+        ':' => shifted(KeyboardSemiColon as u8),
+        '\'' => plain(KeyboardSingleDoubleQuote as u8),
+        '"' => shifted(KeyboardSingleDoubleQuote as u8),
+        ',' => plain(KeyboardCommaLess as u8),
+        '<' => shifted(KeyboardCommaLess as u8),
+        '.' => plain(KeyboardPeriodGreater as u8),
+        '>' => shifted(KeyboardPeriodGreater as u8),
+        '/' => plain(KeyboardSlashQuestion as u8),
+        '?' => shifted(KeyboardSlashQuestion as u8),
+        ' ' => plain(Key::Space.usage()),
+        _ => None,
+    }
+}
+
+// Declares `Key` and its name table together, so they cannot get out of sync.
+// The first name of a key is the one it is displayed with.
+macro_rules! named_keys {
+    ($($key:ident = $usage:literal => [$($name:literal),+],)*) => {
+        // Keys that do not type a character, by their usage ID on the Keyboard/Keypad page
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum Key {
+            $($key = $usage,)*
+        }
+
+        // Every accepted name; compared after `normalize`
+        pub const KEY_NAMES: &[(&str, Key)] = &[$($(($name, Key::$key),)+)*];
+    };
+}
+
+#[rustfmt::skip]
+named_keys! {
+    Enter       = 0x28 => ["ENTER", "RETURN"],
+    Escape      = 0x29 => ["ESCAPE", "ESC"],
+    Backspace   = 0x2a => ["BACKSPACE", "BKSP"],
+    Tab         = 0x2b => ["TAB"],
+    Space       = 0x2c => ["SPACE"],
+    CapsLock    = 0x39 => ["CAPS_LOCK", "CAPSLOCK"],
+    F1          = 0x3a => ["F1"],
+    F2          = 0x3b => ["F2"],
+    F3          = 0x3c => ["F3"],
+    F4          = 0x3d => ["F4"],
+    F5          = 0x3e => ["F5"],
+    F6          = 0x3f => ["F6"],
+    F7          = 0x40 => ["F7"],
+    F8          = 0x41 => ["F8"],
+    F9          = 0x42 => ["F9"],
+    F10         = 0x43 => ["F10"],
+    F11         = 0x44 => ["F11"],
+    F12         = 0x45 => ["F12"],
+    PrintScreen = 0x46 => ["PRINT_SCREEN", "PRINTSCREEN", "PRTSC", "SYSRQ"],
+    ScrollLock  = 0x47 => ["SCROLL_LOCK", "SCROLLLOCK"],
+    Pause       = 0x48 => ["PAUSE", "BREAK"],
+    Insert      = 0x49 => ["INSERT", "INS"],
+    Home        = 0x4a => ["HOME"],
+    PageUp      = 0x4b => ["PAGE_UP", "PAGEUP", "PGUP"],
+    Delete      = 0x4c => ["DELETE", "DEL"],
+    End         = 0x4d => ["END"],
+    PageDown    = 0x4e => ["PAGE_DOWN", "PAGEDOWN", "PGDN"],
+    Right       = 0x4f => ["RIGHT", "RIGHT_ARROW", "RIGHTARROW"],
+    Left        = 0x50 => ["LEFT", "LEFT_ARROW", "LEFTARROW"],
+    Down        = 0x51 => ["DOWN", "DOWN_ARROW", "DOWNARROW"],
+    Up          = 0x52 => ["UP", "UP_ARROW", "UPARROW"],
+    NumLock     = 0x53 => ["NUM_LOCK", "NUMLOCK"],
+    KpDivide    = 0x54 => ["KP_DIVIDE", "KP_SLASH"],
+    KpMultiply  = 0x55 => ["KP_MULTIPLY", "KP_ASTERISK"],
+    KpMinus     = 0x56 => ["KP_MINUS", "KP_SUBTRACT"],
+    KpPlus      = 0x57 => ["KP_PLUS", "KP_ADD"],
+    KpEnter     = 0x58 => ["KP_ENTER"],
+    Kp1         = 0x59 => ["KP_1", "KP_END"],
+    Kp2         = 0x5a => ["KP_2", "KP_DOWN"],
+    Kp3         = 0x5b => ["KP_3", "KP_PAGE_DOWN"],
+    Kp4         = 0x5c => ["KP_4", "KP_LEFT"],
+    Kp5         = 0x5d => ["KP_5"],
+    Kp6         = 0x5e => ["KP_6", "KP_RIGHT"],
+    Kp7         = 0x5f => ["KP_7", "KP_HOME"],
+    Kp8         = 0x60 => ["KP_8", "KP_UP"],
+    Kp9         = 0x61 => ["KP_9", "KP_PAGE_UP"],
+    Kp0         = 0x62 => ["KP_0", "KP_INSERT"],
+    KpDecimal   = 0x63 => ["KP_DECIMAL", "KP_PERIOD", "KP_DELETE"],
+    Menu        = 0x65 => ["MENU", "APPLICATION", "APP"],
+    KpEqual     = 0x67 => ["KP_EQUAL"],
+    F13         = 0x68 => ["F13"],
+    F14         = 0x69 => ["F14"],
+    F15         = 0x6a => ["F15"],
+    F16         = 0x6b => ["F16"],
+    F17         = 0x6c => ["F17"],
+    F18         = 0x6d => ["F18"],
+    F19         = 0x6e => ["F19"],
+    F20         = 0x6f => ["F20"],
+    F21         = 0x70 => ["F21"],
+    F22         = 0x71 => ["F22"],
+    F23         = 0x72 => ["F23"],
+    F24         = 0x73 => ["F24"],
+}
+
+impl Key {
+    pub fn usage(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        KEY_NAMES
+            .iter()
+            .find(|(_, key)| *key == self)
+            .map(|(name, _)| *name)
+            .unwrap_or_default()
+    }
+
+    // Case-insensitive; `_`, `-` and spaces are ignored, so `KP_ENTER`, `kp-enter` and
+    // `KpEnter` are the same key
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = normalize(name);
+        KEY_NAMES
+            .iter()
+            .find(|(candidate, _)| normalize(candidate) == name)
+            .map(|(_, key)| *key)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+impl std::str::FromStr for Key {
+    type Err = KeycodeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::from_name(name).ok_or_else(|| KeycodeError::UnknownKey(name.into()))
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Key {
+    pub fn from_usage(usage: u8) -> Option<Self> {
+        KEY_NAMES
+            .iter()
+            .find(|(_, key)| key.usage() == usage)
+            .map(|(_, key)| *key)
+    }
+}
+
+bitflags::bitflags! {
+    // The modifier byte of `KeyboardReport`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Modifiers: u8 {
+        const LEFT_CTRL = 0x01;
+        const LEFT_SHIFT = 0x02;
+        const LEFT_ALT = 0x04;
+        const LEFT_GUI = 0x08;
+        const RIGHT_CTRL = 0x10;
+        const RIGHT_SHIFT = 0x20;
+        const RIGHT_ALT = 0x40;
+        const RIGHT_GUI = 0x80;
+    }
+}
+
+// Unqualified names mean the left modifier; the first name of each is the one it is displayed with
+#[rustfmt::skip]
+pub const MODIFIER_NAMES: &[(&str, Modifiers)] = &[
+    ("CTRL", Modifiers::LEFT_CTRL),
+    ("SHIFT", Modifiers::LEFT_SHIFT),
+    ("ALT", Modifiers::LEFT_ALT),
+    ("GUI", Modifiers::LEFT_GUI),
+    ("RIGHT_CTRL", Modifiers::RIGHT_CTRL),
+    ("RIGHT_SHIFT", Modifiers::RIGHT_SHIFT),
+    ("RIGHT_ALT", Modifiers::RIGHT_ALT),
+    ("RIGHT_GUI", Modifiers::RIGHT_GUI),
+    ("CONTROL", Modifiers::LEFT_CTRL),
+    ("LEFT_CTRL", Modifiers::LEFT_CTRL),
+    ("LEFT_CONTROL", Modifiers::LEFT_CTRL),
+    ("LEFT_SHIFT", Modifiers::LEFT_SHIFT),
+    ("OPTION", Modifiers::LEFT_ALT),
+    ("LEFT_ALT", Modifiers::LEFT_ALT),
+    ("WIN", Modifiers::LEFT_GUI),
+    ("WINDOWS", Modifiers::LEFT_GUI),
+    ("CMD", Modifiers::LEFT_GUI),
+    ("COMMAND", Modifiers::LEFT_GUI),
+    ("META", Modifiers::LEFT_GUI),
+    ("SUPER", Modifiers::LEFT_GUI),
+    ("LEFT_GUI", Modifiers::LEFT_GUI),
+    ("RIGHT_CONTROL", Modifiers::RIGHT_CTRL),
+    ("ALTGR", Modifiers::RIGHT_ALT),
+    ("RIGHT_WIN", Modifiers::RIGHT_GUI),
+];
+
+impl Modifiers {
+    // Any name of `MODIFIER_NAMES`, matched like `Key::from_name` (whose bitflags counterpart
+    // only takes the exact constant names)
+    pub fn from_alias(name: &str) -> Option<Self> {
+        let name = normalize(name);
+        MODIFIER_NAMES
+            .iter()
+            .find(|(candidate, _)| normalize(candidate) == name)
+            .map(|(_, modifiers)| *modifiers)
+    }
+}
+
+impl std::fmt::Display for Modifiers {
+    // Like `CTRL+RIGHT_ALT`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut separator = "";
+        for modifier in self.iter() {
+            let (name, _) = MODIFIER_NAMES
+                .iter()
+                .find(|(_, candidate)| *candidate == modifier)
+                .expect("every modifier is named");
+            write!(f, "{separator}{name}")?;
+            separator = "+";
+        }
+        Ok(())
+    }
+}
+
+// Modifiers and up to `MAX_CHORD_KEYS` keys pressed together, i.e. one keyboard report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct KeyChord {
+    pub modifiers: Modifiers,
+    // Usage IDs, unused ones are 0
+    keycodes: [u8; MAX_CHORD_KEYS],
+}
+
+impl KeyChord {
+    pub const fn single(modifiers: Modifiers, usage: u8) -> Self {
+        Self {
+            modifiers,
+            keycodes: [usage, 0, 0, 0, 0, 0],
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.keycodes.iter().copied().filter(|&usage| usage != 0)
+    }
+
+    pub fn push_key(&mut self, usage: u8) -> Result<(), KeycodeError> {
+        if self.keys().any(|key| key == usage) {
+            return Ok(());
+        }
+        let slot = self
+            .keycodes
+            .iter_mut()
+            .find(|usage| **usage == 0)
+            .ok_or(KeycodeError::TooManyKeys)?;
+        *slot = usage;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modifiers.is_empty() && self.keys().next().is_none()
+    }
+
+    // Adds the modifiers and keys of `other`
+    pub fn merge(&mut self, other: &KeyChord) -> Result<(), KeycodeError> {
+        self.modifiers |= other.modifiers;
+        other.keys().try_for_each(|usage| self.push_key(usage))
+    }
+
+    // Removes the modifiers and keys of `other`, keeping the remaining keys in order
+    pub fn remove(&mut self, other: &KeyChord) {
+        self.modifiers -= other.modifiers;
+        let remaining: Vec<u8> = self
+            .keys()
+            .filter(|&key| !other.keys().any(|k| k == key))
+            .collect();
+        self.keycodes = [0; MAX_CHORD_KEYS];
+        self.keycodes[..remaining.len()].copy_from_slice(&remaining);
+    }
+
+    // What `self` has that `other` has not
+    pub fn difference(&self, other: &KeyChord) -> KeyChord {
+        let mut difference = *self;
+        difference.remove(other);
+        difference
+    }
+}
+
+impl From<Key> for KeyChord {
+    fn from(key: Key) -> Self {
+        Self::single(Modifiers::empty(), key.usage())
+    }
+}
+
+// Modifiers without keys, e.g. to hold Shift
+impl From<Modifiers> for KeyChord {
+    fn from(modifiers: Modifiers) -> Self {
+        Self {
+            modifiers,
+            ..Default::default()
+        }
     }
 }
 
-#[macro_export]
-macro_rules! key {
-    // key!(mod(modifier1, modifier2), key1)
-    ($(mod($($modifier:expr),*),)? $key:expr) => {
+impl From<KeyChord> for KeyboardReport {
+    fn from(chord: KeyChord) -> Self {
         KeyboardReport {
-            modifier: $($(modifier!($modifier) | )*)? 0 as u8,
+            modifier: chord.modifiers.bits(),
             reserved: 0,
             leds: 0,
-            keycodes: [$key as u8, 0, 0, 0, 0, 0],
+            keycodes: chord.keycodes,
         }
-    };
+    }
 }
 
-macro_rules! modifier {
-    (ctrl)  => { 0b0001 };
-    (shift) => { 0b0010 };
-    (alt)   => { 0b0100 };
-    (gui)   => { 0b1000 };
-    ($modifier:expr) => { $modifier };
+impl std::str::FromStr for KeyChord {
+    type Err = KeycodeError;
+
+    // Modifier and key names joined by `+`, like `Ctrl+Alt+Delete`, `RightAlt+e` or `Ctrl++`.
+    // Single characters stand for the key typing them, with the modifiers it needs; letters are
+    // taken as lowercase so that `Ctrl+T` does not add Shift. Keys without a name or character
+    // are given by usage ID, like `0x87`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || KeycodeError::InvalidChord(text.into());
+        // A `+` on its own or after a separator is the `+` key itself
+        let (modifiers, last) = match text.strip_suffix("++") {
+            Some(rest) => (Some(rest), "+"),
+            None if text.trim() == "+" => (None, "+"),
+            None => match text.rsplit_once('+') {
+                Some((rest, last)) => (Some(rest), last),
+                None => (None, text),
+            },
+        };
+
+        let mut chord = Self::default();
+        for part in modifiers
+            .into_iter()
+            .flat_map(|rest| rest.split('+'))
+            .chain([last])
+        {
+            let part = part.trim();
+            if part.is_empty() {
+                return Err(invalid());
+            }
+            if let Some(modifiers) = Modifiers::from_alias(part) {
+                chord.modifiers |= modifiers;
+                continue;
+            }
+            if let Some(key) = Key::from_name(part) {
+                chord.push_key(key.usage())?;
+                continue;
+            }
+            if let Some(usage) = usage_id(part) {
+                chord.push_key(usage)?;
+                continue;
+            }
+            let mut chars = part.chars();
+            match (chars.next(), chars.next()) {
+                (Some(char), None) => {
+                    let typed = character_to_chord(char.to_ascii_lowercase())
+                        .ok_or(KeycodeError::Unmappable(char))?;
+                    chord.modifiers |= typed.modifiers;
+                    for usage in typed.keys() {
+                        chord.push_key(usage)?;
+                    }
+                }
+                _ => return Err(KeycodeError::UnknownKey(part.into())),
+            }
+        }
+        Ok(chord)
+    }
 }
 
-fn character_to_report(char: char) -> Option<usbd_hid::descriptor::KeyboardReport> {
-    // https://github.com/hathach/tinyusb/blob/fd11bf17fde6cbfdb4bb1ed7070ed4111e503ae8/src/class/hid/hid.h#L952-L1099
-    use usbd_hid::descriptor::{KeyboardReport, KeyboardUsage::*};
+// `0x` and two hex digits, as `KeyChord` displays keys without a name or character
+fn usage_id(part: &str) -> Option<u8> {
+    let digits = part
+        .strip_prefix("0x")
+        .or_else(|| part.strip_prefix("0X"))?;
+    if digits.len() != 2 {
+        return None;
+    }
+    u8::from_str_radix(digits, 16)
+        .ok()
+        .filter(|&usage| usage != 0)
+}
 
-    let shift = tinyusb::hid_keyboard_modifier_bm_t_KEYBOARD_MODIFIER_LEFTSHIFT as u8;
+impl std::fmt::Display for KeyChord {
+    // Parses back to the same chord, unless it is empty
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut separator = "";
+        if !self.modifiers.is_empty() {
+            write!(f, "{}", self.modifiers)?;
+            separator = "+";
+        }
+        for usage in self.keys() {
+            f.write_str(separator)?;
+            separator = "+";
+            match Key::from_usage(usage) {
+                Some(key) => write!(f, "{key}")?,
+                None => match (' '..='~').find(|&char| {
+                    character_to_chord(char) == Some(KeyChord::single(Modifiers::empty(), usage))
+                }) {
+                    Some(char) => write!(f, "{char}")?,
+                    None => write!(f, "0x{usage:02x}")?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
 
-    match char {
-        'A'..='Z' => Some(key!(mod(shift), char as u8 - ('A' as u8 - KeyboardAa as u8))),
-        'a'..='z' => Some(key!(char as u8 - ('a' as u8 - KeyboardAa as u8))),
-        '1'..='9' => Some(key!(char as u8 - ('1' as u8 - Keyboard1Exclamation as u8))),
-        '0' => Some(key!(Keyboard0CloseParens)),
-        '\x08' => Some(key!(KeyboardBackspace)),
-        '\t' => Some(key!(KeyboardTab)),
-        '\n' => Some(key!(KeyboardEnter)),
-        '\x1b' => Some(key!(KeyboardEscape)),
-        '`' => Some(key!(KeyboardBacktickTilde)),
-        '~' => Some(key!(mod(shift), KeyboardBacktickTilde)),
-        '!' => Some(key!(mod(shift), Keyboard1Exclamation)),
-        '@' => Some(key!(mod(shift), Keyboard2At)),
-        '#' => Some(key!(mod(shift), Keyboard3Hash)),
-        '$' => Some(key!(mod(shift), Keyboard4Dollar)),
-        '%' => Some(key!(mod(shift), Keyboard5Percent)),
-        '^' => Some(key!(mod(shift), Keyboard6Caret)),
-        '&' => Some(key!(mod(shift), Keyboard7Ampersand)),
-        '*' => Some(key!(mod(shift), Keyboard8Asterisk)),
-        '(' => Some(key!(mod(shift), Keyboard9OpenParens)),
-        ')' => Some(key!(mod(shift), Keyboard0CloseParens)),
-        '-' => Some(key!(KeyboardDashUnderscore)),
-        '_' => Some(key!(mod(shift), KeyboardDashUnderscore)),
-        '=' => Some(key!(KeyboardEqualPlus)),
-        '+' => Some(key!(mod(shift), KeyboardEqualPlus)),
-        '[' => Some(key!(KeyboardOpenBracketBrace)),
-        '{' => Some(key!(mod(shift), KeyboardOpenBracketBrace)),
-        ']' => Some(key!(KeyboardCloseBracketBrace)),
-        '}' => Some(key!(mod(shift), KeyboardCloseBracketBrace)),
-        '\\' => Some(key!(KeyboardBackslashBar)),
-        '|' => Some(key!(mod(shift), KeyboardBackslashBar)),
-        ';' => Some(key!(KeyboardSemiColon)),
-        ':' => Some(key!(mod(shift), KeyboardSemiColon)),
-        '"' => Some(key!(KeyboardSingleDoubleQuote)),
-        '\'' => Some(key!(mod(shift), KeyboardSingleDoubleQuote)),
-        ',' => Some(key!(KeyboardCommaLess)),
-        '<' => Some(key!(mod(shift), KeyboardCommaLess)),
-        '.' => Some(key!(KeyboardPeriodGreater)),
-        '/' => Some(key!(KeyboardSlashQuestion)),
-        '?' => Some(key!(mod(shift), KeyboardSlashQuestion)),
-        ' ' => Some(key!(KeyboardSpacebar)),
-        _ => None,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const E: u8 = 0x08;
+    const T: u8 = 0x17;
+    const EQUAL: u8 = 0x2e;
+
+    fn chord(text: &str) -> KeyChord {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn chords() {
+        let ctrl_plus = chord("Ctrl++");
+        assert_eq!(
+            ctrl_plus.modifiers,
+            Modifiers::LEFT_CTRL | Modifiers::LEFT_SHIFT
+        );
+        assert_eq!(ctrl_plus.keys().collect::<Vec<_>>(), [EQUAL]);
+        assert_eq!(chord("+"), KeyChord::single(Modifiers::LEFT_SHIFT, EQUAL));
+
+        assert_eq!(
+            chord("RightAlt+e"),
+            KeyChord::single(Modifiers::RIGHT_ALT, E)
+        );
+        // Letters are keys, not characters typed with Shift
+        assert_eq!(chord("Ctrl+T"), KeyChord::single(Modifiers::LEFT_CTRL, T));
+        assert_eq!(chord("ctrl + t"), chord("Ctrl+T"));
+        assert_eq!(
+            chord("CTRL+ALT+DELETE"),
+            KeyChord::single(
+                Modifiers::LEFT_CTRL | Modifiers::LEFT_ALT,
+                Key::Delete.usage()
+            )
+        );
+        assert_eq!(chord("Gui"), KeyChord::from(Modifiers::LEFT_GUI));
+        assert_eq!(chord("0x87"), KeyChord::single(Modifiers::empty(), 0x87));
+    }
+
+    #[test]
+    fn chord_errors() {
+        assert_eq!(
+            "a+b+c+d+e+f+g".parse::<KeyChord>(),
+            Err(KeycodeError::TooManyKeys)
+        );
+        // Repeated keys take no room
+        assert!("a+b+c+d+e+f+a".parse::<KeyChord>().is_ok());
+        assert_eq!(
+            "Ctrl+".parse::<KeyChord>(),
+            Err(KeycodeError::InvalidChord("Ctrl+".into()))
+        );
+        assert_eq!(
+            "Ctrl+Hyper".parse::<KeyChord>(),
+            Err(KeycodeError::UnknownKey("Hyper".into()))
+        );
+        assert_eq!("ü".parse::<KeyChord>(), Err(KeycodeError::Unmappable('ü')));
+        assert!("0x00".parse::<KeyChord>().is_err());
+        assert!("0x123".parse::<KeyChord>().is_err());
+    }
+
+    fn keys(chord: &KeyChord) -> Vec<u8> {
+        chord.keys().collect()
+    }
+
+    #[test]
+    fn merge() {
+        let mut down = chord("Shift+a+b");
+        down.merge(&chord("Ctrl+b+c")).unwrap();
+        assert_eq!(down.modifiers, Modifiers::LEFT_CTRL | Modifiers::LEFT_SHIFT);
+        assert_eq!(keys(&down), keys(&chord("a+b+c")));
+
+        // Six keys fit, a seventh does not
+        down.merge(&chord("d+e+f")).unwrap();
+        assert_eq!(keys(&down).len(), MAX_CHORD_KEYS);
+        down.merge(&chord("a+f")).unwrap();
+        assert_eq!(down.merge(&chord("g")), Err(KeycodeError::TooManyKeys));
+        assert_eq!(
+            chord("a+b+c+d+e").merge(&chord("f+g")),
+            Err(KeycodeError::TooManyKeys)
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut down = chord("Ctrl+Shift+a+b+c+d");
+        // Keys go up in any order; those left keep theirs, so the report does not reorder them
+        down.remove(&chord("Shift+b"));
+        assert_eq!(down.modifiers, Modifiers::LEFT_CTRL);
+        assert_eq!(keys(&down), keys(&chord("a+c+d")));
+        down.remove(&chord("a"));
+        assert_eq!(keys(&down), keys(&chord("c+d")));
+
+        // Removing what is not down changes nothing
+        down.remove(&chord("Alt+x"));
+        assert_eq!(down, chord("Ctrl+c+d"));
+
+        // Freed slots are reused
+        down.merge(&chord("e+f+g+h")).unwrap();
+        assert_eq!(keys(&down), keys(&chord("c+d+e+f+g+h")));
+
+        let all = down;
+        down.remove(&all);
+        assert!(down.is_empty());
+        assert_eq!(down, KeyChord::default());
+    }
+
+    #[test]
+    fn difference() {
+        let held = chord("Shift+a");
+        // What a tap adds to keys already held, and so what it releases again
+        let added = chord("Ctrl+Shift+a+b").difference(&held);
+        assert_eq!(added, chord("Ctrl+b"));
+        assert_eq!(held.difference(&held), KeyChord::default());
+        assert_eq!(held.difference(&KeyChord::default()), held);
+
+        let mut down = held;
+        down.merge(&chord("Ctrl+Shift+a+b")).unwrap();
+        down.remove(&added);
+        assert_eq!(down, held);
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for usage in 1..=u8::MAX {
+            let single = KeyChord::single(Modifiers::empty(), usage);
+            assert_eq!(chord(&single.to_string()), single, "{single}");
+        }
+        for text in [
+            "Ctrl++",
+            "RightAlt+e",
+            "Ctrl+Alt+Delete",
+            "Shift+Gui",
+            "a+b+c+d+e+f",
+            "Ctrl+Shift+0x87+F5",
+        ] {
+            let parsed = chord(text);
+            assert_eq!(chord(&parsed.to_string()), parsed, "{text}");
+        }
+        assert_eq!(chord("ctrl+alt+del").to_string(), "CTRL+ALT+DELETE");
+        assert_eq!(chord("RightAlt+e").to_string(), "RIGHT_ALT+e");
     }
 }
@@ -1,8 +1,20 @@
 use esp_idf_svc::sys::{self, tinyusb};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static mut WL_HANDLE: sys::wl_handle_t = sys::WL_INVALID_HANDLE;
 
-pub fn ensure_wl() -> Result<(), sys::EspError> {
+// Only affects the SCSI layer; the firmware keeps write access through its local mount
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("FATFS partition not found, check the partition table")]
+    PartitionNotFound,
+    #[error(transparent)]
+    Driver(#[from] sys::EspError),
+}
+
+pub fn ensure_wl() -> Result<(), StorageError> {
     if unsafe { WL_HANDLE != sys::WL_INVALID_HANDLE } {
         return Ok(());
     };
@@ -16,16 +28,16 @@ pub fn ensure_wl() -> Result<(), sys::EspError> {
     };
 
     if data_partition == std::ptr::null() {
-        log::error!("Failed to find FATFS partition. Check the partition table.");
-        sys::esp!(sys::ESP_ERR_NOT_FOUND as sys::esp_err_t)?;
+        return Err(StorageError::PartitionNotFound);
     }
 
     sys::esp!(unsafe { sys::wl_mount(data_partition, std::ptr::addr_of_mut!(WL_HANDLE)) })?;
     Ok(())
 }
 
-pub fn init_msc() -> anyhow::Result<()> {
+pub fn init_msc(read_only: bool) -> Result<(), StorageError> {
     ensure_wl()?;
+    READ_ONLY.store(read_only, Ordering::Relaxed);
 
     let mut config_spi: tinyusb::tinyusb_msc_spiflash_config_t = unsafe { std::mem::zeroed() };
     config_spi.wl_handle = unsafe { WL_HANDLE };
@@ -35,17 +47,18 @@ pub fn init_msc() -> anyhow::Result<()> {
         tinyusb::tinyusb_msc_storage_init_spiflash(std::ptr::from_ref(&config_spi))
     })?;
 
-    log::info!("MSC initialized");
+    log::info!("MSC initialized (read-only: {read_only:?})");
     Ok(())
 }
 
-pub fn mount(mount_path: &std::ffi::CStr) -> Result<(), sys::EspError> {
-    sys::esp!(unsafe { tinyusb::tinyusb_msc_storage_mount(mount_path.as_ptr()) })
+pub fn mount(mount_path: &std::ffi::CStr) -> Result<(), StorageError> {
+    sys::esp!(unsafe { tinyusb::tinyusb_msc_storage_mount(mount_path.as_ptr()) })?;
+    Ok(())
 }
 
 pub fn mount_without_msc(
     mount_path: &str,
-) -> Result<esp_idf_svc::io::vfs::MountedFatfs<esp_idf_svc::fs::fatfs::Fatfs<()>>, sys::EspError> {
+) -> Result<esp_idf_svc::io::vfs::MountedFatfs<esp_idf_svc::fs::fatfs::Fatfs<()>>, StorageError> {
     ensure_wl()?;
 
     let drive = {
@@ -55,7 +68,7 @@ pub fn mount_without_msc(
     };
 
     let fs = unsafe { esp_idf_svc::fs::fatfs::Fatfs::new_wl_part(drive, WL_HANDLE)? };
-    esp_idf_svc::io::vfs::MountedFatfs::mount(fs, mount_path, 1)
+    Ok(esp_idf_svc::io::vfs::MountedFatfs::mount(fs, mount_path, 1)?)
 }
 
 pub struct MountedFs {
@@ -64,7 +77,7 @@ pub struct MountedFs {
 }
 
 impl MountedFs {
-    pub fn mount(mount_path: &std::ffi::CStr) -> Result<Self, sys::EspError> {
+    pub fn mount(mount_path: &std::ffi::CStr) -> Result<Self, StorageError> {
         ensure_wl()?;
 
         let drive = {
@@ -97,12 +110,15 @@ impl Drop for MountedFs {
             sys::f_mount(core::ptr::null_mut(), self.path.as_ptr(), 0);
         }
 
-        sys::esp!(unsafe { sys::esp_vfs_fat_unregister_path(self.path.as_ptr()) }).unwrap();
+        if let Err(e) = sys::esp!(unsafe { sys::esp_vfs_fat_unregister_path(self.path.as_ptr()) }) {
+            log::error!("cannot unregister {:?}: {e}", self.path);
+        }
     }
 }
 
-pub fn unmount() -> Result<(), sys::EspError> {
-    sys::esp!(unsafe { tinyusb::tinyusb_msc_storage_unmount() })
+pub fn unmount() -> Result<(), StorageError> {
+    sys::esp!(unsafe { tinyusb::tinyusb_msc_storage_unmount() })?;
+    Ok(())
 }
 
 pub fn deinit() {
@@ -112,3 +128,16 @@ pub fn deinit() {
 pub fn is_exposed() -> bool {
     unsafe { tinyusb::tinyusb_msc_storage_in_use_by_usb_host() }
 }
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/**  CALLBACKS  **/
+
+// Invoked by TinyUSB to fill the write protect bit of MODE SENSE and to reject WRITE10
+// https://github.com/hathach/tinyusb/blob/d10b65ada4be7d5754b3128e80a9b4db72bdb23f/src/class/msc/msc_device.h#L131-L132
+#[no_mangle]
+extern "C" fn tud_msc_is_writable_cb(_lun: u8) -> bool {
+    !READ_ONLY.load(Ordering::Relaxed)
+}
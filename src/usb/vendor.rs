@@ -0,0 +1,116 @@
+// Vendor-defined raw HID interface carrying `protocol` messages, so that a host tool can
+// manage the device without switching to MSC mode.
+//
+// Requests are assembled on the TinyUSB task and handed over to whoever polls `Channel`.
+// The host sends one request and waits for its response, so a single pending request is kept.
+
+use super::{HidInstance, SendError};
+use crate::protocol::{self, Message};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+// Vendor-defined usage page, `protocol::REPORT_SIZE` bytes in and out, no report ID
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff,       // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,             // Usage (0x01)
+    0xa1, 0x01,             // Collection (Application)
+    0x15, 0x00,             //   Logical Minimum (0)
+    0x26, 0xff, 0x00,       //   Logical Maximum (255)
+    0x75, 0x08,             //   Report Size (8)
+    0x95, 0x40,             //   Report Count (64)
+    0x09, 0x02,             //   Usage (0x02)
+    0x81, 0x02,             //   Input (Data, Var, Abs)
+    0x09, 0x03,             //   Usage (0x03)
+    0x91, 0x02,             //   Output (Data, Var, Abs)
+    0xc0,                   // End Collection
+];
+
+const NO_INSTANCE: u8 = u8::MAX;
+static INSTANCE_ID: AtomicU8 = AtomicU8::new(NO_INSTANCE);
+
+// Problems are only recorded here and logged by `Channel::poll`, never on the TinyUSB task
+struct Receiver {
+    assembler: protocol::Assembler,
+    request: Option<Message>,
+    // Requests replaced by a newer one before they were polled
+    dropped: u32,
+    error: Option<protocol::Error>,
+}
+
+static RECEIVER: Mutex<Receiver> = Mutex::new(Receiver {
+    assembler: protocol::Assembler::new(),
+    request: None,
+    dropped: 0,
+    error: None,
+});
+
+fn lock_receiver() -> std::sync::MutexGuard<'static, Receiver> {
+    RECEIVER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The vendor interface as HID instance `instance_id`; only one may be installed
+pub fn instance(instance_id: u8) -> HidInstance<'static> {
+    INSTANCE_ID.store(instance_id, Ordering::Relaxed);
+    HidInstance {
+        instance_id,
+        report_id: 0,
+        descriptor: REPORT_DESCRIPTOR,
+        packet_size: protocol::REPORT_SIZE as u16,
+        has_out_endpoint: true,
+    }
+}
+
+// Called from `tud_hid_set_report_cb`; false if the report is not for the vendor interface
+pub(super) fn on_report(instance: u8, report: &[u8]) -> bool {
+    if instance != INSTANCE_ID.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut receiver = lock_receiver();
+    match receiver.assembler.push(report) {
+        Ok(Some(request)) => {
+            if receiver.request.replace(request).is_some() {
+                receiver.dropped += 1;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => receiver.error = Some(e),
+    }
+    true
+}
+
+pub struct Channel {
+    instance: HidInstance<'static>,
+}
+
+impl Channel {
+    pub fn new(instance: HidInstance<'static>) -> Self {
+        Self { instance }
+    }
+
+    // Never blocks
+    pub fn poll(&self) -> Option<Message> {
+        let mut receiver = lock_receiver();
+        let dropped = std::mem::take(&mut receiver.dropped);
+        let error = receiver.error.take();
+        let request = receiver.request.take();
+        drop(receiver);
+
+        if dropped > 0 {
+            log::warn!(
+                "vendor: {dropped} requests dropped, newer ones arrived before the response"
+            );
+        }
+        if let Some(e) = error {
+            log::warn!("vendor: {e}");
+        }
+        request
+    }
+
+    pub fn respond(&self, response: &Message) -> Result<(), SendError> {
+        for report in response.encode() {
+            self.instance.push_bytes(&report)?;
+        }
+        Ok(())
+    }
+}